@@ -0,0 +1,53 @@
+//! Time `pollard_after_block` with and without its MemForest mirror.
+//!
+//! Run with `cargo bench --bench pollard_after_block`.
+use accumulator_service::pollard::{pollard_after_block, pollard_after_block_unchecked};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::time::{Duration, Instant};
+
+const LEAVES: u32 = 100_000;
+const DELETES: u32 = 2_000;
+const ADDS: u32 = 3_000;
+const RUNS: u32 = 10;
+
+fn hash(i: u32) -> BitcoinNodeHash {
+    let mut bytes = [0u8; 32];
+    bytes[..4].copy_from_slice(&i.to_le_bytes());
+    bytes[31] = 1;
+    BitcoinNodeHash::new(bytes)
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        f();
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let leaves: Vec<_> = (0..LEAVES).map(hash).collect();
+    let mut forest = MemForest::<BitcoinNodeHash>::new();
+    forest.modify(&leaves, &[]).unwrap();
+    let mut bytes = Vec::new();
+    forest.serialize(&mut bytes).unwrap();
+
+    // a block spending every 50th leaf and creating new ones
+    let deletes: Vec<_> = (0..DELETES).map(|i| leaves[(i * 50) as usize]).collect();
+    let adds: Vec<_> = (LEAVES..LEAVES + ADDS).map(hash).collect();
+
+    let checked = time(|| {
+        pollard_after_block(&bytes, &deletes, &adds).unwrap();
+    });
+    let unchecked = time(|| {
+        pollard_after_block_unchecked(&bytes, &deletes, &adds).unwrap();
+    });
+    println!("{LEAVES} leaves, {DELETES} deletes, {ADDS} adds, mean of {RUNS} runs");
+    println!("  checked:   {checked:?}");
+    println!("  unchecked: {unchecked:?}");
+    println!(
+        "  speedup:   {:.2}x",
+        checked.as_secs_f64() / unchecked.as_secs_f64()
+    );
+}
@@ -1,18 +1,68 @@
+use accumulator_service::config::{ServiceConfig, DEFAULT_BIND, DEFAULT_DATA_DIR};
+use accumulator_service::state_machine::COMMAND_QUEUE_CAPACITY;
 use accumulator_service::{api, Context};
-use actix_web::{web, App, HttpServer};
-use log::info;
+use actix_web::{rt, web, App, HttpServer};
+use clap::Parser;
+use log::{info, warn};
+use std::path::PathBuf;
+
+/// CLI arguments
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Address to listen on, as host:port
+    #[arg(long, env = "ACC_SERVICE_BIND", default_value = DEFAULT_BIND)]
+    bind: String,
+    /// Commands that may wait for the state machine before requests get 503
+    #[arg(long, env = "ACC_SERVICE_COMMAND_QUEUE", default_value_t = COMMAND_QUEUE_CAPACITY)]
+    command_queue: usize,
+    /// Directory for mem_forest.bin, pollard.bin and the other working files
+    #[arg(long, env = "ACC_SERVICE_DATA_DIR", default_value = DEFAULT_DATA_DIR)]
+    data_dir: PathBuf,
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
-    info!("Starting accumulator-service HTTP server at http://127.0.0.1:8080");
-    let ctx = Context::new();
-    HttpServer::new(move || {
+    let args = Args::parse();
+    let config = ServiceConfig::from_bind(&args.bind)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?
+        .with_data_dir(args.data_dir);
+    info!(
+        "Starting accumulator-service HTTP server at http://{}",
+        config.bind
+    );
+    if args.command_queue == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--command-queue must be greater than zero",
+        ));
+    }
+    std::fs::create_dir_all(&config.data_dir)?;
+    info!("Keeping accumulator files in {:?}", config.data_dir);
+    let ctx = Context::with_capacity_in(args.command_queue, config.data_dir.clone());
+    let app_ctx = ctx.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(ctx.clone()))
+            .app_data(web::Data::new(app_ctx.clone()))
             .configure(api::configure)
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .bind(config.bind)?
+    .disable_signals()
+    .run();
+
+    // On Ctrl-C, let the running job stop cleanly before the server goes away, so it can't
+    // be killed half way through writing mem_forest.bin
+    let handle = server.handle();
+    rt::spawn(async move {
+        if rt::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        info!("Ctrl-C received, stopping the running job");
+        if let Err(e) = ctx.shutdown().await {
+            warn!("state machine did not shut down cleanly: {e:?}");
+        }
+        handle.stop(true).await;
+    });
+    server.await
 }
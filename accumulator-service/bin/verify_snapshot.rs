@@ -0,0 +1,51 @@
+//! Snapshot checker: confirms a pruned `pollard.bin` matches the full `mem_forest.bin`
+//! (same roots, same leaf count) and exits non-zero otherwise.
+use accumulator_service::delta::{load_forest, DELTA_FILE};
+use accumulator_service::verify::verify_snapshot;
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+
+/// CLI arguments
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Path to the full MemForest file
+    #[arg(long, default_value = "mem_forest.bin")]
+    forest: PathBuf,
+    /// Path to the pruned Pollard file
+    #[arg(long, default_value = "pollard.bin")]
+    pollard: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    // replay any delta log kept next to the snapshot, as the service does
+    let mut forest = Vec::new();
+    load_forest(&args.forest, &args.forest.with_file_name(DELTA_FILE))
+        .with_context(|| format!("loading forest file {:?}", args.forest))?
+        .serialize(&mut forest)?;
+    let pollard = std::fs::read(&args.pollard)
+        .with_context(|| format!("reading pollard file {:?}", args.pollard))?;
+
+    let report = verify_snapshot(&forest, &pollard)?;
+    if report.is_consistent() {
+        println!(
+            "OK: {} leaves, roots match ({:?} vs {:?})",
+            report.forest_leaves, args.forest, args.pollard
+        );
+        return Ok(());
+    }
+
+    eprintln!("MISMATCH between {:?} and {:?}", args.forest, args.pollard);
+    if report.forest_leaves != report.pollard_leaves {
+        eprintln!(
+            "- leaf count: forest = {}, pollard = {}",
+            report.forest_leaves, report.pollard_leaves
+        );
+    }
+    if !report.differing_roots.is_empty() {
+        eprintln!("- differing root indices: {:?}", report.differing_roots);
+    }
+    std::process::exit(1);
+}
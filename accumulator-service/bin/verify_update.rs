@@ -1,18 +1,22 @@
 //! Standalone verifier: loads a pruned Pollard, fetches block H and H+1,
 //! and applies UTXO changes to advance the Pollard.
-use accumulator_service::script_utils::btc_rpc::{get_block_leaf_hashes, BitcoinRpc};
-use anyhow::{anyhow, Context, Result};
-use bitcoincore_rpc::{Auth, Client, RpcApi};
+use accumulator_service::delta::{self, DELTA_FILE};
+use accumulator_service::pollard::{abbreviate_roots, decode, roots_hex, summary};
+use accumulator_service::rpc::CoreRpcClient;
+use accumulator_service::script_utils::btc_rpc::{
+    block_changes, block_input_leaves, BitcoinRpc, MissingPrevout,
+};
+use accumulator_service::verify::{check_difficulty, modify_verified, DifficultyCheck, Network};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use clap::Parser;
 use log::{info, warn};
-use rustreexo::accumulator::mem_forest::MemForest;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
-use rustreexo::accumulator::pollard::{Pollard, PollardAddition};
+use rustreexo::accumulator::pollard::PollardAddition;
 use rustreexo::accumulator::proof::Proof;
 use std::fs::File;
-use std::io::{Cursor, Read};
-use std::path::PathBuf;
-use utreexo::LeafData;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
 
 /// CLI arguments
 #[derive(Parser)]
@@ -24,6 +28,15 @@ struct Args {
     /// Block height H to process updates for H and H+1
     #[arg(long)]
     height: u64,
+    /// Network the node runs on; decides how strictly the blocks' difficulty is compared
+    #[arg(long, value_enum, default_value = "mainnet")]
+    network: Network,
+    /// Print only this many roots from each end of the committed root lists
+    #[arg(long)]
+    roots_shown: Option<usize>,
+    /// Treat OP_RETURN outputs as leaves; must match how the Pollard was built
+    #[arg(long)]
+    keep_op_return: bool,
 }
 
 fn main() -> Result<()> {
@@ -37,19 +50,30 @@ fn main() -> Result<()> {
     File::open(&args.pollard)
         .with_context(|| format!("opening pollard file {:?}", args.pollard))?
         .read_to_end(&mut pollard_bytes)?;
-    let mut rdr = Cursor::new(&pollard_bytes);
-    let mut pollard: Pollard<BitcoinNodeHash> =
-        Pollard::deserialize(&mut rdr).context("failed to deserialize pollard")?;
-    let prev_roots = pollard.roots().to_vec();
-    info!("Previous Utreexo roots: {:?}", prev_roots);
+    let (mut pollard, meta) = decode(&pollard_bytes).context("failed to deserialize pollard")?;
+    // The Pollard must be the state after block H, the one being advanced from
+    match &meta {
+        Some(meta) => {
+            meta.expect_height(args.height)?;
+            if let Some(keep_op_return) = meta.keep_op_return {
+                ensure!(
+                    keep_op_return == args.keep_op_return,
+                    "{:?} was built with keep_op_return = {keep_op_return}, but --keep-op-return is {}",
+                    args.pollard,
+                    args.keep_op_return
+                );
+            }
+        }
+        None => warn!(
+            "{:?} has no block metadata; can't check it is the state at height {}",
+            args.pollard, args.height
+        ),
+    }
+    let prev_roots_hex = roots_hex(&pollard)?;
+    info!("Previous accumulator: {}", summary(&pollard));
 
     // (2) Connect to local Bitcoin Core RPC
-    let rpc_url = std::env::var("BITCOIN_CORE_RPC_URL").context("missing BITCOIN_CORE_RPC_URL")?;
-    let cookie =
-        std::env::var("BITCOIN_CORE_COOKIE_FILE").context("missing BITCOIN_CORE_COOKIE_FILE")?;
-    let rpc_client = Client::new(&rpc_url, Auth::CookieFile(cookie.into()))
-        .context("failed to connect to Bitcoin RPC")?;
-    let rpc = RpcClient(rpc_client);
+    let rpc = CoreRpcClient::from_env()?;
 
     // (3) Fetch block H and H+1
     let bh0 = rpc.get_block_hash(args.height)?;
@@ -58,89 +82,77 @@ fn main() -> Result<()> {
     let bh1 = rpc.get_block_hash(h1)?;
     let block1 = rpc.get_block(&bh1)?;
     info!("Block {} hash = {}", args.height, bh0);
+    if let Some(hash) = meta.and_then(|meta| meta.block_hash) {
+        if hash != bh0 {
+            bail!(
+                "Pollard is the state after block {hash}, but block {} is {bh0}",
+                args.height
+            );
+        }
+    }
     info!("Block {} hash = {}", h1, bh1);
 
-    // (4) Verify difficulty target matches between blocks
-    if block0.header.bits != block1.header.bits {
-        warn!(
-            "Bits mismatch: {:?} vs {:?}",
+    // (4) Verify difficulty target is consistent between blocks
+    match check_difficulty(args.network, h1, block0.header.bits, block1.header.bits) {
+        DifficultyCheck::Consistent => {}
+        DifficultyCheck::Unusual => warn!(
+            "Bits changed outside a retarget: {:?} vs {:?}",
             block0.header.bits, block1.header.bits
-        );
+        ),
+        DifficultyCheck::Mismatch => bail!(
+            "bits mismatch outside a retarget: {:?} vs {:?}",
+            block0.header.bits,
+            block1.header.bits
+        ),
     }
 
-    // (5) Compute deletes (spent UTXO leaves) for block H+1
-    let deletes = get_block_leaf_hashes(&rpc, h1).context("failed to fetch block leaf hashes")?;
-    info!("Deletes from block {}: {} leaves", h1, deletes.len());
+    // (5) Compute the leaves spent by block H+1
+    let spent = block_input_leaves(
+        &rpc,
+        &block1,
+        MissingPrevout::Fail,
+        args.keep_op_return,
+        &CancellationToken::new(),
+    )
+    .context("failed to fetch block leaf hashes")?
+    .hashes;
 
-    // (6) Compute adds (new UTXO leaves) from block H+1
-    let height_code = rpc.get_block_height(&bh1).context("fetch block height")? << 1;
-    let mut adds = Vec::new();
-    for tx in &block1.txdata {
-        for (vout, out) in tx.output.iter().enumerate() {
-            let leaf_data = LeafData {
-                block_hash: bh1,
-                prevout: bitcoin::OutPoint {
-                    txid: tx.compute_txid(),
-                    vout: vout as u32,
-                },
-                header_code: height_code,
-                utxo: out.clone(),
-            };
-            adds.push(PollardAddition {
-                hash: leaf_data.get_leaf_hashes(),
-                remember: false,
-            });
-        }
-    }
+    // (6) Compute adds (new UTXO leaves) and deletes for block H+1; outputs spent within the
+    // block are neither
+    let height = rpc.get_block_height(&bh1).context("fetch block height")?;
+    let (adds, deletes) = block_changes(&block1, height, spent, args.keep_op_return)?;
+    info!("Deletes from block {}: {} leaves", h1, deletes.len());
+    let adds: Vec<_> = adds
+        .into_iter()
+        .map(|hash| PollardAddition {
+            hash,
+            remember: false,
+        })
+        .collect();
     info!("Adds from block {}: {} leaves", h1, adds.len());
 
-    // (7) Load full MemForest to generate an update proof
-    let mut forest_bytes = Vec::new();
-    File::open("mem_forest.bin")
-        .context("opening mem_forest.bin")?
-        .read_to_end(&mut forest_bytes)?;
-    let mut fcur = Cursor::new(&forest_bytes);
-    let forest: MemForest<BitcoinNodeHash> =
-        MemForest::deserialize(&mut fcur).context("deserialize forest")?;
+    // (7) Load full MemForest (snapshot plus delta log) to generate an update proof
+    let forest = delta::load_forest(Path::new("mem_forest.bin"), Path::new(DELTA_FILE))
+        .context("loading mem_forest.bin")?;
     let proof: Proof<BitcoinNodeHash> = forest
         .prove(&deletes)
         .map_err(|e| anyhow!("prove failed: {:?}", e))?;
 
-    // (8) Apply add/delete/proof to the pruned Pollard
-    pollard
-        .modify(&adds, &deletes, proof)
-        .map_err(|e| anyhow!("pollard.modify failed: {:?}", e))?;
-    let new_roots = pollard.roots().to_vec();
-    info!("New Utreexo roots: {:?}", new_roots);
+    // (8) Check the proof against the previous roots, then apply add/delete/proof to the
+    // pruned Pollard
+    modify_verified(&mut pollard, &adds, &deletes, proof)
+        .with_context(|| format!("refusing to apply block {h1}"))?;
+    info!("New accumulator: {}", summary(&pollard));
 
     // (9) Output commit values
+    let show = |roots: &[String]| match args.roots_shown {
+        Some(shown) => abbreviate_roots(roots, shown),
+        None => format!("{roots:?}"),
+    };
     info!("Commit:");
     info!("- prev_block_hash = {}", bh0);
-    info!("- prev_utreexo_roots = {:?}", prev_roots);
+    info!("- prev_utreexo_roots = {}", show(&prev_roots_hex));
     info!("- block_hash = {}", bh1);
-    info!("- new_utreexo_roots = {:?}", new_roots);
+    info!("- new_utreexo_roots = {}", show(&roots_hex(&pollard)?));
     Ok(())
 }
-
-/// A thin RPCAdapter implementing BitcoinRpc for our usage
-struct RpcClient(Client);
-impl BitcoinRpc for RpcClient {
-    fn get_block_hash(&self, height: u64) -> Result<bitcoin::BlockHash> {
-        Ok(self.0.get_block_hash(height)?)
-    }
-    fn get_block(&self, hash: &bitcoin::BlockHash) -> Result<bitcoin::Block> {
-        Ok(self.0.get_block(hash)?)
-    }
-    fn get_txout(&self, prev: &bitcoin::OutPoint) -> Result<(u64, Vec<u8>)> {
-        let info = self.0.get_raw_transaction_info(&prev.txid, None)?;
-        let v = info
-            .vout
-            .into_iter()
-            .find(|v| v.n == prev.vout)
-            .ok_or_else(|| anyhow!("vout not found"))?;
-        Ok((v.value.to_sat(), hex::decode(v.script_pub_key.hex)?))
-    }
-    fn get_block_height(&self, hash: &bitcoin::BlockHash) -> Result<u32> {
-        Ok(self.0.get_block_header_info(hash)?.height as u32)
-    }
-}
@@ -1,29 +1,71 @@
 use crate::{
-    state_machine::{Command, DispatchError},
+    builder, forest, pollard,
+    state_machine::{Command, DispatchError, ImportError, RestoreError},
+    sync_state,
+    updater::ApplyBlockError,
+    verify::{self, SnapshotReport},
     Context,
 };
-use actix_web::{web, HttpResponse, Responder};
-use serde::Deserialize;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::Block;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
 use std::path::PathBuf;
 
 /// Request to start or resume a build
 #[derive(Deserialize)]
 pub struct BuildRequest {
     pub parquet: String,
+    /// Snapshot path, or the block hash of a previous build to resume from
     pub resume_from: Option<String>,
+    /// Block hash the dump was taken at; a resume is refused if the snapshot is for another block
+    #[serde(default)]
+    pub block_hash: Option<String>,
+    /// Cross-check this many randomly sampled rows against Bitcoin Core before building
+    #[serde(default)]
+    pub validate_sample: Option<usize>,
+    /// Dump rows to add to the forest at a time, instead of the default
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// Keep OP_RETURN outputs as leaves; later updates follow whatever the build chose
+    #[serde(default)]
+    pub keep_op_return: bool,
+    /// Only report the dump's row count and the projected forest size, without building
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
-/// POST /build
+/// POST /build. With `dry_run`, responds 200 with a [`builder::BuildEstimate`] instead of
+/// starting a build.
 pub async fn post_build(ctx: web::Data<Context>, req: web::Json<BuildRequest>) -> impl Responder {
+    if req.batch_size == Some(0) {
+        return HttpResponse::BadRequest().body("batch_size must be greater than zero");
+    }
+    if req.dry_run {
+        let parquet = req.parquet.clone();
+        return match web::block(move || builder::estimate(&parquet)).await {
+            Ok(Ok(estimate)) => HttpResponse::Ok().json(estimate),
+            Ok(Err(e)) => HttpResponse::BadRequest().body(format!("{e:#}")),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        };
+    }
     match ctx
         .send(Command::Build {
             parquet: req.parquet.clone(),
             resume_from: req.resume_from.clone(),
+            block_hash: req.block_hash.clone(),
+            validate_sample: req.validate_sample,
+            batch_size: req.batch_size,
+            keep_op_return: req.keep_op_return,
         })
         .await
     {
         Ok(_) => HttpResponse::Accepted().finish(),
         Err(DispatchError::InvalidState) => HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => HttpResponse::ServiceUnavailable().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
@@ -34,11 +76,180 @@ pub async fn get_status(ctx: web::Data<Context>) -> impl Responder {
     HttpResponse::Ok().json(status)
 }
 
+/// GET /healthz: 200 as long as the state-machine worker is alive
+pub async fn get_healthz(ctx: web::Data<Context>) -> impl Responder {
+    if ctx.is_alive() {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::ServiceUnavailable().finish()
+    }
+}
+
+/// GET /readyz: 200 once `mem_forest.bin` exists with a valid header, 503 otherwise.
+/// Only the header is read, not the whole forest.
+pub async fn get_readyz(ctx: web::Data<Context>) -> impl Responder {
+    match File::open(ctx.data_dir().join("mem_forest.bin")).and_then(forest::read_header) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// GET /height: `{ height, block_hash }` of the block the forest is synced to, 404 before the
+/// first build
+pub async fn get_height(ctx: web::Data<Context>) -> impl Responder {
+    match sync_state::read(ctx.data_dir()) {
+        Ok(Some(state)) => HttpResponse::Ok().json(state),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Response of `GET /roots`
+#[derive(Serialize)]
+pub struct RootsResponse {
+    pub leaves: u64,
+    /// Roots as hex, an empty root as all zeros
+    pub roots: Vec<String>,
+}
+
+/// GET /roots: leaf count and roots of `pollard.bin`, 404 before the first build
+pub async fn get_roots(ctx: web::Data<Context>) -> impl Responder {
+    let bytes = match std::fs::read(ctx.data_dir().join("pollard.bin")) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return HttpResponse::NotFound().finish()
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let roots = pollard::decode(&bytes).and_then(|(pollard, _)| {
+        let roots = pollard::roots_hex(&pollard)?;
+        anyhow::Ok(RootsResponse {
+            leaves: pollard.leaves(),
+            roots,
+        })
+    });
+    match roots {
+        Ok(roots) => HttpResponse::Ok().json(roots),
+        Err(e) => HttpResponse::InternalServerError().body(format!("{e:#}")),
+    }
+}
+
+/// Header carrying the SHA-256 (hex) of a `/snapshot` body, in both directions.
+pub const SNAPSHOT_CHECKSUM_HEADER: &str = "x-snapshot-sha256";
+
+/// GET /snapshot: `pollard.bin` as is, block height envelope included, for another instance to
+/// `POST /snapshot`. The body's SHA-256 is in [`SNAPSHOT_CHECKSUM_HEADER`]. 404 before the
+/// first build or update, 500 if the file doesn't decode, e.g. while it is being rewritten
+pub async fn get_snapshot(ctx: web::Data<Context>) -> impl Responder {
+    let bytes = match std::fs::read(ctx.data_dir().join("pollard.bin")) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return HttpResponse::NotFound().finish()
+        }
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    if let Err(e) = pollard::decode(&bytes) {
+        return HttpResponse::InternalServerError().body(format!("{e:#}"));
+    }
+    HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header((
+            SNAPSHOT_CHECKSUM_HEADER,
+            sha256::Hash::hash(&bytes).to_string(),
+        ))
+        .body(bytes)
+}
+
+/// Largest `POST /snapshot` body accepted. The service's `pollard.bin` keeps only the roots, at
+/// most one per row, so a snapshot is a few kB; anything much larger isn't one.
+pub const SNAPSHOT_REQUEST_LIMIT: usize = 64 << 10;
+
+/// POST /snapshot: seed an idle instance that has no forest yet with the body of another
+/// instance's `GET /snapshot`, whose SHA-256 must be sent in [`SNAPSHOT_CHECKSUM_HEADER`].
+/// Writes `pollard.bin`, and `sync_state.json` from its envelope, so `/roots` and `/height`
+/// match the source; without `mem_forest.bin` it can't apply blocks. 201 once written, 400 if
+/// the checksum is missing or wrong or the snapshot doesn't decode, 413 if the body is over
+/// [`SNAPSHOT_REQUEST_LIMIT`], 409 if the service is busy or already has a forest (use
+/// `/restore` for that). The worker writes the files (see [`Context::import_snapshot`]), so no
+/// build or update can start in between
+pub async fn post_snapshot(
+    ctx: web::Data<Context>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> impl Responder {
+    let expected = req
+        .headers()
+        .get(SNAPSHOT_CHECKSUM_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let actual = sha256::Hash::hash(&body).to_string();
+    match expected {
+        Some(expected) if expected.eq_ignore_ascii_case(&actual) => {}
+        Some(expected) => {
+            return HttpResponse::BadRequest().body(format!(
+                "checksum mismatch: got {actual}, expected {expected}"
+            ))
+        }
+        None => {
+            return HttpResponse::BadRequest()
+                .body(format!("missing {SNAPSHOT_CHECKSUM_HEADER} header"))
+        }
+    }
+    let outcome = match ctx.import_snapshot(body.to_vec()).await {
+        Ok(outcome) => outcome,
+        Err(DispatchError::InvalidState) => return HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => return HttpResponse::ServiceUnavailable().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match outcome.await {
+        Ok(Ok(())) => HttpResponse::Created().finish(),
+        Ok(Err(ImportError::Busy)) => HttpResponse::Conflict().finish(),
+        Ok(Err(e @ ImportError::HasForest)) => HttpResponse::Conflict().body(e.to_string()),
+        Ok(Err(e @ ImportError::Invalid(_))) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        // the service stopped before the snapshot's turn
+        Err(_) => {
+            HttpResponse::ServiceUnavailable().body("snapshot was dropped before it was taken")
+        }
+    }
+}
+
+/// Response of `POST /verify`
+#[derive(Serialize)]
+pub struct VerifyResponse {
+    pub consistent: bool,
+    /// Height the forest is synced to, if known
+    pub height: Option<u64>,
+    #[serde(flatten)]
+    pub report: SnapshotReport,
+}
+
+/// POST /verify: compare `pollard.bin` with `mem_forest.bin` (plus the delta log) without
+/// changing either; 200 with the comparison, 500 if they can't be read
+pub async fn post_verify(ctx: web::Data<Context>) -> impl Responder {
+    let dir = ctx.data_dir().to_owned();
+    let checked = web::block(move || {
+        let report = verify::verify_working_state(&dir)?;
+        let height = sync_state::read(&dir)?.and_then(|state| state.height);
+        anyhow::Ok(VerifyResponse {
+            consistent: report.is_consistent(),
+            height,
+            report,
+        })
+    })
+    .await;
+    match checked {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("{e:#}")),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
 /// POST /pause
 pub async fn post_pause(ctx: web::Data<Context>) -> impl Responder {
     match ctx.send(Command::Pause).await {
         Ok(_) => HttpResponse::Accepted().finish(),
         Err(DispatchError::InvalidState) => HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => HttpResponse::ServiceUnavailable().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
@@ -48,6 +259,7 @@ pub async fn post_resume(ctx: web::Data<Context>) -> impl Responder {
     match ctx.send(Command::Resume).await {
         Ok(_) => HttpResponse::Accepted().finish(),
         Err(DispatchError::InvalidState) => HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => HttpResponse::ServiceUnavailable().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
@@ -57,6 +269,7 @@ pub async fn post_stop(ctx: web::Data<Context>) -> impl Responder {
     match ctx.send(Command::Stop).await {
         Ok(_) => HttpResponse::Accepted().finish(),
         Err(DispatchError::InvalidState) => HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => HttpResponse::ServiceUnavailable().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
@@ -71,13 +284,76 @@ pub async fn post_update(ctx: web::Data<Context>, req: web::Json<UpdateRequest>)
     match ctx.send(Command::Update(req.height)).await {
         Ok(_) => HttpResponse::Accepted().finish(),
         Err(DispatchError::InvalidState) => HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => HttpResponse::ServiceUnavailable().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 
+/// Largest `POST /block` body accepted: a 4 MB block as hex plus its input leaf hashes.
+pub const BLOCK_REQUEST_LIMIT: usize = 16 << 20;
+
+/// Request to apply a block the service doesn't have to fetch itself
+#[derive(Deserialize)]
+pub struct BlockRequest {
+    pub height: u64,
+    /// Consensus-serialized block as hex
+    pub block: String,
+    /// Leaf hash (hex) of every non-coinbase input, in block order
+    #[serde(default)]
+    pub input_leaves: Vec<String>,
+}
+
+/// POST /block: apply a submitted block once the jobs queued before it have run, without
+/// Bitcoin Core RPC. 200 with the new leaf count and roots; 400 if the request can't be parsed
+/// or the block doesn't fit the forest, 409 if it doesn't extend the synced tip
+pub async fn post_block(ctx: web::Data<Context>, req: web::Json<BlockRequest>) -> impl Responder {
+    let block = match Vec::<u8>::from_hex(&req.block)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| {
+            bitcoin::consensus::deserialize::<Block>(&bytes).map_err(|e| e.to_string())
+        }) {
+        Ok(block) => block,
+        Err(e) => return HttpResponse::BadRequest().body(format!("invalid block: {e}")),
+    };
+    let input_leaves = match req
+        .input_leaves
+        .iter()
+        .map(|hex| <[u8; 32]>::from_hex(hex).map(BitcoinNodeHash::new))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(leaves) => leaves,
+        Err(e) => return HttpResponse::BadRequest().body(format!("invalid input leaf: {e}")),
+    };
+    let outcome = match ctx.submit_block(req.height, block, input_leaves).await {
+        Ok(outcome) => outcome,
+        Err(DispatchError::InvalidState) => return HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => return HttpResponse::ServiceUnavailable().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match outcome.await {
+        Ok(Ok(stump)) => match utreexo::roots_bytes(&stump.roots) {
+            Ok(roots) => HttpResponse::Ok().json(RootsResponse {
+                leaves: stump.leaves,
+                roots: roots
+                    .iter()
+                    .map(|root| root.to_lower_hex_string())
+                    .collect(),
+            }),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        Ok(Err(e @ ApplyBlockError::NotOnTip(_))) => HttpResponse::Conflict().body(e.to_string()),
+        Ok(Err(e @ ApplyBlockError::Invalid(_))) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        // cleared from the queue or the service stopped before the block's turn
+        Err(_) => {
+            HttpResponse::ServiceUnavailable().body("block was dropped before it was applied")
+        }
+    }
+}
+
 /// POST /dump: trigger pollard prune and return 202 Accepted
 pub async fn post_dump(ctx: web::Data<Context>) -> impl Responder {
-    // default dump directory "snapshot" inside current working directory
+    // default dump directory "snapshot" inside the data directory
     match ctx
         .send(Command::Dump {
             dir: PathBuf::from("snapshot"),
@@ -86,20 +362,35 @@ pub async fn post_dump(ctx: web::Data<Context>) -> impl Responder {
     {
         Ok(_) => HttpResponse::Accepted().finish(),
         Err(DispatchError::InvalidState) => HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => HttpResponse::ServiceUnavailable().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 
-/// POST /restore: trigger service to reload state from disk
+/// POST /restore: trigger service to reload state from disk. Answers once the files are
+/// replaced, or with 500 and the reason if the snapshot was refused.
 pub async fn post_restore(ctx: web::Data<Context>) -> impl Responder {
-    match ctx
-        .send(Command::Restore {
-            dir: PathBuf::from("snapshot"),
-        })
-        .await
-    {
-        Ok(_) => HttpResponse::Created().finish(),
+    let outcome = match ctx.restore("snapshot").await {
+        Ok(outcome) => outcome,
+        Err(DispatchError::InvalidState) => return HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => return HttpResponse::ServiceUnavailable().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match outcome.await {
+        Ok(Ok(())) => HttpResponse::Created().finish(),
+        Ok(Err(RestoreError::Busy)) => HttpResponse::Conflict().finish(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        // the service stopped before the restore's turn
+        Err(_) => HttpResponse::ServiceUnavailable().body("restore was dropped before it ran"),
+    }
+}
+
+/// DELETE /queue: drop the builds and updates waiting behind the running job
+pub async fn delete_queue(ctx: web::Data<Context>) -> impl Responder {
+    match ctx.send(Command::ClearQueue).await {
+        Ok(_) => HttpResponse::Accepted().finish(),
         Err(DispatchError::InvalidState) => HttpResponse::Conflict().finish(),
+        Err(DispatchError::Busy) => HttpResponse::ServiceUnavailable().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
@@ -111,7 +402,24 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
         .service(web::resource("/resume").route(web::post().to(post_resume)))
         .service(web::resource("/stop").route(web::post().to(post_stop)))
         .service(web::resource("/update").route(web::post().to(post_update)))
+        .service(
+            web::resource("/block")
+                .app_data(web::JsonConfig::default().limit(BLOCK_REQUEST_LIMIT))
+                .route(web::post().to(post_block)),
+        )
         .service(web::resource("/dump").route(web::post().to(post_dump)))
+        .service(web::resource("/verify").route(web::post().to(post_verify)))
         .service(web::resource("/restore").route(web::post().to(post_restore)))
-        .service(web::resource("/status").route(web::get().to(get_status)));
+        .service(web::resource("/queue").route(web::delete().to(delete_queue)))
+        .service(web::resource("/status").route(web::get().to(get_status)))
+        .service(web::resource("/height").route(web::get().to(get_height)))
+        .service(web::resource("/roots").route(web::get().to(get_roots)))
+        .service(
+            web::resource("/snapshot")
+                .app_data(web::PayloadConfig::new(SNAPSHOT_REQUEST_LIMIT))
+                .route(web::get().to(get_snapshot))
+                .route(web::post().to(post_snapshot)),
+        )
+        .service(web::resource("/healthz").route(web::get().to(get_healthz)))
+        .service(web::resource("/readyz").route(web::get().to(get_readyz)));
 }
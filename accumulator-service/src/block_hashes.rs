@@ -0,0 +1,194 @@
+//! Height → block hash lookup backed by `block_hashes.bin`.
+//!
+//! Leaf hashes commit to the hash of the block that created the UTXO, which UTXO dumps don't
+//! record. The file holds one 32-byte block hash per height, starting at genesis, in internal
+//! byte order.
+//!
+//! Without the file, the hashes can be fetched from Bitcoin Core for just the heights a dump
+//! references (see [`BlockHashes::fetch`]).
+use crate::script_utils::btc_rpc::BitcoinRpc;
+use anyhow::{bail, ensure, Context, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+
+/// Lookup file kept next to `mem_forest.bin`.
+pub const BLOCK_HASHES_FILE: &str = "block_hashes.bin";
+
+/// Block hashes by height. Heights may be missing when only some were fetched.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockHashes(BTreeMap<u64, BlockHash>);
+
+impl BlockHashes {
+    /// `hashes[h]` must be the hash of the block at height `h`.
+    pub fn new(hashes: Vec<BlockHash>) -> Self {
+        Self((0..).zip(hashes).collect())
+    }
+
+    /// Ask the node for the hash of each of `heights`, once per distinct height.
+    pub fn fetch<R: BitcoinRpc>(rpc: &R, heights: &[u64]) -> Result<Self> {
+        Self::fetch_cancellable(rpc, heights, &CancellationToken::new())
+    }
+
+    /// Like [`BlockHashes::fetch`], but checks `cancel` before each request and bails once it
+    /// is cancelled, since a dump can reference hundreds of thousands of heights.
+    pub fn fetch_cancellable<R: BitcoinRpc>(
+        rpc: &R,
+        heights: &[u64],
+        cancel: &CancellationToken,
+    ) -> Result<Self> {
+        let mut hashes = BTreeMap::new();
+        for &height in heights {
+            if let Entry::Vacant(entry) = hashes.entry(height) {
+                if cancel.is_cancelled() {
+                    bail!("block hash fetch cancelled");
+                }
+                let hash = rpc
+                    .get_block_hash(height)
+                    .with_context(|| format!("failed to fetch block hash at height {height}"))?;
+                entry.insert(hash);
+            }
+        }
+        Ok(Self(hashes))
+    }
+
+    /// Read a lookup file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("failed to read {path:?}"))?;
+        ensure!(
+            data.len() % 32 == 0,
+            "{path:?} is {} bytes, not a whole number of block hashes",
+            data.len()
+        );
+        let hashes = data
+            .chunks_exact(32)
+            .map(|chunk| BlockHash::from_slice(chunk).expect("chunk is 32 bytes"))
+            .collect();
+        Ok(Self::new(hashes))
+    }
+
+    /// Write the lookup file. The file has no way to skip a height, so every height from
+    /// genesis up to the highest one must be known.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        ensure!(
+            self.0.keys().copied().eq(0..self.0.len() as u64),
+            "only block hashes for every height from genesis can be saved"
+        );
+        let data: Vec<u8> = self
+            .0
+            .values()
+            .flat_map(|hash| *hash.as_byte_array())
+            .collect();
+        std::fs::write(path, data).with_context(|| format!("failed to write {path:?}"))
+    }
+
+    /// Hash of the block at `height`, if known.
+    pub fn get(&self, height: u64) -> Option<BlockHash> {
+        self.0.get(&height).copied()
+    }
+
+    /// Number of heights with a known hash.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script_utils::btc_rpc::TxOutInfo;
+    use crate::script_utils::parquet::distinct_heights;
+    use anyhow::anyhow;
+    use duckdb::{params, Connection};
+    use std::cell::Cell;
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(BLOCK_HASHES_FILE);
+        let hashes = BlockHashes::new(
+            (0..3u8)
+                .map(|i| BlockHash::from_byte_array([i; 32]))
+                .collect(),
+        );
+        hashes.save(&path).unwrap();
+        let loaded = BlockHashes::load(&path).unwrap();
+        assert_eq!(loaded, hashes);
+        assert_eq!(loaded.get(2), Some(BlockHash::from_byte_array([2; 32])));
+        assert_eq!(loaded.get(3), None);
+
+        std::fs::write(&path, [0u8; 33]).unwrap();
+        assert!(BlockHashes::load(&path).is_err());
+    }
+
+    /// Counts `get_block_hash` calls and answers with the height in every byte.
+    #[derive(Default)]
+    struct CountingRpc(Cell<usize>);
+
+    impl BitcoinRpc for CountingRpc {
+        fn get_block_hash(&self, height: u64) -> Result<BlockHash> {
+            self.0.set(self.0.get() + 1);
+            Ok(BlockHash::from_byte_array([height as u8; 32]))
+        }
+        fn get_block(&self, _hash: &BlockHash) -> Result<bitcoin::Block> {
+            Err(anyhow!("not used"))
+        }
+        fn get_txout(&self, _prevout: &bitcoin::OutPoint) -> Result<TxOutInfo> {
+            Err(anyhow!("not used"))
+        }
+        fn get_block_height(&self, _hash: &BlockHash) -> Result<u32> {
+            Err(anyhow!("not used"))
+        }
+    }
+
+    #[test]
+    fn fetches_only_heights_in_the_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("utxos.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+            [],
+        )
+        .unwrap();
+        for (vout, height) in [(0, 3), (1, 7), (2, 3)] {
+            conn.execute(
+                "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+                params!["a".repeat(64), 1_000, vout, height, vec![0x51u8], false],
+            )
+            .unwrap();
+        }
+        let sql = format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        );
+        conn.execute(&sql, []).unwrap();
+
+        let heights = distinct_heights(&path).unwrap();
+        assert_eq!(heights, vec![3, 7]);
+        let rpc = CountingRpc::default();
+        let hashes = BlockHashes::fetch(&rpc, &heights).unwrap();
+        assert_eq!(rpc.0.get(), 2);
+        assert_eq!(hashes.get(7), Some(BlockHash::from_byte_array([7; 32])));
+        assert_eq!(hashes.get(5), None);
+
+        // a sparse lookup can't be written as a block_hashes.bin
+        assert!(hashes.save(&dir.path().join(BLOCK_HASHES_FILE)).is_err());
+    }
+
+    #[test]
+    fn cancelled_fetch_stops_asking() {
+        let rpc = CountingRpc::default();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        assert!(BlockHashes::fetch_cancellable(&rpc, &[1, 2, 3], &cancel).is_err());
+        assert_eq!(rpc.0.get(), 0);
+    }
+}
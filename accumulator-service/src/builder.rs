@@ -1,31 +1,280 @@
-use crate::script_utils::parquet::get_all_leaf_hashes;
+use crate::block_hashes::{BlockHashes, BLOCK_HASHES_FILE};
+use crate::delta::{self, DELTA_FILE};
+use crate::rpc::CoreRpcClient;
+use crate::script_utils::btc_rpc::BitcoinRpc;
+use crate::script_utils::parquet::{
+    count_rows, distinct_heights, for_each_leaf_batch_cancellable, max_height, sample_rows,
+};
+use crate::script_utils::pollard_conv::prune_to;
+use crate::sync_state::{self, SyncState};
+use crate::{forest, pollard};
 /// Builder logic: load leaf hashes from Parquet, build or resume a MemForest, and serialize it.
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
+use bitcoin::hex::DisplayHex;
+use bitcoin::BlockHash;
+use log::info;
 use rustreexo::accumulator::mem_forest::MemForest;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
-use std::fs::File;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+
+/// Default number of dump rows extracted and added to the forest at a time. A build checks for
+/// cancellation between chunks of this size.
+pub const BUILD_BATCH_SIZE: usize = 1 << 20;
+
+/// Checkpoint written next to `mem_forest.bin` after a successful build.
+pub const CHECKPOINT_FILE: &str = "build_checkpoint.json";
+
+/// Records which UTXO set a finished build corresponds to, so a later resume can refuse to mix
+/// a snapshot with a dump taken at a different block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildCheckpoint {
+    /// Block hash of the UTXO dump the forest was built from, if the caller supplied one.
+    pub block_hash: Option<BlockHash>,
+    /// Number of leaves in the forest when the checkpoint was written.
+    pub leaves: u64,
+}
+
+fn read_checkpoint(path: &Path) -> Result<Option<BuildCheckpoint>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read(path).with_context(|| format!("failed to read {path:?}"))?;
+    let checkpoint =
+        serde_json::from_slice(&data).with_context(|| format!("failed to parse {path:?}"))?;
+    Ok(Some(checkpoint))
+}
+
+/// Work out which snapshot to resume from.
+///
+/// `resume_from` is either a path to a serialized MemForest or, if it parses as a block hash,
+/// the block of a previous build whose checkpoint sits next to `mem_forest.bin` in `dir`.
+/// Either way the snapshot's recorded block must match `dump_block` when both are known.
+fn resume_path(dir: &Path, resume_from: &str, dump_block: Option<BlockHash>) -> Result<PathBuf> {
+    if let Ok(wanted) = resume_from.parse::<BlockHash>() {
+        let recorded = read_checkpoint(&dir.join(CHECKPOINT_FILE))?.and_then(|c| c.block_hash);
+        ensure!(
+            recorded == Some(wanted),
+            "no build checkpoint for block {wanted} (checkpoint is for {recorded:?})"
+        );
+        if let Some(dump) = dump_block {
+            ensure!(
+                dump == wanted,
+                "cannot resume the build for block {wanted} from a dump for block {dump}"
+            );
+        }
+        return Ok(dir.join("mem_forest.bin"));
+    }
+
+    let path = PathBuf::from(resume_from);
+    let recorded =
+        read_checkpoint(&path.with_file_name(CHECKPOINT_FILE))?.and_then(|c| c.block_hash);
+    if let (Some(dump), Some(recorded)) = (dump_block, recorded) {
+        ensure!(
+            dump == recorded,
+            "snapshot {resume_from} was built from block {recorded}, but the dump is for block {dump}"
+        );
+    }
+    Ok(path)
+}
+
+/// What a build from a dump would produce, worked out without building anything.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildEstimate {
+    /// Non-coinbase rows in the dump: the leaves the build adds, unspendable scripts aside.
+    pub rows: u64,
+    /// Projected size of `mem_forest.bin` in bytes (see [`forest::serialized_size`]).
+    pub forest_bytes: u64,
+}
+
+/// Estimate a build from `parquet` by counting its rows, which is far cheaper than extracting
+/// the leaves.
+pub fn estimate(parquet: &str) -> Result<BuildEstimate> {
+    let rows = count_rows(parquet)?;
+    Ok(BuildEstimate {
+        rows,
+        forest_bytes: forest::serialized_size(rows),
+    })
+}
+
+/// Cross-check `count` randomly sampled Parquet rows against the node's copy of the same
+/// outputs, failing on the first row whose amount or script differs. Catches a corrupt export
+/// before it turns into a wrong accumulator.
+pub fn validate_sample<R: BitcoinRpc>(rpc: &R, parquet: &str, count: usize) -> Result<()> {
+    let rows = sample_rows(parquet, count)
+        .with_context(|| format!("failed to sample rows from {parquet}"))?;
+    for row in rows {
+        let txout = rpc
+            .get_txout(&row.prevout)
+            .with_context(|| format!("failed to fetch {} from Bitcoin Core", row.prevout))?;
+        ensure!(
+            txout.value == row.amount && txout.script == row.script,
+            "Parquet row {} does not match Bitcoin Core: amount {} vs {}, script {} vs {}",
+            row.prevout,
+            row.amount,
+            txout.value,
+            row.script.to_lower_hex_string(),
+            txout.script.to_lower_hex_string(),
+        );
+    }
+    Ok(())
+}
 
 /// Start building the accumulator from a Parquet dump, optionally resuming from an existing snapshot.
-/// On success writes out `mem_forest.bin` in the current directory.
-pub async fn start_build(parquet: &str, resume_from: Option<&str>) -> Result<()> {
+/// `block_hash` is the block the dump was taken at, used to validate resumes and recorded in
+/// the checkpoint. With `validate_sample`, that many rows are first checked against Bitcoin
+/// Core (see [`validate_sample`]), connecting via `BITCOIN_CORE_RPC_URL` and
+/// `BITCOIN_CORE_COOKIE_FILE`.
+/// Block hashes for the leaves are read from `block_hashes.bin` in the data directory `dir` or,
+/// failing that, fetched from Bitcoin Core for just the heights the dump references.
+/// On success writes out `mem_forest.bin`, its checkpoint and the sync state in `dir`.
+///
+/// With `keep_op_return`, OP_RETURN outputs become leaves too (see [`utreexo::is_excluded`]).
+/// The choice is recorded in the sync state, so later updates apply the same policy.
+///
+/// The dump is read and added to the forest `batch_size` rows at a time ([`BUILD_BATCH_SIZE`]
+/// by default): smaller batches use less memory, larger ones fewer queries. The build runs on
+/// a blocking thread and checks `cancel` between batches; a cancelled build returns `Ok`
+/// without writing anything.
+#[allow(clippy::too_many_arguments)]
+pub async fn start_build(
+    dir: &Path,
+    parquet: &str,
+    resume_from: Option<&str>,
+    block_hash: Option<&str>,
+    validate_sample: Option<usize>,
+    batch_size: Option<usize>,
+    keep_op_return: bool,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let batch_size = batch_size.unwrap_or(BUILD_BATCH_SIZE);
+    ensure!(batch_size > 0, "batch size must be greater than zero");
+    let dir = dir.to_owned();
+    let parquet = parquet.to_owned();
+    let resume_from = resume_from.map(str::to_owned);
+    let block_hash = block_hash.map(str::to_owned);
+    tokio::task::spawn_blocking(move || {
+        build(
+            &dir,
+            &parquet,
+            resume_from.as_deref(),
+            block_hash.as_deref(),
+            validate_sample,
+            batch_size,
+            keep_op_return,
+            &cancel,
+        )
+    })
+    .await?
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build(
+    dir: &Path,
+    parquet: &str,
+    resume_from: Option<&str>,
+    block_hash: Option<&str>,
+    validate_sample: Option<usize>,
+    batch_size: usize,
+    keep_op_return: bool,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let dump_block = block_hash
+        .map(|h| h.parse::<BlockHash>())
+        .transpose()
+        .context("invalid dump block hash")?;
+    if let Some(count) = validate_sample {
+        let rpc = CoreRpcClient::from_env()?;
+        self::validate_sample(&rpc, parquet, count)?;
+    }
     // Load existing forest or create new
-    let mut forest: MemForest<BitcoinNodeHash> = if let Some(path) = resume_from {
-        let mut f = File::open(path).with_context(|| format!("failed to open snapshot: {path}"))?;
-        MemForest::deserialize(&mut f).context("failed to deserialize existing MemForest")?
+    let mut forest: MemForest<BitcoinNodeHash> = if let Some(resume_from) = resume_from {
+        let path = resume_path(dir, resume_from, dump_block)?;
+        delta::load_forest(&path, &path.with_file_name(DELTA_FILE))
+            .context("failed to load existing MemForest")?
     } else {
         MemForest::new()
     };
-    // Extract all leaf hashes from the Parquet file
-    let leaves = get_all_leaf_hashes(parquet)
-        .with_context(|| format!("failed to extract leaf hashes from {parquet}"))?;
-    // Apply all leaves as additions (initial build)
-    forest
-        .modify(&leaves, &[])
-        .map_err(|e| anyhow::anyhow!("failed to insert leaves into MemForest: {}", e))?;
-    // Serialize the updated forest to disk
-    let mut out = File::create("mem_forest.bin").context("failed to create mem_forest.bin")?;
-    forest
-        .serialize(&mut out)
-        .context("failed to serialize MemForest")?;
+    // Leaves commit to their creating block's hash, which the dump doesn't record. Without it
+    // every leaf would commit to the wrong block, so there is no fallback
+    let block_hashes_file = dir.join(BLOCK_HASHES_FILE);
+    let block_hashes = if block_hashes_file.exists() {
+        BlockHashes::load(&block_hashes_file)?
+    } else {
+        let rpc = CoreRpcClient::from_env().with_context(|| {
+            format!(
+                "no {BLOCK_HASHES_FILE} in {dir:?} and Bitcoin Core is not configured to fetch \
+                 block hashes from"
+            )
+        })?;
+        // Only the heights the dump references, not every block up to the tip
+        let heights = distinct_heights(parquet)?;
+        info!("fetching {} block hashes from Bitcoin Core", heights.len());
+        match BlockHashes::fetch_cancellable(&rpc, &heights, cancel) {
+            Err(_) if cancel.is_cancelled() => {
+                info!("build from {parquet} cancelled, nothing written");
+                return Ok(());
+            }
+            fetched => fetched.context("failed to fetch the dump's block hashes")?,
+        }
+    };
+    // Stream the dump's leaf hashes into the forest in batches, so the full leaf set is never
+    // held in memory next to the forest
+    let extracted = for_each_leaf_batch_cancellable(
+        parquet,
+        Some(&block_hashes),
+        keep_op_return,
+        batch_size,
+        cancel,
+        |leaves| {
+            forest
+                .modify(leaves, &[])
+                .map_err(|e| anyhow::anyhow!("failed to insert leaves into MemForest: {}", e))
+        },
+    );
+    if cancel.is_cancelled() {
+        info!("build from {parquet} cancelled, nothing written");
+        return Ok(());
+    }
+    extracted.with_context(|| format!("failed to extract leaf hashes from {parquet}"))?;
+    // Serialize the updated forest to disk; it supersedes any delta log
+    let height = max_height(parquet)?;
+    delta::write_snapshot(
+        &forest,
+        height,
+        &dir.join("mem_forest.bin"),
+        &dir.join(DELTA_FILE),
+    )?;
+    let checkpoint = BuildCheckpoint {
+        block_hash: dump_block,
+        leaves: forest.leaves,
+    };
+    std::fs::write(dir.join(CHECKPOINT_FILE), serde_json::to_vec(&checkpoint)?)
+        .context("failed to write build checkpoint")?;
+    // /roots and /snapshot serve pollard.bin, so it gets the new roots too, renamed into place
+    // like the forest
+    let pollard = prune_to(&forest, &[]).context("failed to prune forest to Pollard")?;
+    let bytes = match height {
+        Some(height) => pollard::encode_with_meta(&pollard, height, dump_block, keep_op_return)?,
+        // an empty export doesn't tell which block it was taken at
+        None => {
+            let mut buf = Vec::new();
+            pollard
+                .serialize(&mut buf)
+                .context("failed to serialize Pollard")?;
+            buf
+        }
+    };
+    pollard::save(dir, &bytes)?;
+    sync_state::write(
+        dir,
+        &SyncState {
+            height,
+            block_hash: dump_block,
+            keep_op_return,
+            unresolved_prevouts: 0,
+        },
+    )?;
     Ok(())
 }
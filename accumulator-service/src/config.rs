@@ -0,0 +1,68 @@
+//! Startup configuration for the HTTP server.
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Address the server listens on when neither `--bind` nor `ACC_SERVICE_BIND` is given.
+pub const DEFAULT_BIND: &str = "127.0.0.1:8080";
+
+/// Directory the service keeps its files in when neither `--data-dir` nor
+/// `ACC_SERVICE_DATA_DIR` is given: the current directory.
+pub const DEFAULT_DATA_DIR: &str = ".";
+
+/// Settings the server binary needs before it starts listening.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceConfig {
+    pub bind: SocketAddr,
+    /// Where `mem_forest.bin`, `pollard.bin` and the other working files live
+    pub data_dir: PathBuf,
+}
+
+impl ServiceConfig {
+    /// Build a config from a `host:port` string, e.g. from `--bind` or `ACC_SERVICE_BIND`,
+    /// keeping files in [`DEFAULT_DATA_DIR`].
+    pub fn from_bind(bind: &str) -> Result<Self> {
+        let bind = bind
+            .parse()
+            .map_err(|e| anyhow!("invalid bind address {bind:?} (expected host:port): {e}"))?;
+        Ok(Self {
+            bind,
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+        })
+    }
+
+    /// Keep the service's files in `data_dir` instead.
+    pub fn with_data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self::from_bind(DEFAULT_BIND).expect("default bind address is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bind_address() {
+        let config = ServiceConfig::from_bind("0.0.0.0:9090").unwrap();
+        assert_eq!(config.bind, "0.0.0.0:9090".parse().unwrap());
+        assert_eq!(ServiceConfig::default().bind.to_string(), DEFAULT_BIND);
+        assert_eq!(config.data_dir, PathBuf::from(DEFAULT_DATA_DIR));
+        let config = config.with_data_dir("/var/lib/acc");
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/acc"));
+    }
+
+    #[test]
+    fn invalid_bind_address_is_rejected() {
+        for bad in ["localhost", "127.0.0.1", "127.0.0.1:notaport", ""] {
+            let err = ServiceConfig::from_bind(bad).unwrap_err().to_string();
+            assert!(err.contains("invalid bind address"), "{err}");
+        }
+    }
+}
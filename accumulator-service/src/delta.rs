@@ -0,0 +1,392 @@
+//! Append-only log of per-block forest changes, so an update only writes what the block changed
+//! instead of rewriting the whole `mem_forest.bin`.
+//!
+//! The current forest is the last full snapshot with every logged block replayed on top. Once
+//! the log holds [`SNAPSHOT_INTERVAL`] blocks, the forest is written out as a new snapshot and
+//! the log is cleared.
+//!
+//! Each record is `height: u64 | adds: u32 | dels: u32` (little-endian) followed by the added
+//! and then the deleted hashes, 32 bytes each.
+//!
+//! Each snapshot has a [`SnapshotMeta`] sidecar, so records a crash left behind in the log are
+//! not replayed onto a snapshot that already contains them.
+use crate::proof::fingerprint;
+use anyhow::{anyhow, ensure, Context, Result};
+use bitcoin::hashes::sha256;
+use log::warn;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Delta log kept next to `mem_forest.bin`.
+pub const DELTA_FILE: &str = "forest.delta";
+
+/// Number of logged blocks after which the full snapshot is rewritten (about a day of blocks).
+pub const SNAPSHOT_INTERVAL: usize = 144;
+
+/// The changes one block made to the forest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaRecord {
+    pub height: u64,
+    pub adds: Vec<BitcoinNodeHash>,
+    pub dels: Vec<BitcoinNodeHash>,
+}
+
+impl DeltaRecord {
+    fn write<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_all(&self.height.to_le_bytes())?;
+        w.write_all(&(self.adds.len() as u32).to_le_bytes())?;
+        w.write_all(&(self.dels.len() as u32).to_le_bytes())?;
+        for hash in self.adds.iter().chain(&self.dels) {
+            w.write_all(&**hash)?;
+        }
+        Ok(())
+    }
+
+    /// Size of the record in the log.
+    fn encoded_len(&self) -> u64 {
+        16 + 32 * (self.adds.len() + self.dels.len()) as u64
+    }
+
+    /// Read the next record, or `None` once the log runs out, either at the end of the last
+    /// record or part way through one.
+    fn read<R: Read>(mut r: R) -> io::Result<Option<Self>> {
+        match Self::read_record(&mut r) {
+            Ok(record) => Ok(Some(record)),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_record<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut height = [0u8; 8];
+        r.read_exact(&mut height)?;
+        let mut len = [0u8; 4];
+        r.read_exact(&mut len)?;
+        let adds = u32::from_le_bytes(len) as usize;
+        r.read_exact(&mut len)?;
+        let dels = u32::from_le_bytes(len) as usize;
+        let mut read_hashes = |n: usize| -> io::Result<Vec<BitcoinNodeHash>> {
+            (0..n)
+                .map(|_| {
+                    let mut hash = [0u8; 32];
+                    r.read_exact(&mut hash)?;
+                    Ok(BitcoinNodeHash::new(hash))
+                })
+                .collect()
+        };
+        Ok(DeltaRecord {
+            height: u64::from_le_bytes(height),
+            adds: read_hashes(adds)?,
+            dels: read_hashes(dels)?,
+        })
+    }
+}
+
+/// What the log needs to know about the snapshot it extends, kept next to it as
+/// `<snapshot>.meta.json`.
+///
+/// A snapshot is renamed into place before the log is cleared, so a crash in between leaves
+/// records the new snapshot already contains; they are at or below `height` and are skipped on
+/// replay. The sidecar is written just before the rename, so it is only trusted while
+/// `fingerprint` matches the snapshot actually in place.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SnapshotMeta {
+    /// Last block the snapshot contains, if it is known
+    height: Option<u64>,
+    /// [`fingerprint`] of the snapshot's leaf count and roots
+    fingerprint: sha256::Hash,
+    /// Records appended to the log since the snapshot. May be one short after a crash, which
+    /// only delays the next snapshot by a block.
+    logged: usize,
+}
+
+/// Where the [`SnapshotMeta`] sidecar of `snapshot` is kept. A copy of the snapshot needs it
+/// too, or the copy's log is replayed without knowing which records the snapshot contains.
+pub fn meta_path(snapshot: &Path) -> PathBuf {
+    snapshot.with_extension("meta.json")
+}
+
+fn read_meta(snapshot: &Path) -> Result<Option<SnapshotMeta>> {
+    let path = meta_path(snapshot);
+    match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .with_context(|| format!("failed to parse {path:?}")),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {path:?}")),
+    }
+}
+
+fn write_meta(snapshot: &Path, meta: &SnapshotMeta) -> Result<()> {
+    let path = meta_path(snapshot);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(meta)?)
+        .with_context(|| format!("failed to write {tmp:?}"))?;
+    fs::rename(&tmp, &path).with_context(|| format!("failed to replace {path:?}"))
+}
+
+fn state_of(forest: &MemForest<BitcoinNodeHash>) -> sha256::Hash {
+    let roots: Vec<_> = forest.get_roots().iter().map(|r| r.get_data()).collect();
+    fingerprint(forest.leaves, &roots)
+}
+
+/// Read every record in the log at `path`; a missing log is empty.
+///
+/// A crash while a record was being appended leaves it cut short at the end of the log. That
+/// block was never committed, so the torn record is ignored with a warning (and dropped from
+/// the file by the next [`commit`]).
+pub fn read_all(path: &Path) -> Result<Vec<DeltaRecord>> {
+    Ok(read_log(path)?.0)
+}
+
+/// [`read_all`], plus the length of the log up to the end of its last whole record.
+fn read_log(path: &Path) -> Result<(Vec<DeltaRecord>, u64)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e).with_context(|| format!("failed to open {path:?}")),
+    };
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut records = Vec::new();
+    let mut len = 0;
+    while let Some(record) =
+        DeltaRecord::read(&mut reader).with_context(|| format!("failed to read {path:?}"))?
+    {
+        len += record.encoded_len();
+        records.push(record);
+    }
+    if len < file_len {
+        warn!(
+            "ignoring a torn record in {path:?}: {} bytes after the last whole one",
+            file_len - len
+        );
+    }
+    Ok((records, len))
+}
+
+/// Load the snapshot at `snapshot` and replay the log at `delta` on top of it, skipping
+/// records the snapshot already contains.
+pub fn load_forest(snapshot: &Path, delta: &Path) -> Result<MemForest<BitcoinNodeHash>> {
+    Ok(load_forest_at(snapshot, delta)?.0)
+}
+
+/// [`load_forest`], plus the last block the forest contains: the last one replayed from the
+/// log, or else the snapshot's, if either is known.
+///
+/// The log is committed before `sync_state.json` is written, so after a crash in between this
+/// is ahead of the sync state, and the block it names must not be applied again.
+pub fn load_forest_at(
+    snapshot: &Path,
+    delta: &Path,
+) -> Result<(MemForest<BitcoinNodeHash>, Option<u64>)> {
+    let mut f = File::open(snapshot).with_context(|| format!("failed to open {snapshot:?}"))?;
+    let mut forest = MemForest::deserialize(&mut f).context("failed to deserialize MemForest")?;
+    let mut contained = match read_meta(snapshot)? {
+        Some(meta) if meta.fingerprint == state_of(&forest) => meta.height,
+        _ => None,
+    };
+    for record in read_all(delta)? {
+        if contained.is_some_and(|height| record.height <= height) {
+            continue;
+        }
+        forest
+            .modify(&record.adds, &record.dels)
+            .map_err(|e| anyhow!("failed to replay block {}: {}", record.height, e))?;
+        contained = Some(record.height);
+    }
+    Ok((forest, contained))
+}
+
+/// Write `forest`, which contains every block up to `height`, as the new snapshot and clear the
+/// log. The snapshot is written to a temporary file and renamed into place, so a crash never
+/// leaves a half-written snapshot next to a log that was meant for the previous one.
+pub fn write_snapshot(
+    forest: &MemForest<BitcoinNodeHash>,
+    height: Option<u64>,
+    snapshot: &Path,
+    delta: &Path,
+) -> Result<()> {
+    let tmp = snapshot.with_extension("tmp");
+    let mut out = File::create(&tmp).with_context(|| format!("failed to create {tmp:?}"))?;
+    forest
+        .serialize(&mut out)
+        .context("failed to serialize MemForest")?;
+    out.sync_all()?;
+    write_meta(
+        snapshot,
+        &SnapshotMeta {
+            height,
+            fingerprint: state_of(forest),
+            logged: 0,
+        },
+    )?;
+    fs::rename(&tmp, snapshot).with_context(|| format!("failed to replace {snapshot:?}"))?;
+    match fs::remove_file(delta) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to clear {delta:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Record that `record` was applied to `forest`. The record is appended to the log; once the
+/// log holds `interval` blocks the full snapshot is rewritten instead. Returns whether the
+/// snapshot was rewritten.
+pub fn commit(
+    forest: &MemForest<BitcoinNodeHash>,
+    record: &DeltaRecord,
+    snapshot: &Path,
+    delta: &Path,
+    interval: usize,
+) -> Result<bool> {
+    ensure!(interval > 0, "snapshot interval must be greater than zero");
+    let meta = read_meta(snapshot)?;
+    let logged = match &meta {
+        Some(meta) => meta.logged,
+        // a snapshot from before the sidecar existed
+        None => read_all(delta)?.len(),
+    };
+    if logged + 1 >= interval {
+        write_snapshot(forest, Some(record.height), snapshot, delta)?;
+        return Ok(true);
+    }
+    let (_, whole) = read_log(delta)?;
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(delta)
+        .with_context(|| format!("failed to open {delta:?}"))?;
+    // drop a torn record left by a crash, so this one starts where the last whole one ends
+    log.set_len(whole)?;
+    let mut buf = Vec::new();
+    record.write(&mut buf)?;
+    log.write_all(&buf)?;
+    log.sync_data()?;
+    if let Some(mut meta) = meta {
+        meta.logged += 1;
+        write_meta(snapshot, &meta)?;
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(range: std::ops::Range<u8>) -> Vec<BitcoinNodeHash> {
+        range.map(|i| BitcoinNodeHash::new([i; 32])).collect()
+    }
+
+    /// Five blocks, each adding four leaves and spending one from the block before.
+    fn blocks() -> Vec<DeltaRecord> {
+        (0..5u8)
+            .map(|b| DeltaRecord {
+                height: 100 + u64::from(b),
+                adds: hashes(b * 4 + 1..b * 4 + 5),
+                dels: if b == 0 {
+                    vec![]
+                } else {
+                    hashes(b * 4 - 2..b * 4 - 1)
+                },
+            })
+            .collect()
+    }
+
+    fn roots(forest: &MemForest<BitcoinNodeHash>) -> Vec<BitcoinNodeHash> {
+        forest.get_roots().iter().map(|r| r.get_data()).collect()
+    }
+
+    /// Apply `blocks()` through the log, starting from an empty snapshot, and compare the
+    /// reloaded forest with a from-scratch build.
+    fn replay_matches_scratch(interval: usize) -> Vec<DeltaRecord> {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("mem_forest.bin");
+        let delta = dir.path().join(DELTA_FILE);
+        write_snapshot(&MemForest::new(), None, &snapshot, &delta).unwrap();
+
+        let mut scratch = MemForest::<BitcoinNodeHash>::new();
+        for block in blocks() {
+            // a fresh load per block, like the updater after a restart
+            let mut forest = load_forest(&snapshot, &delta).unwrap();
+            forest.modify(&block.adds, &block.dels).unwrap();
+            commit(&forest, &block, &snapshot, &delta, interval).unwrap();
+            scratch.modify(&block.adds, &block.dels).unwrap();
+        }
+
+        let replayed = load_forest(&snapshot, &delta).unwrap();
+        assert_eq!(replayed.leaves, scratch.leaves);
+        assert_eq!(roots(&replayed), roots(&scratch));
+        read_all(&delta).unwrap()
+    }
+
+    #[test]
+    fn replayed_log_matches_scratch_build() {
+        let logged = replay_matches_scratch(SNAPSHOT_INTERVAL);
+        assert_eq!(logged, blocks());
+    }
+
+    #[test]
+    fn snapshot_is_rewritten_every_interval() {
+        // blocks 2 and 4 trigger a rewrite, leaving only block 5 in the log
+        let logged = replay_matches_scratch(2);
+        assert_eq!(logged, blocks()[4..]);
+    }
+
+    #[test]
+    fn crash_before_clearing_log_does_not_replay_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("mem_forest.bin");
+        let delta = dir.path().join(DELTA_FILE);
+        write_snapshot(&MemForest::new(), None, &snapshot, &delta).unwrap();
+        let mut forest = load_forest(&snapshot, &delta).unwrap();
+        for block in &blocks()[..3] {
+            forest.modify(&block.adds, &block.dels).unwrap();
+            commit(&forest, block, &snapshot, &delta, SNAPSHOT_INTERVAL).unwrap();
+        }
+        assert_eq!(read_meta(&snapshot).unwrap().unwrap().logged, 3);
+        let old_snapshot = fs::read(&snapshot).unwrap();
+        let old_log = fs::read(&delta).unwrap();
+        write_snapshot(&forest, Some(102), &snapshot, &delta).unwrap();
+
+        // crash after the rename: the log still holds blocks the snapshot contains
+        fs::write(&delta, &old_log).unwrap();
+        let reloaded = load_forest(&snapshot, &delta).unwrap();
+        assert_eq!(roots(&reloaded), roots(&forest));
+
+        // crash before the rename: the new sidecar doesn't describe the old snapshot
+        fs::write(&snapshot, &old_snapshot).unwrap();
+        let reloaded = load_forest(&snapshot, &delta).unwrap();
+        assert_eq!(roots(&reloaded), roots(&forest));
+    }
+
+    #[test]
+    fn torn_last_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("mem_forest.bin");
+        let delta = dir.path().join(DELTA_FILE);
+        write_snapshot(&MemForest::new(), None, &snapshot, &delta).unwrap();
+        let mut forest = load_forest(&snapshot, &delta).unwrap();
+        for block in &blocks()[..2] {
+            forest.modify(&block.adds, &block.dels).unwrap();
+            commit(&forest, block, &snapshot, &delta, SNAPSHOT_INTERVAL).unwrap();
+        }
+
+        // a crash part way through appending the second block
+        let log = fs::read(&delta).unwrap();
+        fs::write(&delta, &log[..log.len() - 5]).unwrap();
+        assert_eq!(read_all(&delta).unwrap(), blocks()[..1]);
+        let mut forest = load_forest(&snapshot, &delta).unwrap();
+
+        // retrying the block replaces the torn record
+        let block = &blocks()[1];
+        forest.modify(&block.adds, &block.dels).unwrap();
+        commit(&forest, block, &snapshot, &delta, SNAPSHOT_INTERVAL).unwrap();
+        assert_eq!(fs::read(&delta).unwrap(), log);
+        assert_eq!(read_all(&delta).unwrap(), blocks()[..2]);
+    }
+}
@@ -0,0 +1,541 @@
+//! MemForest helpers built on top of rustreexo's public API.
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::{AccumulatorHash, BitcoinNodeHash};
+use std::fmt;
+use std::io::{self, Cursor, Read};
+
+/// Add `values` to the forest and return the position of every inserted leaf, in input order.
+///
+/// Additions never move existing leaves and a new leaf stays on row 0, where its position is
+/// its insertion index. The returned positions therefore hold once the whole batch, including
+/// any root merging, has been applied (until a later deletion moves leaves up).
+pub fn add_with_positions(
+    forest: &mut MemForest<BitcoinNodeHash>,
+    values: &[BitcoinNodeHash],
+) -> Result<Vec<u64>> {
+    let start = forest.leaves;
+    forest
+        .modify(values, &[])
+        .map_err(|e| anyhow!("failed to add leaves to MemForest: {}", e))?;
+    Ok((start..start + values.len() as u64).collect())
+}
+
+/// Why a position could not be resolved to a hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrabError {
+    /// The position lies outside the populated part of the forest.
+    OutOfRange(u64),
+    /// The position is in range but holds no node, e.g. because its leaf was deleted.
+    NotFound(u64),
+}
+
+impl fmt::Display for GrabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrabError::OutOfRange(pos) => write!(f, "position {pos} is out of range"),
+            GrabError::NotFound(pos) => write!(f, "no node at position {pos}"),
+        }
+    }
+}
+
+impl std::error::Error for GrabError {}
+
+/// Number of rows of a forest with `leaves` leaves, as rustreexo computes it.
+pub(crate) fn tree_rows(leaves: u64) -> u8 {
+    if leaves == 0 {
+        0
+    } else {
+        (64 - (leaves - 1).leading_zeros()) as u8
+    }
+}
+
+/// First position on `row` in a forest with `rows` rows.
+pub(crate) fn row_offset(row: u8, rows: u8) -> u64 {
+    (2u64 << rows) - (2u64 << (rows - row))
+}
+
+/// Split a position into its `(row, index within row)`, or `None` if it lies past the forest.
+pub(crate) fn row_and_index(pos: u64, rows: u8) -> Option<(u8, u64)> {
+    (0..=rows)
+        .find(|&row| pos < row_offset(row, rows) + (1u64 << (rows - row)))
+        .map(|row| (row, pos - row_offset(row, rows)))
+}
+
+/// Whether `pos` lies within the populated trees of `forest`.
+fn in_range(forest: &MemForest<BitcoinNodeHash>, pos: u64) -> bool {
+    match row_and_index(pos, tree_rows(forest.leaves)) {
+        Some((row, index)) => index < forest.leaves >> row,
+        None => false,
+    }
+}
+
+/// Descend to `pos`, which must be [`in_range`]: the hashes of the node and of its sibling.
+fn grab(
+    forest: &MemForest<BitcoinNodeHash>,
+    pos: u64,
+) -> Result<(BitcoinNodeHash, BitcoinNodeHash), GrabError> {
+    let (node, sibling, _) = forest
+        .grab_node(pos)
+        .map_err(|_| GrabError::NotFound(pos))?;
+    Ok((node.get_data(), sibling.get_data()))
+}
+
+/// `hash` as found at `pos`, unless the node there is deleted or empty.
+fn populated(hash: BitcoinNodeHash, pos: u64) -> Result<BitcoinNodeHash, GrabError> {
+    match hash {
+        BitcoinNodeHash::Some(_) => Ok(hash),
+        _ => Err(GrabError::NotFound(pos)),
+    }
+}
+
+/// Resolve the hash stored at `pos`.
+///
+/// Unlike calling `MemForest::grab_node` directly, positions outside the populated trees are
+/// rejected before touching the forest (`grab_node` indexes the roots unchecked), and deleted or
+/// empty nodes are reported as [`GrabError::NotFound`] instead of being returned as a hash.
+pub fn hash_at(
+    forest: &MemForest<BitcoinNodeHash>,
+    pos: u64,
+) -> Result<BitcoinNodeHash, GrabError> {
+    if !in_range(forest, pos) {
+        return Err(GrabError::OutOfRange(pos));
+    }
+    populated(grab(forest, pos)?.0, pos)
+}
+
+/// Resolve many positions at once, like [`hash_at`] for each, with the results in input order.
+///
+/// Each distinct position is looked up once, in ascending order, however often it is listed.
+/// MemForest doesn't expose a node's children, so descents can't follow a shared path down
+/// the tree, but `grab_node` returns a node's sibling with it: a left node and its right
+/// sibling, the pairs a proof asks for, are resolved by a single descent.
+pub fn hashes_at(
+    forest: &MemForest<BitcoinNodeHash>,
+    positions: &[u64],
+) -> Result<Vec<BitcoinNodeHash>, GrabError> {
+    let mut sorted = positions.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut resolved = Vec::with_capacity(sorted.len());
+    let mut rest = sorted.iter().copied().peekable();
+    while let Some(pos) = rest.next() {
+        if !in_range(forest, pos) {
+            return Err(GrabError::OutOfRange(pos));
+        }
+        let (hash, sibling) = grab(forest, pos)?;
+        resolved.push(populated(hash, pos)?);
+        // positions and row indices share their parity, and a left node whose right
+        // neighbour is populated isn't a root, so that neighbour is its sibling
+        if pos % 2 == 0 && rest.peek() == Some(&(pos + 1)) && in_range(forest, pos + 1) {
+            rest.next();
+            resolved.push(populated(sibling, pos + 1)?);
+        }
+    }
+    Ok(positions
+        .iter()
+        .map(|pos| resolved[sorted.binary_search(pos).expect("position was resolved")])
+        .collect())
+}
+
+/// Delete the leaves at `positions` rather than by hash.
+///
+/// Every position is resolved to its hash before anything is deleted, so positions that move
+/// as earlier deletions are applied don't matter: they all refer to the forest as it is now.
+/// MemForest itself still deletes by hash, so a leaf whose hash is shared with another leaf
+/// can't be addressed exactly; that is reported as an error instead of deleting the wrong one.
+pub fn delete_by_position(
+    forest: &mut MemForest<BitcoinNodeHash>,
+    positions: &[u64],
+) -> Result<()> {
+    let mut wanted = positions.to_vec();
+    wanted.sort_unstable();
+    if let Some(pair) = wanted.windows(2).find(|pair| pair[0] == pair[1]) {
+        bail!("position {} is listed twice", pair[0]);
+    }
+    let hashes = positions
+        .iter()
+        .map(|&pos| hash_at(forest, pos))
+        .collect::<Result<Vec<_>, _>>()?;
+    // the proof's targets are where the forest's own lookup puts each hash
+    let mut targets = forest
+        .prove(&hashes)
+        .map_err(|e| anyhow!("no leaf at the given positions: {e:?}"))?
+        .targets;
+    targets.sort_unstable();
+    if let Some((pos, _)) = wanted
+        .iter()
+        .zip(&targets)
+        .find(|(pos, target)| pos != target)
+    {
+        bail!("the leaf at position {pos} shares its hash with another leaf");
+    }
+    forest
+        .modify(&[], &hashes)
+        .map_err(|e| anyhow!("failed to delete leaves from MemForest: {}", e))
+}
+
+// Serialized MemForest layout (rustreexo 0.4), all integers little-endian:
+//
+//   leaves: u64 | roots_len: u64 | roots_len trees
+//
+// Each tree is written pre-order. A node is an 8-byte type tag (0 = branch, 1 = leaf) followed
+// by its hash: a one-byte variant tag, then the 32 hash bytes for a regular hash (tag 2) or
+// nothing for an empty one (tag 0, the root of a tree whose leaves were all deleted). Tag 1 is
+// rustreexo's placeholder, which only exists while it computes roots and is never valid in a
+// snapshot. A branch is followed by its left subtree, then its right subtree; a leaf ends the
+// recursion. Deleting a leaf moves its sibling up in place, so a branch always has both
+// children.
+
+/// Size in bytes of a serialized MemForest with `leaves` leaves added and none deleted: the
+/// 16-byte header plus 41 bytes (type tag, hash tag, hash) for each of the `2 * leaves -
+/// roots` nodes. Deletions only shrink the forest, so this is an upper bound for any forest
+/// with that many leaves added.
+pub fn serialized_size(leaves: u64) -> u64 {
+    16 + 41 * (2 * leaves - u64::from(leaves.count_ones()))
+}
+
+/// Read the `leaves` / `roots_len` header of a serialized MemForest without loading any nodes,
+/// and check that the root count matches the leaf count (one root per set bit). Returns the
+/// declared number of leaves.
+pub fn read_header<R: Read>(mut reader: R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    let leaves = u64::from_le_bytes(buf);
+    reader.read_exact(&mut buf)?;
+    let roots = u64::from_le_bytes(buf);
+    if roots != u64::from(leaves.count_ones()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{leaves} leaves cannot have {roots} roots"),
+        ));
+    }
+    Ok(leaves)
+}
+
+/// Read one serialized node and its subtree, checking that every branch's stored hash is the
+/// parent hash of its children. Returns the node's hash.
+///
+/// The subtree may be at most `rows` rows tall: no tree in the forest is taller, and the input
+/// may be untrusted, so nesting beyond that is rejected before it can exhaust the stack.
+fn verify_node<R: Read>(reader: &mut R, rows: u8) -> Result<BitcoinNodeHash> {
+    let mut tag = [0u8; 8];
+    reader.read_exact(&mut tag)?;
+    let hash = <BitcoinNodeHash as AccumulatorHash>::read(reader)?;
+    ensure!(
+        hash != BitcoinNodeHash::Placeholder,
+        "placeholder hash in serialized forest"
+    );
+    match u64::from_le_bytes(tag) {
+        0 => {
+            ensure!(rows > 0, "branch nested deeper than the forest's rows");
+            let left = verify_node(reader, rows - 1)?;
+            let right = verify_node(reader, rows - 1)?;
+            let expected = <BitcoinNodeHash as AccumulatorHash>::parent_hash(&left, &right);
+            ensure!(
+                hash == expected,
+                "branch hash {hash} does not match its children (expected {expected})"
+            );
+        }
+        1 => {}
+        other => bail!("unknown node type {other}"),
+    }
+    Ok(hash)
+}
+
+/// Deserialize a MemForest after checking that it is internally consistent: every branch hash
+/// must be the parent hash of its two children, so the roots really commit to the leaves.
+///
+/// `MemForest::deserialize` trusts the stored hashes, so a crafted file could claim arbitrary
+/// roots. Use this for snapshots that come from outside the service. It costs one extra pass
+/// over the bytes and a hash per branch.
+pub fn deserialize_verified(bytes: &[u8]) -> Result<MemForest<BitcoinNodeHash>> {
+    let mut reader = Cursor::new(bytes);
+    let leaves = read_header(&mut reader).context("invalid MemForest header")?;
+    for tree in 0..leaves.count_ones() {
+        verify_node(&mut reader, tree_rows(leaves))
+            .with_context(|| format!("tree {tree} is inconsistent"))?;
+    }
+    ensure!(
+        reader.position() == bytes.len() as u64,
+        "{} trailing bytes after the last tree",
+        bytes.len() as u64 - reader.position()
+    );
+    MemForest::deserialize(bytes).context("failed to deserialize MemForest")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(range: std::ops::Range<u8>) -> Vec<BitcoinNodeHash> {
+        range.map(|i| BitcoinNodeHash::new([i; 32])).collect()
+    }
+
+    #[test]
+    fn serialized_size_matches_serialize() {
+        for n in [0u8, 1, 2, 5, 8, 13] {
+            let mut forest = MemForest::<BitcoinNodeHash>::new();
+            forest.modify(&hashes(0..n), &[]).unwrap();
+            let mut buf = Vec::new();
+            forest.serialize(&mut buf).unwrap();
+            assert_eq!(
+                serialized_size(u64::from(n)),
+                buf.len() as u64,
+                "{n} leaves"
+            );
+        }
+    }
+
+    #[test]
+    fn positions_resolve_to_added_hashes() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves = hashes(0..8);
+        let positions = add_with_positions(&mut forest, &leaves).unwrap();
+        assert_eq!(positions, (0..8).collect::<Vec<u64>>());
+        for (pos, leaf) in positions.iter().zip(&leaves) {
+            assert_eq!(hash_at(&forest, *pos), Ok(*leaf));
+        }
+    }
+
+    #[test]
+    fn positions_account_for_existing_leaves() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&hashes(0..3), &[]).unwrap();
+        let leaves = hashes(3..8);
+        let positions = add_with_positions(&mut forest, &leaves).unwrap();
+        assert_eq!(positions, vec![3, 4, 5, 6, 7]);
+        for (pos, leaf) in positions.iter().zip(&leaves) {
+            assert_eq!(hash_at(&forest, *pos), Ok(*leaf));
+        }
+    }
+
+    #[test]
+    fn hash_at_valid_position() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves = hashes(0..8);
+        forest.modify(&leaves, &[]).unwrap();
+        assert_eq!(hash_at(&forest, 5), Ok(leaves[5]));
+        // The root of an 8-leaf forest sits at the last position, 14.
+        assert_eq!(hash_at(&forest, 14), Ok(forest.get_roots()[0].get_data()));
+    }
+
+    #[test]
+    fn hash_at_past_the_tree() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&hashes(0..5), &[]).unwrap();
+        // 5 leaves use 3 rows: leaf 7 and the row-1 node over leaves 4 and 5 don't exist.
+        assert_eq!(hash_at(&forest, 7), Err(GrabError::OutOfRange(7)));
+        assert_eq!(hash_at(&forest, 10), Err(GrabError::OutOfRange(10)));
+        assert_eq!(hash_at(&forest, 1_000), Err(GrabError::OutOfRange(1_000)));
+        let empty = MemForest::<BitcoinNodeHash>::new();
+        assert_eq!(hash_at(&empty, 0), Err(GrabError::OutOfRange(0)));
+    }
+
+    #[test]
+    fn hash_at_deleted_position() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves = hashes(0..4);
+        forest.modify(&leaves, &[]).unwrap();
+        forest.modify(&[], &[leaves[1]]).unwrap();
+        // Leaf 0 moves up into its parent's slot, leaving positions 0 and 1 empty.
+        assert_eq!(hash_at(&forest, 1), Err(GrabError::NotFound(1)));
+        assert_eq!(hash_at(&forest, 4), Ok(leaves[0]));
+    }
+
+    #[test]
+    fn hashes_at_matches_single_lookups() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&hashes(0..8), &[]).unwrap();
+        // leaves, interior nodes (8, 12) and the root (14), unsorted and repeated, with the
+        // sibling pairs 2-3, 8-9 and 12-13
+        let positions = [14, 3, 8, 0, 12, 3, 7, 9, 2, 13];
+        let expected = positions
+            .iter()
+            .map(|&pos| hash_at(&forest, pos).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(hashes_at(&forest, &positions), Ok(expected));
+        assert_eq!(hashes_at(&forest, &[1, 15]), Err(GrabError::OutOfRange(15)));
+
+        // deleting leaf 5 moves leaf 4 up to 10, next to its new sibling 11
+        let leaves = hashes(0..8);
+        forest.modify(&[], &[leaves[5]]).unwrap();
+        assert_eq!(hashes_at(&forest, &[4, 5]), Err(GrabError::NotFound(4)));
+        let pair = [hash_at(&forest, 10).unwrap(), hash_at(&forest, 11).unwrap()];
+        assert_eq!(pair[0], leaves[4]);
+        assert_eq!(hashes_at(&forest, &[11, 10]), Ok(vec![pair[1], pair[0]]));
+    }
+
+    #[test]
+    fn delete_by_position_matches_delete_by_hash() {
+        let leaves = hashes(0..8);
+        let mut by_hash = MemForest::<BitcoinNodeHash>::new();
+        by_hash.modify(&leaves, &[]).unwrap();
+        by_hash.modify(&[], &[leaves[2], leaves[5]]).unwrap();
+
+        let mut by_position = MemForest::<BitcoinNodeHash>::new();
+        by_position.modify(&leaves, &[]).unwrap();
+        // deleting 5 first would not move 2, and vice versa: both are resolved up front
+        delete_by_position(&mut by_position, &[5, 2]).unwrap();
+        assert_same(&by_hash, &by_position);
+
+        let err = delete_by_position(&mut by_position, &[3, 3]).unwrap_err();
+        assert!(err.to_string().contains("listed twice"), "{err}");
+        // position 2 was emptied by the deletion above
+        assert!(delete_by_position(&mut by_position, &[2]).is_err());
+    }
+
+    fn roundtrip(forest: &MemForest<BitcoinNodeHash>) -> (MemForest<BitcoinNodeHash>, Vec<u8>) {
+        let mut buf = Vec::new();
+        forest.serialize(&mut buf).unwrap();
+        let decoded = MemForest::<BitcoinNodeHash>::deserialize(buf.as_slice()).unwrap();
+        let mut again = Vec::new();
+        decoded.serialize(&mut again).unwrap();
+        assert_eq!(buf, again, "re-serialization differs");
+        (decoded, buf)
+    }
+
+    /// Roots, leaf count and every populated position must survive the round trip.
+    fn assert_same(a: &MemForest<BitcoinNodeHash>, b: &MemForest<BitcoinNodeHash>) {
+        assert_eq!(a.leaves, b.leaves);
+        let roots = |f: &MemForest<BitcoinNodeHash>| {
+            f.get_roots()
+                .iter()
+                .map(|r| r.get_data())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(roots(a), roots(b));
+        for pos in 0..(2u64 << tree_rows(a.leaves)) {
+            assert_eq!(hash_at(a, pos), hash_at(b, pos), "position {pos}");
+        }
+    }
+
+    #[test]
+    fn serialization_single_leaf() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&hashes(0..1), &[]).unwrap();
+        let (decoded, buf) = roundtrip(&forest);
+        assert_same(&forest, &decoded);
+        // header, then one leaf node: type tag 1 and a regular hash
+        assert_eq!(read_header(buf.as_slice()).unwrap(), 1);
+        assert_eq!(buf[16..24], 1u64.to_le_bytes());
+        assert_eq!(buf[24], 2);
+        assert_eq!(buf.len(), 16 + 8 + 1 + 32);
+    }
+
+    #[test]
+    fn serialization_branches() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&hashes(0..7), &[]).unwrap();
+        let (decoded, buf) = roundtrip(&forest);
+        assert_same(&forest, &decoded);
+        // the first root is a branch
+        assert_eq!(buf[16..24], 0u64.to_le_bytes());
+    }
+
+    #[test]
+    fn serialization_after_deletions() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves = hashes(0..8);
+        forest.modify(&leaves, &[]).unwrap();
+        // a leaf whose sibling moves up, and a whole subtree moving up a row
+        forest
+            .modify(&[], &[leaves[0], leaves[5], leaves[6], leaves[7]])
+            .unwrap();
+        let (decoded, _) = roundtrip(&forest);
+        assert_same(&forest, &decoded);
+    }
+
+    #[test]
+    fn serialization_fully_deleted_tree() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves = hashes(0..3);
+        forest.modify(&leaves, &[]).unwrap();
+        forest.modify(&[], &[leaves[2]]).unwrap();
+        let (decoded, buf) = roundtrip(&forest);
+        assert_same(&forest, &decoded);
+        assert_eq!(read_header(buf.as_slice()).unwrap(), 3);
+    }
+
+    #[test]
+    fn verified_deserialize_detects_tampering() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves = hashes(0..8);
+        forest.modify(&leaves, &[]).unwrap();
+        forest.modify(&[], &[leaves[3]]).unwrap();
+        let mut buf = Vec::new();
+        forest.serialize(&mut buf).unwrap();
+        let verified = deserialize_verified(&buf).unwrap();
+        assert_same(&forest, &verified);
+
+        // flip a byte of the root hash, then of the last leaf's hash
+        for offset in [16 + 8 + 1, buf.len() - 1] {
+            let mut tampered = buf.clone();
+            tampered[offset] ^= 1;
+            // the fast path accepts it...
+            assert!(MemForest::<BitcoinNodeHash>::deserialize(tampered.as_slice()).is_ok());
+            // ...the verified one doesn't
+            let err = format!("{:#}", deserialize_verified(&tampered).unwrap_err());
+            assert!(err.contains("does not match its children"), "{err}");
+        }
+    }
+
+    #[test]
+    fn verified_deserialize_rejects_placeholder() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&hashes(0..1), &[]).unwrap();
+        let mut buf = Vec::new();
+        forest.serialize(&mut buf).unwrap();
+        // retag the single leaf's hash as a placeholder, which carries no hash bytes
+        buf[16 + 8] = 1;
+        buf.truncate(16 + 8 + 1);
+        let err = format!("{:#}", deserialize_verified(&buf).unwrap_err());
+        assert!(err.contains("placeholder hash"), "{err}");
+    }
+
+    #[test]
+    fn verified_deserialize_rejects_excess_depth() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&hashes(0..2), &[]).unwrap();
+        let mut buf = Vec::new();
+        forest.serialize(&mut buf).unwrap();
+        // header, then the root branch and its two leaves, each node the same size
+        let node = (buf.len() - 16) / 3;
+        let (header, nodes) = buf.split_at(16);
+        let (root, leaves) = nodes.split_at(node);
+        // nest a copy of the whole tree where the left leaf was: one row more than 2 leaves have
+        let deeper = [header, root, nodes, &leaves[node..]].concat();
+        let err = format!("{:#}", deserialize_verified(&deeper).unwrap_err());
+        assert!(err.contains("nested deeper"), "{err}");
+    }
+
+    #[test]
+    fn verified_deserialize_accepts_forest_after_blocks() {
+        // The block fixtures under test-data hash their branches with SHA-256, not the
+        // SHA-512/256 rustreexo 0.4 uses, so they can't pass; build a forest over several
+        // blocks of additions and deletions instead
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves = hashes(0..100);
+        forest.modify(&leaves[..60], &[]).unwrap();
+        forest
+            .modify(&leaves[60..75], &[leaves[3], leaves[17], leaves[59]])
+            .unwrap();
+        forest
+            .modify(
+                &leaves[75..],
+                &[leaves[0], leaves[1], leaves[64], leaves[70]],
+            )
+            .unwrap();
+        let mut buf = Vec::new();
+        forest.serialize(&mut buf).unwrap();
+        assert_same(&forest, &deserialize_verified(&buf).unwrap());
+    }
+
+    #[test]
+    fn serialization_fixture_is_byte_identical() {
+        let fixture = include_bytes!("../../test-data/block-2txs/acc-after.txt");
+        let forest = MemForest::<BitcoinNodeHash>::deserialize(&fixture[..]).unwrap();
+        let (_, buf) = roundtrip(&forest);
+        assert_eq!(buf, fixture);
+    }
+}
@@ -1,9 +1,18 @@
 //! Common library for the accumulator service.
 pub mod api;
+pub mod block_hashes;
 pub mod builder;
+pub mod config;
+pub mod delta;
+pub mod forest;
 pub mod pollard;
+pub mod proof;
+pub mod rpc;
 pub mod script_utils;
 pub mod state_machine;
+pub mod sync_state;
+pub mod txoutset;
 pub mod updater;
+pub mod verify;
 /// Expose the primary service context.
 pub use state_machine::Context;
@@ -1,42 +1,43 @@
 //! Pollard logic stubs and helpers
-use crate::script_utils::pollard_conv::forest_to_pollard;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::BlockHash;
 use rustreexo::accumulator::mem_forest::MemForest;
-use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use rustreexo::accumulator::node_hash::{AccumulatorHash, BitcoinNodeHash};
 use rustreexo::accumulator::pollard::{Pollard, PollardAddition};
 use rustreexo::accumulator::proof::Proof;
+use rustreexo::accumulator::stump::Stump;
 use std::fs;
-use std::io::Cursor;
-
-/// Prune a MemForest snapshot into a Pollard using the provided delete list (ignored for empty deletions).
-/// Reads the serialized MemForest from `snapshot_path`, runs the forest_to_pollard conversion,
-/// and writes out `pollard.bin` in the current directory.
-pub async fn prune_forest(snapshot_path: &str, _delete_list: &str) -> Result<()> {
-    // Load the full MemForest bytes
-    let data = fs::read(snapshot_path)?;
-    // Convert to Pollard (empty deletions by default)
-    let pollard =
-        forest_to_pollard(&data, &[]).map_err(|e| anyhow!("pollard conversion failed: {}", e))?;
-    // Serialize Pollard to disk
-    let mut out = fs::File::create("pollard.bin")?;
-    pollard
-        .serialize(&mut out)
-        .map_err(|e| anyhow!("failed to serialize Pollard: {}", e))?;
-    Ok(())
-}
-/// Synchronous version of prune_forest for use in blocking contexts.
-pub fn prune_forest_sync(snapshot_path: &str, _delete_list: &str) -> Result<()> {
-    // Load the full MemForest bytes
-    let data = fs::read(snapshot_path)?;
-    // Convert to Pollard (empty deletions by default)
-    let pollard =
-        forest_to_pollard(&data, &[]).map_err(|e| anyhow!("pollard conversion failed: {}", e))?;
-    // Serialize Pollard to disk
-    let mut out = fs::File::create("pollard.bin")?;
-    pollard
-        .serialize(&mut out)
-        .map_err(|e| anyhow!("failed to serialize Pollard: {}", e))?;
-    Ok(())
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use utreexo::roots::PlaceholderRoot;
+
+// ----------------------------------------------------------------------------
+// Roots in forest order
+// ----------------------------------------------------------------------------
+//
+// A `MemForest` and a `Stump` list their roots tallest tree first, and proofs are checked
+// against roots in that order. A `Pollard` keeps a slot per row instead: `Pollard::roots`
+// returns the roots lowest row first, and `Pollard::from_roots` takes the root of row `r` from
+// index `r`, so a forest's roots passed straight to it are lost or misplaced once there are two.
+
+/// A Pollard holding only `roots`, listed tallest tree first as a `MemForest` or `Stump` lists
+/// them, over `leaves` leaves.
+pub fn from_forest_roots(roots: &[BitcoinNodeHash], leaves: u64) -> Pollard<BitcoinNodeHash> {
+    let mut by_row = vec![BitcoinNodeHash::Empty; 64];
+    let rows = (0..64).rev().filter(|row| leaves >> row & 1 == 1);
+    for (row, root) in rows.zip(roots) {
+        by_row[row] = *root;
+    }
+    Pollard::from_roots(by_row, leaves)
+}
+
+/// `pollard`'s roots tallest tree first, the order a `MemForest` or `Stump` lists them in.
+pub fn forest_roots(pollard: &Pollard<BitcoinNodeHash>) -> Vec<BitcoinNodeHash> {
+    let mut roots = pollard.roots();
+    roots.reverse();
+    roots
 }
 
 // ----------------------------------------------------------------------------
@@ -56,6 +57,28 @@ pub fn pollard_after_block(
     mem_forest_bytes: &[u8],
     deletes: &[BitcoinNodeHash],
     new_leaves: &[BitcoinNodeHash],
+) -> Result<Pollard<BitcoinNodeHash>> {
+    apply_to_pollard(mem_forest_bytes, deletes, new_leaves, true)
+}
+
+/// [`pollard_after_block`] without step 5. The forest is still needed to prove `deletes`, but
+/// the block isn't also applied to it, which roughly halves the work.
+///
+/// This trades safety for speed: a Pollard that went wrong is returned as if it were right.
+/// Keep the checked version for tests and debugging.
+pub fn pollard_after_block_unchecked(
+    mem_forest_bytes: &[u8],
+    deletes: &[BitcoinNodeHash],
+    new_leaves: &[BitcoinNodeHash],
+) -> Result<Pollard<BitcoinNodeHash>> {
+    apply_to_pollard(mem_forest_bytes, deletes, new_leaves, false)
+}
+
+fn apply_to_pollard(
+    mem_forest_bytes: &[u8],
+    deletes: &[BitcoinNodeHash],
+    new_leaves: &[BitcoinNodeHash],
+    verify: bool,
 ) -> Result<Pollard<BitcoinNodeHash>> {
     // 1) deserialize full forest
     let mut cursor = Cursor::new(mem_forest_bytes);
@@ -80,10 +103,13 @@ pub fn pollard_after_block(
             remember: false,
         })
         .collect::<Vec<_>>();
-    let mut pollard = Pollard::from_roots(roots, mem.leaves);
+    let mut pollard = from_forest_roots(&roots, mem.leaves);
     pollard
         .modify(&adds, deletes, proof)
         .map_err(|e| anyhow!("pollard.modify failed: {e}"))?;
+    if !verify {
+        return Ok(pollard);
+    }
 
     // 4) sanity check: mirror on MemForest and compare roots
     mem.modify(new_leaves, deletes)
@@ -93,9 +119,608 @@ pub fn pollard_after_block(
         .iter()
         .map(|r| r.get_data())
         .collect::<Vec<_>>();
-    if pollard.roots() != expected {
+    if forest_roots(&pollard) != expected {
         return Err(anyhow!("root mismatch: Pollard vs MemForest after block"));
     }
 
     Ok(pollard)
 }
+
+// Serialized Pollard layout (rustreexo 0.4):
+//
+//   leaves: u64 BE | for each of the 64 rows, lowest first: a root marker byte, then the
+//                    row's tree if the marker is 1
+//
+// Marker `row` is 1 when the accumulator has a tree of 2^row leaves, i.e. when bit `row` of
+// `leaves` is set, and 0 otherwise. Each tree is written pre-order: a one-byte leaf flag (1 for
+// a node with nothing below it, 0 otherwise), then the node's hash as in a MemForest (a
+// one-byte variant tag, then the 32 hash bytes for a regular hash). A node that isn't a leaf is
+// followed by its two subtrees. An empty Pollard is 72 bytes: the leaf count and 64 zeros.
+
+/// Number of root markers in a serialized Pollard, one per possible tree row.
+const ROOT_MARKERS: usize = 64;
+
+/// Walk a serialized Pollard without building any nodes and check its structure: a root marker
+/// set for every set bit of the leaf count and for nothing else, every marked tree complete,
+/// and nothing after the last one. Returns the declared number of leaves.
+pub fn check_layout(bytes: &[u8]) -> io::Result<u64> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut reader = Cursor::new(bytes);
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    let leaves = u64::from_be_bytes(buf);
+    for row in 0..ROOT_MARKERS {
+        let mut marker = [0u8];
+        reader.read_exact(&mut marker)?;
+        match (marker[0], leaves >> row & 1) {
+            (0, 0) => continue,
+            (1, 1) => {}
+            (0, _) => {
+                return Err(invalid(format!(
+                    "{leaves} leaves have a tree on row {row}, but no root is marked there"
+                )))
+            }
+            (1, _) => {
+                return Err(invalid(format!(
+                    "{leaves} leaves have no tree on row {row}, but a root is marked there"
+                )))
+            }
+            (m, _) => return Err(invalid(format!("invalid root marker {m} for row {row}"))),
+        }
+        // Rows of the nodes still to be read in this tree, walked without recursion. A node
+        // that isn't a leaf adds its two subtrees a row further down, so no tree can nest
+        // deeper than its root's row. `Pollard::deserialize` recurses once per level, and
+        // would overflow the stack on a deep enough chain of nodes
+        let mut pending = vec![row];
+        while let Some(node_row) = pending.pop() {
+            let mut is_leaf = [0u8];
+            reader.read_exact(&mut is_leaf)?;
+            match is_leaf[0] {
+                1 => {}
+                0 if node_row > 0 => pending.extend([node_row - 1; 2]),
+                0 => {
+                    return Err(invalid(format!(
+                        "the tree on row {row} nests deeper than its {row} rows"
+                    )))
+                }
+                f => {
+                    return Err(invalid(format!(
+                        "invalid leaf flag {f} in the tree on row {row}"
+                    )))
+                }
+            }
+            BitcoinNodeHash::read(&mut reader)?;
+        }
+    }
+    let trailing = bytes.len() as u64 - reader.position();
+    if trailing > 0 {
+        return Err(invalid(format!(
+            "{trailing} trailing bytes after the Pollard's roots"
+        )));
+    }
+    Ok(leaves)
+}
+
+/// Deserialize a `pollard.bin`, rejecting input that only looks like a Pollard.
+///
+/// `Pollard::deserialize` reads the root markers without checking them against the leaf count
+/// and stops once it has read the marked roots, so a file cut down to a zeroed start, or one
+/// with garbage appended, could pass for a valid (possibly empty) Pollard. It also recurses once
+/// per level of a tree, so an untrusted file could nest nodes deep enough to overflow the stack.
+/// Here the layout, tree depth included, is checked first (see [`check_layout`]).
+pub fn deserialize_strict(bytes: &[u8]) -> Result<Pollard<BitcoinNodeHash>> {
+    check_layout(bytes).context("truncated or corrupt Pollard")?;
+    Pollard::deserialize(&mut Cursor::new(bytes)).context("truncated or corrupt Pollard")
+}
+
+/// Tag at the start of a `pollard.bin` with a [`PollardMeta`] envelope. Read as the leaf count
+/// of a bare Pollard it would be over 10^18 leaves, so the two formats can't be confused.
+pub const ENVELOPE_MAGIC: [u8; 8] = *b"UXPOLLD2";
+
+/// Tag of the first envelope, which had no flags byte. Still read, without a leaf policy.
+pub const ENVELOPE_MAGIC_V1: [u8; 8] = *b"UXPOLLD1";
+
+/// Envelope size in bytes: magic, height, block hash, leaf count and flags.
+const ENVELOPE_LEN: usize = 8 + 8 + 32 + 8 + 1;
+
+/// Bit of the envelope's flags byte set when OP_RETURN outputs are leaves.
+const FLAG_KEEP_OP_RETURN: u8 = 1;
+
+/// The block a `pollard.bin` is the accumulator state after, stored in front of the Pollard as
+///
+/// ```text
+/// ENVELOPE_MAGIC | block_height (u64 LE) | block_hash (32, zeros if unknown) | leaf_count (u64 LE) | flags (u8)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollardMeta {
+    pub block_height: u64,
+    /// `None` when the hash wasn't known, e.g. an update run without Bitcoin Core RPC
+    pub block_hash: Option<BlockHash>,
+    pub leaf_count: u64,
+    /// Whether OP_RETURN outputs are leaves (see [`SyncState::keep_op_return`]); `None` for
+    /// an envelope written before the policy was recorded.
+    ///
+    /// [`SyncState::keep_op_return`]: crate::sync_state::SyncState::keep_op_return
+    pub keep_op_return: Option<bool>,
+}
+
+impl PollardMeta {
+    /// Fail unless the Pollard is the state after block `height`.
+    pub fn expect_height(&self, height: u64) -> Result<()> {
+        ensure!(
+            self.block_height == height,
+            "Pollard is the state at height {}, not {height}",
+            self.block_height
+        );
+        Ok(())
+    }
+}
+
+/// Serialize `pollard` behind a [`PollardMeta`] envelope for the block at `block_height`,
+/// recording whether OP_RETURN outputs are leaves.
+pub fn encode_with_meta(
+    pollard: &Pollard<BitcoinNodeHash>,
+    block_height: u64,
+    block_hash: Option<BlockHash>,
+    keep_op_return: bool,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(ENVELOPE_LEN);
+    buf.extend_from_slice(&ENVELOPE_MAGIC);
+    buf.extend_from_slice(&block_height.to_le_bytes());
+    buf.extend_from_slice(
+        block_hash
+            .unwrap_or_else(BlockHash::all_zeros)
+            .as_byte_array(),
+    );
+    buf.extend_from_slice(&pollard.leaves().to_le_bytes());
+    buf.push(if keep_op_return {
+        FLAG_KEEP_OP_RETURN
+    } else {
+        0
+    });
+    pollard
+        .serialize(&mut buf)
+        .context("failed to serialize Pollard")?;
+    Ok(buf)
+}
+
+/// Read a `pollard.bin` with or without a [`PollardMeta`] envelope; files written before the
+/// envelope existed are bare Pollards and come back without metadata. Either way the Pollard
+/// is read with [`deserialize_strict`], and an envelope's leaf count must match it.
+pub fn decode(bytes: &[u8]) -> Result<(Pollard<BitcoinNodeHash>, Option<PollardMeta>)> {
+    // the first envelope lacks the flags byte
+    let (rest, header_len) = if let Some(rest) = bytes.strip_prefix(&ENVELOPE_MAGIC[..]) {
+        (rest, ENVELOPE_LEN - ENVELOPE_MAGIC.len())
+    } else if let Some(rest) = bytes.strip_prefix(&ENVELOPE_MAGIC_V1[..]) {
+        (rest, ENVELOPE_LEN - ENVELOPE_MAGIC.len() - 1)
+    } else {
+        return Ok((deserialize_strict(bytes)?, None));
+    };
+    ensure!(rest.len() >= header_len, "truncated pollard.bin envelope");
+    let (header, rest) = rest.split_at(header_len);
+    let block_height = u64::from_le_bytes(header[..8].try_into().expect("8 bytes"));
+    let block_hash = BlockHash::from_slice(&header[8..40]).expect("32 bytes");
+    let leaf_count = u64::from_le_bytes(header[40..48].try_into().expect("8 bytes"));
+    let keep_op_return = match header.get(48) {
+        Some(&flags) => {
+            ensure!(
+                flags & !FLAG_KEEP_OP_RETURN == 0,
+                "unknown pollard.bin envelope flags {flags:#04x}"
+            );
+            Some(flags & FLAG_KEEP_OP_RETURN != 0)
+        }
+        None => None,
+    };
+    let pollard = deserialize_strict(rest)?;
+    ensure!(
+        pollard.leaves() == leaf_count,
+        "envelope records {leaf_count} leaves, but the Pollard has {}",
+        pollard.leaves()
+    );
+    let meta = PollardMeta {
+        block_height,
+        block_hash: (block_hash != BlockHash::all_zeros()).then_some(block_hash),
+        leaf_count,
+        keep_op_return,
+    };
+    Ok((pollard, Some(meta)))
+}
+
+/// Replace `pollard.bin` in the data directory `dir` with `bytes`, as encoded by
+/// [`encode_with_meta`]. The bytes go to a temporary file that is renamed into place, so a
+/// reader never sees half a file.
+pub fn save(dir: &Path, bytes: &[u8]) -> Result<()> {
+    let path = dir.join("pollard.bin");
+    let tmp = dir.join("pollard.bin.tmp");
+    fs::write(&tmp, bytes).with_context(|| format!("failed to write {tmp:?}"))?;
+    fs::rename(&tmp, &path).with_context(|| format!("failed to replace {path:?}"))
+}
+
+/// The Pollard's roots as committed to outside the accumulator: an empty root, left after all
+/// of its tree's leaves were deleted, is all zeros, and a placeholder is an error (see
+/// [`utreexo::roots`]).
+pub fn roots_bytes(pollard: &Pollard<BitcoinNodeHash>) -> Result<Vec<[u8; 32]>, PlaceholderRoot> {
+    utreexo::roots::roots_bytes(&forest_roots(pollard))
+}
+
+/// [`roots_bytes`] as lowercase hex, the form the API reports roots in.
+pub fn roots_hex(pollard: &Pollard<BitcoinNodeHash>) -> Result<Vec<String>, PlaceholderRoot> {
+    Ok(roots_bytes(pollard)?
+        .iter()
+        .map(|root| root.to_lower_hex_string())
+        .collect())
+}
+
+/// Roots [`abbreviate_roots`] keeps at each end of a long list by default.
+pub const ROOTS_SHOWN: usize = 2;
+
+/// `roots` for a log line: all of them if there are at most `2 * shown`, otherwise the first and
+/// last `shown` with the number left out in between. A mainnet accumulator has 30 or more roots,
+/// which is too many to print every time.
+pub fn abbreviate_roots(roots: &[String], shown: usize) -> String {
+    if roots.len() <= 2 * shown {
+        return format!("[{}]", roots.join(", "));
+    }
+    let mut parts = roots[..shown].to_vec();
+    parts.push(format!("… {} more …", roots.len() - 2 * shown));
+    parts.extend_from_slice(&roots[roots.len() - shown..]);
+    format!("[{}]", parts.join(", "))
+}
+
+/// One-line description of `pollard` for logs, e.g.
+/// `"1099511627775 leaves, 40 roots, root0=01010101…01010101"`: the first root is cut to its first and
+/// last 4 bytes, the rest are left out.
+pub fn summary(pollard: &Pollard<BitcoinNodeHash>) -> String {
+    let roots = forest_roots(pollard);
+    let mut line = format!("{} leaves, {} roots", pollard.leaves(), roots.len());
+    if let Some(root) = roots.first() {
+        match utreexo::roots::root_bytes(root) {
+            Some(bytes) => line.push_str(&format!(
+                ", root0={}…{}",
+                bytes[..4].to_lower_hex_string(),
+                bytes[28..].to_lower_hex_string()
+            )),
+            None => line.push_str(", root0=placeholder"),
+        }
+    }
+    line
+}
+
+/// Combine two accumulators built over consecutive runs of leaves, e.g. shards of a dump built
+/// in parallel, into the accumulator holding all of them.
+///
+/// `second`'s leaves must come *after* `first`'s, and `first` must end on a boundary of
+/// `second`'s largest tree: its leaf count has to be a multiple of that tree's size, so every
+/// tree of `second` sits exactly where it would have if its leaves were added to `first` one
+/// by one. The result is then the same as that append. An empty root, whose leaves were all
+/// deleted, gives way to the tree it is merged with.
+///
+/// Only roots are combined, so the result remembers no leaves.
+pub fn merge(
+    first: &Pollard<BitcoinNodeHash>,
+    second: &Pollard<BitcoinNodeHash>,
+) -> Result<Pollard<BitcoinNodeHash>> {
+    let mut leaves = first.leaves();
+    let mut roots = forest_roots(first);
+    // `second`'s roots run from its largest tree to its smallest
+    let heights = (0..64u8).rev().filter(|h| second.leaves() >> h & 1 == 1);
+    for (h, root) in heights.zip(forest_roots(second)) {
+        ensure!(
+            leaves.is_multiple_of(1u64 << h),
+            "cannot append a tree of {} leaves after {} leaves",
+            1u64 << h,
+            leaves
+        );
+        let mut node = root;
+        let mut row = h;
+        while leaves >> row & 1 == 1 {
+            let left = roots
+                .pop()
+                .context("fewer roots than the leaf count implies")?;
+            node = match (left, node) {
+                (BitcoinNodeHash::Empty, right) => right,
+                (left, BitcoinNodeHash::Empty) => left,
+                (left, right) => AccumulatorHash::parent_hash(&left, &right),
+            };
+            row += 1;
+        }
+        roots.push(node);
+        leaves += 1u64 << h;
+    }
+    Ok(from_forest_roots(&roots, leaves))
+}
+
+/// Roots `pollard` would have after adding `adds` and deleting `dels`, without modifying it.
+///
+/// Only the roots and leaf count are copied, into a `Stump`, which is all the mutation needs
+/// besides a proof for `dels`; those must be leaves the Pollard remembers.
+pub fn preview_modify(
+    pollard: &Pollard<BitcoinNodeHash>,
+    adds: &[BitcoinNodeHash],
+    dels: &[BitcoinNodeHash],
+) -> Result<Vec<BitcoinNodeHash>> {
+    let proof = pollard
+        .batch_proof(dels)
+        .map_err(|e| anyhow!("failed to prove deletions: {e}"))?;
+    let stump = Stump {
+        leaves: pollard.leaves(),
+        roots: forest_roots(pollard),
+    };
+    let (stump, _) = stump
+        .modify(adds, dels, &proof)
+        .map_err(|e| anyhow!("failed to apply block to Stump: {e:?}"))?;
+    Ok(stump.roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script_utils::pollard_conv::prune_to;
+
+    fn serialized(pollard: &Pollard<BitcoinNodeHash>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        pollard.serialize(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn roots_hex_renders_empty_root_as_zeros() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves: Vec<_> = (1..=3u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        forest.modify(&leaves, &[]).unwrap();
+        // the third leaf is a tree on its own, which is emptied by deleting it
+        forest.modify(&[], &leaves[2..]).unwrap();
+        let pollard = prune_to(&forest, &[]).unwrap();
+        assert_eq!(
+            roots_hex(&pollard).unwrap(),
+            vec![
+                "3d69cccb1da0a704005bad797bf7e9f7d1df28d04741df3ca8a7c18a80bf71b3".to_string(),
+                "00".repeat(32),
+            ]
+        );
+        assert_eq!(roots_bytes(&pollard).unwrap()[1], [0; 32]);
+    }
+
+    #[test]
+    fn unchecked_pollard_after_block_matches_checked() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        let leaves: Vec<_> = (0..12u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        forest.modify(&leaves, &[]).unwrap();
+        let mut bytes = Vec::new();
+        forest.serialize(&mut bytes).unwrap();
+
+        let deletes = [leaves[1], leaves[6], leaves[11]];
+        let adds: Vec<_> = (20..25u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let checked = pollard_after_block(&bytes, &deletes, &adds).unwrap();
+        let unchecked = pollard_after_block_unchecked(&bytes, &deletes, &adds).unwrap();
+        assert_eq!(unchecked.roots(), checked.roots());
+        assert_eq!(unchecked.leaves(), checked.leaves());
+    }
+
+    #[test]
+    fn summary_is_one_short_line_for_a_large_accumulator() {
+        // one root per set bit: 40 trees
+        let leaves = (1u64 << 40) - 1;
+        let roots: Vec<_> = (1..=40u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let pollard = from_forest_roots(&roots, leaves);
+        let line = summary(&pollard);
+        assert_eq!(
+            line,
+            "1099511627775 leaves, 40 roots, root0=01010101…01010101"
+        );
+        assert_eq!(summary(&pollard), line);
+        assert_eq!(summary(&Pollard::new()), "0 leaves, 0 roots");
+    }
+
+    #[test]
+    fn long_root_lists_are_abbreviated() {
+        let roots: Vec<_> = (0..7).map(|i| format!("r{i}")).collect();
+        assert_eq!(
+            abbreviate_roots(&roots, ROOTS_SHOWN),
+            "[r0, r1, … 3 more …, r5, r6]"
+        );
+        assert_eq!(
+            abbreviate_roots(&roots[..4], ROOTS_SHOWN),
+            "[r0, r1, r2, r3]"
+        );
+        assert_eq!(abbreviate_roots(&roots, 0), "[… 7 more …]");
+    }
+
+    #[test]
+    fn strict_deserialize_accepts_empty_pollard() {
+        let buf = serialized(&Pollard::new());
+        assert_eq!(buf, [0u8; 8 + ROOT_MARKERS]);
+        assert_eq!(check_layout(&buf).unwrap(), 0);
+        assert_eq!(deserialize_strict(&buf).unwrap().leaves(), 0);
+    }
+
+    #[test]
+    fn strict_deserialize_accepts_serialized_pollards() {
+        for n in [1u8, 2, 3, 7, 8, 13] {
+            let leaves: Vec<_> = (0..n).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+            let mut forest = MemForest::<BitcoinNodeHash>::new();
+            forest.modify(&leaves, &[]).unwrap();
+            if n > 2 {
+                forest.modify(&[], &leaves[1..2]).unwrap();
+            }
+            // roots only, and every remaining leaf remembered
+            for keep in [&[][..], &leaves[leaves.len().min(2)..]] {
+                let pollard = prune_to(&forest, keep).unwrap();
+                let buf = serialized(&pollard);
+                assert_eq!(check_layout(&buf).unwrap(), u64::from(n));
+                let decoded = deserialize_strict(&buf).unwrap();
+                assert_eq!(decoded.leaves(), u64::from(n));
+                assert_eq!(decoded.roots(), pollard.roots());
+            }
+        }
+    }
+
+    #[test]
+    fn strict_deserialize_rejects_missing_root() {
+        // one leaf, its root marked, but the root itself is cut off
+        let buf = serialized(&pollard_of(0..1));
+        assert!(deserialize_strict(&buf[..8 + ROOT_MARKERS]).is_err());
+        // an empty Pollard with a root marked anyway
+        let mut buf = serialized(&Pollard::new());
+        buf[8] = 1;
+        assert!(deserialize_strict(&buf).is_err());
+    }
+
+    #[test]
+    fn strict_deserialize_checks_roots_against_leaves() {
+        let pollard = pollard_of(0..8);
+        assert_eq!(pollard.roots().len(), 1);
+        let mut buf = serialized(&pollard);
+        assert_eq!(deserialize_strict(&buf).unwrap().leaves(), 8);
+
+        // 8 leaves form a single tree on row 3; a root marked on row 0 is corrupt
+        buf[8] = 1;
+        let err = format!("{:#}", deserialize_strict(&buf).unwrap_err());
+        assert!(
+            err.contains("8 leaves have no tree on row 0, but a root is marked there"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn strict_deserialize_rejects_deep_nesting() {
+        // one leaf, a tree on row 0, whose root claims 7000 levels of branches below it
+        let mut buf = 1u64.to_be_bytes().to_vec();
+        buf.push(1);
+        for _ in 0..7000 {
+            buf.extend([0, 0]);
+        }
+        buf.resize(buf.len() + ROOT_MARKERS - 1, 0);
+        let err = format!("{:#}", deserialize_strict(&buf).unwrap_err());
+        assert!(err.contains("the tree on row 0 nests deeper"), "{err}");
+
+        // a four-leaf tree with every leaf remembered nests exactly as deep as it may
+        let leaves: Vec<_> = (0..4u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&leaves, &[]).unwrap();
+        let pollard = prune_to(&forest, &leaves).unwrap();
+        let buf = serialized(&pollard);
+        assert_eq!(deserialize_strict(&buf).unwrap().roots(), pollard.roots());
+    }
+
+    #[test]
+    fn strict_deserialize_rejects_trailing_bytes() {
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest
+            .modify(&[BitcoinNodeHash::new([1; 32])], &[])
+            .unwrap();
+        let pollard = prune_to(&forest, &[]).unwrap();
+        let mut buf = serialized(&pollard);
+        assert_eq!(deserialize_strict(&buf).unwrap().roots(), pollard.roots());
+
+        buf.push(0xde);
+        let err = format!("{:#}", deserialize_strict(&buf).unwrap_err());
+        assert!(err.contains("1 trailing bytes"), "{err}");
+        // a zeroed 32-byte file is an empty Pollard followed by garbage
+        assert!(deserialize_strict(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn envelope_roundtrip_and_raw_fallback() {
+        let pollard = pollard_of(0..5);
+        let hash = BlockHash::from_byte_array([7; 32]);
+        let bytes = encode_with_meta(&pollard, 100, Some(hash), true).unwrap();
+        let (decoded, meta) = decode(&bytes).unwrap();
+        assert_eq!(decoded.roots(), pollard.roots());
+        let meta = meta.unwrap();
+        assert_eq!(
+            meta,
+            PollardMeta {
+                block_height: 100,
+                block_hash: Some(hash),
+                leaf_count: 5,
+                keep_op_return: Some(true),
+            }
+        );
+        meta.expect_height(100).unwrap();
+        let err = meta.expect_height(101).unwrap_err();
+        assert!(err.to_string().contains("height 100, not 101"), "{err}");
+
+        let (_, meta) = decode(&encode_with_meta(&pollard, 3, None, false).unwrap()).unwrap();
+        let meta = meta.unwrap();
+        assert_eq!(meta.block_hash, None);
+        assert_eq!(meta.keep_op_return, Some(false));
+
+        // the first envelope, without the flags byte, has no leaf policy
+        let mut v1 = bytes.clone();
+        v1[..8].copy_from_slice(&ENVELOPE_MAGIC_V1);
+        v1.remove(ENVELOPE_LEN - 1);
+        let (decoded, meta) = decode(&v1).unwrap();
+        assert_eq!(decoded.roots(), pollard.roots());
+        assert_eq!(meta.unwrap().keep_op_return, None);
+        // nor can a flag no version defines be ignored
+        let mut unknown = bytes;
+        unknown[ENVELOPE_LEN - 1] |= 0x80;
+        assert!(decode(&unknown).is_err());
+        // a bare Pollard still loads, without metadata
+        let (decoded, meta) = decode(&serialized(&pollard)).unwrap();
+        assert_eq!(decoded.leaves(), 5);
+        assert!(meta.is_none());
+    }
+
+    #[test]
+    fn envelope_leaf_count_must_match() {
+        let pollard = pollard_of(0..5);
+        let mut bytes = encode_with_meta(&pollard, 100, None, false).unwrap();
+        bytes[48..56].copy_from_slice(&6u64.to_le_bytes());
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains("records 6 leaves"), "{err}");
+        assert!(decode(&bytes[..20]).is_err());
+    }
+
+    fn pollard_of(range: std::ops::Range<u8>) -> Pollard<BitcoinNodeHash> {
+        let leaves: Vec<_> = range.map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&leaves, &[]).unwrap();
+        prune_to(&forest, &[]).unwrap()
+    }
+
+    #[test]
+    fn merged_halves_match_one_shot_build() {
+        let whole = pollard_of(0..8);
+        let merged = merge(&pollard_of(0..4), &pollard_of(4..8)).unwrap();
+        assert_eq!(merged.leaves(), 8);
+        assert_eq!(merged.roots(), whole.roots());
+
+        // a smaller second half leaves several roots
+        let merged = merge(&pollard_of(0..4), &pollard_of(4..7)).unwrap();
+        assert_eq!(merged.roots(), pollard_of(0..7).roots());
+    }
+
+    #[test]
+    fn misaligned_merge_is_rejected() {
+        // the 4-leaf tree can't start after 3 leaves
+        let err = merge(&pollard_of(0..3), &pollard_of(3..7)).unwrap_err();
+        assert!(err.to_string().contains("after 3 leaves"), "{err}");
+    }
+
+    #[test]
+    fn preview_matches_modify_and_leaves_pollard_unchanged() {
+        let leaves: Vec<BitcoinNodeHash> =
+            (0..8u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&leaves, &[]).unwrap();
+        let dels = [leaves[2], leaves[5]];
+        let pollard = prune_to(&forest, &dels).unwrap();
+        let before = pollard.roots().to_vec();
+
+        let adds: Vec<BitcoinNodeHash> = (8..11u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let preview = preview_modify(&pollard, &adds, &dels).unwrap();
+
+        assert_eq!(pollard.roots(), before);
+        assert_eq!(pollard.leaves(), 8);
+
+        forest.modify(&adds, &dels).unwrap();
+        let expected = forest
+            .get_roots()
+            .iter()
+            .map(|r| r.get_data())
+            .collect::<Vec<_>>();
+        assert_eq!(preview, expected);
+    }
+}
@@ -0,0 +1,275 @@
+//! Batch proofs assembled from other proofs, and proofs stamped with the accumulator state
+//! they were made against.
+use crate::forest::{row_and_index, row_offset, tree_rows};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use rustreexo::accumulator::proof::Proof;
+use rustreexo::accumulator::stump::Stump;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Positions whose hashes a proof for `targets` carries in a forest with `leaves` leaves, in
+/// ascending order, which is the order of `Proof::hashes`.
+///
+/// Walking up from the targets one row at a time, a node needs its sibling's hash unless the
+/// sibling is itself a target or can be computed from one; roots need nothing.
+pub fn proof_positions(targets: &[u64], leaves: u64) -> Result<Vec<u64>> {
+    let rows = tree_rows(leaves);
+    let mut computed = targets.to_vec();
+    computed.sort_unstable();
+    computed.dedup();
+    for &target in &computed {
+        ensure!(
+            target < leaves,
+            "target {target} is not a leaf of a forest with {leaves} leaves"
+        );
+    }
+
+    let mut positions = Vec::new();
+    for row in 0..=rows {
+        let mut nodes = computed
+            .iter()
+            .filter_map(|&pos| row_and_index(pos, rows).filter(|(r, _)| *r == row))
+            .map(|(_, index)| index)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        let row_len = leaves >> row;
+        while let Some(index) = nodes.next() {
+            if row_len & 1 == 1 && index == row_len - 1 {
+                // the root of this row's tree
+                continue;
+            }
+            if nodes.peek() == Some(&(index ^ 1)) {
+                nodes.next();
+            } else {
+                positions.push(row_offset(row, rows) + (index ^ 1));
+            }
+            computed.push(row_offset(row + 1, rows) + index / 2);
+        }
+    }
+    positions.sort_unstable();
+    Ok(positions)
+}
+
+/// Combine proofs made against the same accumulator, with `leaves` leaves, into one batch
+/// proof for all of their targets.
+///
+/// The targets keep their order of first appearance, duplicates dropped, so the hashes of the
+/// deleted leaves line up as they would for `MemForest::prove` on the same leaves. Interior
+/// hashes that several proofs carry are included once, and those that the combined targets
+/// make computable are left out. Two proofs that disagree on the hash at a position can't have
+/// been made against the same accumulator and are rejected; proofs that share no position
+/// can't be told apart that way, so verify the result against the roots.
+pub fn merge(proofs: &[Proof<BitcoinNodeHash>], leaves: u64) -> Result<Proof<BitcoinNodeHash>> {
+    let mut known = BTreeMap::new();
+    let mut targets = Vec::new();
+    for (i, proof) in proofs.iter().enumerate() {
+        let positions = proof_positions(&proof.targets, leaves)
+            .with_context(|| format!("proof {i} is not for a forest with {leaves} leaves"))?;
+        ensure!(
+            positions.len() == proof.hashes.len(),
+            "proof {i} has {} hashes, but its targets need {}",
+            proof.hashes.len(),
+            positions.len()
+        );
+        for (pos, hash) in positions.into_iter().zip(&proof.hashes) {
+            if let Some(other) = known.insert(pos, *hash) {
+                if other != *hash {
+                    bail!(
+                        "proof {i} disagrees with an earlier proof on position {pos}; they \
+                         were made against different accumulators"
+                    );
+                }
+            }
+        }
+        for &target in &proof.targets {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+
+    // every position the combined proof needs is needed by one of the proofs it came from
+    let hashes = proof_positions(&targets, leaves)?
+        .into_iter()
+        .map(|pos| known[&pos])
+        .collect();
+    Ok(Proof::new(targets, hashes))
+}
+
+/// Commitment to an accumulator state: SHA-256 over the leaf count (u64 LE) and the roots.
+/// Deleting leaves keeps the count but changes the roots, so both are covered.
+pub fn fingerprint(leaves: u64, roots: &[BitcoinNodeHash]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&leaves.to_le_bytes());
+    for root in roots {
+        engine.input(&**root);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+/// A proof along with the [`fingerprint`] of the accumulator it was made against.
+///
+/// Positions shift as the accumulator changes, so a proof is only good for the exact state it
+/// was made for. Checking the stamp first turns a proof that was valid once into a
+/// [`ProofError::Stale`] instead of a bare verification failure.
+#[derive(Debug, Clone)]
+pub struct StampedProof {
+    pub proof: Proof<BitcoinNodeHash>,
+    /// Leaf count of the accumulator when the proof was made
+    pub leaves: u64,
+    pub fingerprint: sha256::Hash,
+}
+
+/// Why a [`StampedProof`] was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofError {
+    /// The proof was made against another accumulator state, e.g. before leaves were added.
+    Stale { proof_leaves: u64, leaves: u64 },
+    /// The proof is for this state but doesn't prove the given leaves.
+    Invalid(String),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::Stale {
+                proof_leaves,
+                leaves,
+            } => write!(
+                f,
+                "stale proof: made against {proof_leaves} leaves, accumulator now has {leaves} \
+                 or different roots"
+            ),
+            ProofError::Invalid(msg) => write!(f, "invalid proof: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+/// Prove `hashes` in `forest` and stamp the proof with the forest's current state.
+pub fn prove_stamped(
+    forest: &MemForest<BitcoinNodeHash>,
+    hashes: &[BitcoinNodeHash],
+) -> Result<StampedProof> {
+    let proof = forest.prove(hashes).map_err(|e| anyhow!("prove: {e:?}"))?;
+    let roots: Vec<_> = forest.get_roots().iter().map(|r| r.get_data()).collect();
+    Ok(StampedProof {
+        proof,
+        leaves: forest.leaves,
+        fingerprint: fingerprint(forest.leaves, &roots),
+    })
+}
+
+/// Verify that `proof` proves `hashes` in `stump`, rejecting it as stale first if it was made
+/// against a different state.
+pub fn verify_stamped(
+    stump: &Stump,
+    proof: &StampedProof,
+    hashes: &[BitcoinNodeHash],
+) -> Result<(), ProofError> {
+    if proof.fingerprint != fingerprint(stump.leaves, &stump.roots) {
+        return Err(ProofError::Stale {
+            proof_leaves: proof.leaves,
+            leaves: stump.leaves,
+        });
+    }
+    match stump.verify(&proof.proof, hashes) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ProofError::Invalid("roots do not match".into())),
+        Err(e) => Err(ProofError::Invalid(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forest(leaves: &[BitcoinNodeHash]) -> MemForest<BitcoinNodeHash> {
+        let mut forest = MemForest::new();
+        forest.modify(leaves, &[]).unwrap();
+        forest
+    }
+
+    fn hashes(range: std::ops::Range<u8>) -> Vec<BitcoinNodeHash> {
+        range.map(|i| BitcoinNodeHash::new([i; 32])).collect()
+    }
+
+    #[test]
+    fn positions_match_memforest_proofs() {
+        let leaves = hashes(0..11);
+        let forest = forest(&leaves);
+        for targets in [vec![0], vec![0, 1], vec![3, 9], vec![10], vec![2, 5, 6, 7]] {
+            let del: Vec<_> = targets.iter().map(|&t| leaves[t]).collect();
+            let proof = forest.prove(&del).unwrap();
+            let positions = proof_positions(&proof.targets, 11).unwrap();
+            assert_eq!(positions.len(), proof.hashes.len(), "{targets:?}");
+        }
+    }
+
+    #[test]
+    fn single_leaf_proofs_merge_into_batch_proof() {
+        let leaves = hashes(0..8);
+        let forest = forest(&leaves);
+        let single: Vec<_> = [0, 1, 6]
+            .iter()
+            .map(|&i| forest.prove(&[leaves[i]]).unwrap())
+            .collect();
+        let merged = merge(&single, 8).unwrap();
+
+        let del = [leaves[0], leaves[1], leaves[6]];
+        let batch = forest.prove(&del).unwrap();
+        assert_eq!(merged.targets, batch.targets);
+        assert_eq!(merged.hashes, batch.hashes);
+
+        assert!(stump(&forest).verify(&merged, &del).unwrap());
+    }
+
+    fn stump(forest: &MemForest<BitcoinNodeHash>) -> Stump {
+        Stump {
+            leaves: forest.leaves,
+            roots: forest.get_roots().iter().map(|r| r.get_data()).collect(),
+        }
+    }
+
+    #[test]
+    fn proof_is_stale_after_an_addition() {
+        let leaves = hashes(0..5);
+        let mut forest = forest(&leaves);
+        let proof = prove_stamped(&forest, &leaves[1..2]).unwrap();
+        assert_eq!(
+            verify_stamped(&stump(&forest), &proof, &leaves[1..2]),
+            Ok(())
+        );
+        // the wrong leaf for a current proof is invalid, not stale
+        assert!(matches!(
+            verify_stamped(&stump(&forest), &proof, &leaves[2..3]),
+            Err(ProofError::Invalid(_))
+        ));
+
+        forest.modify(&hashes(5..6), &[]).unwrap();
+        assert_eq!(
+            verify_stamped(&stump(&forest), &proof, &leaves[1..2]),
+            Err(ProofError::Stale {
+                proof_leaves: 5,
+                leaves: 6
+            })
+        );
+    }
+
+    #[test]
+    fn proofs_from_different_accumulators_are_rejected() {
+        let leaves = hashes(0..8);
+        let mut changed = leaves.clone();
+        changed[5] = BitcoinNodeHash::new([0xff; 32]);
+        // both proofs carry the hash of leaves 4..8, which differs between the two
+        let a = forest(&leaves).prove(&[leaves[0]]).unwrap();
+        let b = forest(&changed).prove(&[changed[2]]).unwrap();
+        let err = merge(&[a, b], 8).unwrap_err();
+        assert!(err.to_string().contains("different accumulators"), "{err}");
+    }
+}
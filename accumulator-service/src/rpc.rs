@@ -0,0 +1,129 @@
+//! Bitcoin Core RPC adapter shared by the updater and the standalone binaries.
+use crate::script_utils::btc_rpc::{BitcoinRpc, TxOutInfo};
+use anyhow::{anyhow, Context, Result};
+use bitcoin::{Block, BlockHash, OutPoint};
+use bitcoincore_rpc::json::GetRawTransactionResultVout;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+
+/// [`BitcoinRpc`] implementation backed by a `bitcoincore_rpc::Client`.
+pub struct CoreRpcClient(pub Client);
+
+impl CoreRpcClient {
+    /// Connect to `url`, authenticating with Bitcoin Core's cookie file.
+    pub fn new(url: &str, cookie_file: &str) -> Result<Self> {
+        let client = Client::new(url, Auth::CookieFile(cookie_file.into()))
+            .context("failed to connect to Bitcoin RPC")?;
+        Ok(Self(client))
+    }
+
+    /// Connect using `BITCOIN_CORE_RPC_URL` and `BITCOIN_CORE_COOKIE_FILE`.
+    pub fn from_env() -> Result<Self> {
+        let url = std::env::var("BITCOIN_CORE_RPC_URL").context("missing BITCOIN_CORE_RPC_URL")?;
+        let cookie = std::env::var("BITCOIN_CORE_COOKIE_FILE")
+            .context("missing BITCOIN_CORE_COOKIE_FILE")?;
+        Self::new(&url, &cookie)
+    }
+}
+
+/// Find output `prevout.vout` among a transaction's outputs, as returned by
+/// `getrawtransaction`, and return its value in sats and its raw script.
+pub fn find_vout(
+    vout: Vec<GetRawTransactionResultVout>,
+    prevout: &OutPoint,
+) -> Result<(u64, Vec<u8>)> {
+    let out = vout
+        .into_iter()
+        .find(|v| v.n == prevout.vout)
+        .ok_or_else(|| {
+            anyhow!(
+                "vout {} not found in transaction {}",
+                prevout.vout,
+                prevout.txid
+            )
+        })?;
+    // bitcoincore_rpc already decodes the `hex` field into raw script bytes
+    Ok((out.value.to_sat(), out.script_pub_key.hex))
+}
+
+impl BitcoinRpc for CoreRpcClient {
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash> {
+        Ok(self.0.get_block_hash(height)?)
+    }
+    fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+        Ok(self.0.get_block(hash)?)
+    }
+    fn get_txout(&self, prevout: &OutPoint) -> Result<TxOutInfo> {
+        let info = self.0.get_raw_transaction_info(&prevout.txid, None)?;
+        let block_hash = info
+            .blockhash
+            .ok_or_else(|| anyhow!("transaction {} is not in a block yet", prevout.txid))?;
+        let is_coinbase = info.is_coinbase();
+        let (value, script) = find_vout(info.vout, prevout)?;
+        Ok(TxOutInfo {
+            value,
+            script,
+            block_hash,
+            is_coinbase,
+        })
+    }
+    fn get_block_height(&self, hash: &BlockHash) -> Result<u32> {
+        let height = self.0.get_block_header_info(hash)?.height;
+        u32::try_from(height).map_err(|_| anyhow!("block {hash} has height {height}, over u32"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+
+    /// `vout` array of a `getrawtransaction ... true` response.
+    const VOUT: &str = r#"[
+        {
+            "value": 0.00010000,
+            "n": 0,
+            "scriptPubKey": {
+                "asm": "0 abababababababababababababababababababab",
+                "hex": "0014abababababababababababababababababababab",
+                "type": "witness_v0_keyhash"
+            }
+        },
+        {
+            "value": 1.5,
+            "n": 1,
+            "scriptPubKey": {
+                "asm": "OP_TRUE",
+                "hex": "51",
+                "type": "nonstandard"
+            }
+        }
+    ]"#;
+
+    fn outpoint(vout: u32) -> OutPoint {
+        OutPoint {
+            txid: Txid::from_byte_array([7; 32]),
+            vout,
+        }
+    }
+
+    #[test]
+    fn find_vout_returns_value_and_raw_script() {
+        let vout: Vec<GetRawTransactionResultVout> = serde_json::from_str(VOUT).unwrap();
+        let (value, script) = find_vout(vout.clone(), &outpoint(1)).unwrap();
+        assert_eq!(value, 150_000_000);
+        assert_eq!(script, vec![0x51]);
+
+        let (value, script) = find_vout(vout, &outpoint(0)).unwrap();
+        assert_eq!(value, 10_000);
+        assert_eq!(script.len(), 22);
+        assert_eq!(script[..2], [0x00, 0x14]);
+    }
+
+    #[test]
+    fn find_vout_reports_missing_output() {
+        let vout: Vec<GetRawTransactionResultVout> = serde_json::from_str(VOUT).unwrap();
+        let err = find_vout(vout, &outpoint(2)).unwrap_err().to_string();
+        assert!(err.contains("vout 2 not found"), "{err}");
+    }
+}
@@ -2,7 +2,7 @@
 //! `script/` crate.  Keeping them here allows us to drop the path
 //! dependency and ship a single crate.
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
 // -------------------------------------------------------------------
 // Parquet → leaf-hash extraction (was script/src/lib.rs)
@@ -10,51 +10,394 @@ use anyhow::{Context, Result};
 
 pub mod parquet {
     use super::*;
+    use crate::block_hashes::BlockHashes;
+    use crate::txoutset;
+    use anyhow::bail;
     use bitcoin::hashes::{sha256d::Hash as Sha256dHash, Hash};
-    use bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf, TxOut};
-    use duckdb::Connection;
+    use bitcoin::hex::FromHex;
+    use bitcoin::{Amount, BlockHash, OutPoint, Script, ScriptBuf, TxOut};
+    use duckdb::types::{Type, ValueRef};
+    use duckdb::{Connection, Row};
     use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+    use std::fs::File;
+    use std::io::{BufReader, Read};
     use std::path::Path;
-    use utreexo::LeafData;
+    use tokio_util::sync::CancellationToken;
+    use utreexo::{header_code, is_excluded, LeafData};
+
+    /// UTXO dump formats the builder recognises.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum DumpFormat {
+        /// Parquet, as produced by `utxo-to-parquet`.
+        Parquet,
+        /// CSV with a header and the Parquet column names; `script` is hex.
+        Csv,
+        /// Bitcoin Core's raw `dumptxoutset` output.
+        CoreSnapshot,
+    }
+
+    /// Detect a dump's format from its magic bytes, falling back to the file extension.
+    pub fn detect_format(path: &Path) -> Result<DumpFormat> {
+        let mut magic = Vec::with_capacity(5);
+        File::open(path)
+            .with_context(|| format!("failed to open {path:?}"))?
+            .take(5)
+            .read_to_end(&mut magic)?;
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        Ok(match (&magic[..], extension.as_deref()) {
+            ([b'P', b'A', b'R', b'1', ..], _) => DumpFormat::Parquet,
+            // Core >= 28 starts snapshots with "utxo\xff"; older ones have no magic
+            ([b'u', b't', b'x', b'o', 0xff], _) | (_, Some("dat")) => DumpFormat::CoreSnapshot,
+            (_, Some("csv")) => DumpFormat::Csv,
+            _ => bail!(
+                "unrecognized UTXO dump format for {path:?} \
+                 (expected Parquet, CSV or a Core snapshot)"
+            ),
+        })
+    }
+
+    /// DuckDB table expression reading the dump at `path` through `conn`, whatever its format.
+    /// A Core snapshot has no DuckDB reader, so its coins are first loaded into a table of
+    /// `conn`.
+    fn source(conn: &Connection, path: &Path) -> Result<String> {
+        let path_str = path.to_str().context("invalid UTF-8 in dump path")?;
+        match detect_format(path)? {
+            DumpFormat::Parquet => Ok(sql_string(path_str)),
+            DumpFormat::Csv => Ok(format!(
+                "read_csv_auto({}, header = true, \
+                 types = {{'txid': 'VARCHAR', 'script': 'VARCHAR'}})",
+                sql_string(path_str)
+            )),
+            DumpFormat::CoreSnapshot => {
+                load_core_snapshot(conn, path)?;
+                Ok(CORE_SNAPSHOT_TABLE.into())
+            }
+        }
+    }
+
+    /// `s` as an SQL string literal.
+    fn sql_string(s: &str) -> String {
+        format!("'{}'", s.replace('\'', "''"))
+    }
+
+    /// Table [`source`] loads a Core snapshot into, with the Parquet columns.
+    const CORE_SNAPSHOT_TABLE: &str = "core_snapshot";
+
+    fn load_core_snapshot(conn: &Connection, path: &Path) -> Result<()> {
+        let file = File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+        let dump = txoutset::Dump::new(BufReader::new(file))
+            .with_context(|| format!("failed to read the snapshot header of {path:?}"))?;
+        conn.execute_batch(&format!(
+            "CREATE TABLE {CORE_SNAPSHOT_TABLE} (txid VARCHAR, amount UBIGINT, vout UINTEGER, \
+             height UINTEGER, script BLOB, coinbase BOOLEAN)"
+        ))?;
+        let mut appender = conn.appender(CORE_SNAPSHOT_TABLE)?;
+        for coin in dump {
+            let coin = coin.with_context(|| format!("bad coin in {path:?}"))?;
+            appender.append_row(duckdb::params![
+                coin.outpoint.txid.to_string(),
+                coin.txout.value.to_sat(),
+                coin.outpoint.vout,
+                coin.height,
+                coin.txout.script_pubkey.as_bytes(),
+                coin.coinbase,
+            ])?;
+        }
+        appender.flush();
+        Ok(())
+    }
+
+    /// Read a `script` column, stored as a BLOB in Parquet and as hex text in CSV.
+    fn script_column(r: &Row, idx: usize) -> duckdb::Result<Vec<u8>> {
+        match r.get_ref(idx)? {
+            ValueRef::Blob(bytes) => Ok(bytes.to_vec()),
+            ValueRef::Text(text) => std::str::from_utf8(text)
+                .ok()
+                .and_then(|hex| Vec::from_hex(hex).ok())
+                .ok_or_else(|| {
+                    duckdb::Error::FromSqlConversionFailure(
+                        idx,
+                        Type::Text,
+                        anyhow!("script is not valid hex").into(),
+                    )
+                }),
+            other => Err(duckdb::Error::InvalidColumnType(
+                idx,
+                "script".into(),
+                other.data_type(),
+            )),
+        }
+    }
+
+    /// Leaf hash for one `txid, amount, vout, height, script` row, or `None`
+    /// if its script is left out of the accumulator (see [`is_excluded`]).
+    /// `scratch` is reused across rows to encode the utxo without allocating
+    /// per leaf.
+    fn leaf_from_row(
+        r: &Row,
+        block_hashes: Option<&BlockHashes>,
+        keep_op_return: bool,
+        scratch: &mut Vec<u8>,
+    ) -> duckdb::Result<Option<BitcoinNodeHash>> {
+        let txid_hex: String = r.get(0)?;
+        let sats: u64 = r.get(1)?;
+        let vout: u32 = r.get(2)?;
+        let height: u64 = r.get(3)?;
+        let script_bytes = script_column(r, 4)?;
+        if is_excluded(Script::from_bytes(&script_bytes), keep_op_return) {
+            return Ok(None);
+        }
+
+        let block_hash = match block_hashes {
+            Some(lookup) => lookup.get(height).ok_or_else(|| {
+                duckdb::Error::FromSqlConversionFailure(
+                    3,
+                    Type::BigInt,
+                    anyhow!("no block hash known for height {height}").into(),
+                )
+            })?,
+            None => BlockHash::from_raw_hash(Sha256dHash::all_zeros()),
+        };
+        let txid = txid_hex.parse().map_err(|e| {
+            duckdb::Error::FromSqlConversionFailure(
+                0,
+                duckdb::types::Type::Text,
+                anyhow!("invalid txid {txid_hex:?} (vout {vout}): {e}").into(),
+            )
+        })?;
+        let prevout = OutPoint { txid, vout };
+        let header_code = u32::try_from(height)
+            .ok()
+            .and_then(|height| header_code(height, false))
+            .ok_or_else(|| {
+                duckdb::Error::FromSqlConversionFailure(
+                    3,
+                    Type::BigInt,
+                    anyhow!("height {height} is too large for a header code").into(),
+                )
+            })?;
+        let utxo = TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: ScriptBuf::from_bytes(script_bytes),
+        };
+        let leaf = LeafData {
+            block_hash,
+            prevout,
+            header_code,
+            utxo,
+        };
+        Ok(Some(leaf.get_leaf_hashes_into(scratch)))
+    }
 
     /// Extract all leaf hashes from every *non-coinbase* UTXO row in a
-    /// Parquet export created by Bitcoin Core’s `dumptxoutset`.  This
-    /// matches the behaviour of the original script.
-    pub fn get_all_leaf_hashes<P: AsRef<Path>>(parquet: P) -> Result<Vec<BitcoinNodeHash>> {
+    /// dump of Bitcoin Core’s UTXO set, in any format [`detect_format`]
+    /// accepts.  This matches the behaviour of the original script.  Rows
+    /// with an unspendable script are skipped, like everywhere else leaves
+    /// are built.
+    ///
+    /// Leaves commit to the hash of the block that created each UTXO, which
+    /// dumps don't record, so it is looked up by height in `block_hashes`.
+    /// Without a lookup an all-zero placeholder is used instead; such leaves
+    /// only agree with each other, not with the ones `process_block` builds.
+    pub fn get_all_leaf_hashes<P: AsRef<Path>>(
+        parquet: P,
+        block_hashes: Option<&BlockHashes>,
+    ) -> Result<Vec<BitcoinNodeHash>> {
+        let mut leaves = Vec::new();
+        for_each_leaf_batch(parquet, block_hashes, usize::MAX, |batch| {
+            leaves.extend_from_slice(batch);
+            Ok(())
+        })?;
+        Ok(leaves)
+    }
+
+    /// Like [`get_all_leaf_hashes`], but hands the leaf hashes to `f` in
+    /// batches of at most `batch_size`, in dump order, so the whole set never
+    /// has to be held in memory at once.
+    pub fn for_each_leaf_batch<P, F>(
+        parquet: P,
+        block_hashes: Option<&BlockHashes>,
+        batch_size: usize,
+        mut f: F,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&[BitcoinNodeHash]) -> Result<()>,
+    {
+        anyhow::ensure!(batch_size > 0, "batch size must be greater than zero");
         let parquet = parquet.as_ref();
         let conn = Connection::open_in_memory().context("open in-mem DuckDB")?;
         let path_str = parquet.to_str().context("invalid UTF-8 in Parquet path")?;
         let sql = format!(
-            "SELECT txid, amount, vout, height, script FROM '{path_str}' WHERE coinbase = FALSE",
+            "SELECT txid, amount, vout, height, script FROM {} WHERE coinbase = FALSE",
+            source(&conn, parquet)?,
         );
         let mut stmt = conn.prepare(&sql).context("prepare DuckDB query")?;
-        let mut leaves = Vec::new();
+        let mut batch = Vec::new();
+        let mut scratch = Vec::new();
+        for row in stmt.query_map([], |r| leaf_from_row(r, block_hashes, false, &mut scratch))? {
+            if let Some(leaf) = row.with_context(|| format!("bad UTXO row in {path_str}"))? {
+                batch.push(leaf);
+                if batch.len() == batch_size {
+                    f(&batch)?;
+                    batch.clear();
+                }
+            }
+        }
+        if !batch.is_empty() {
+            f(&batch)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`for_each_leaf_batch`], but hands `f` the leaves of `chunk_rows`
+    /// dump rows at a time and checks `cancel` before each chunk, so a long
+    /// extraction can be stopped part way. The dump is read by one query, in
+    /// dump order. Returns an error once `cancel` has fired. With
+    /// `keep_op_return`, OP_RETURN rows become leaves too.
+    pub fn for_each_leaf_batch_cancellable<P, F>(
+        parquet: P,
+        block_hashes: Option<&BlockHashes>,
+        keep_op_return: bool,
+        chunk_rows: usize,
+        cancel: &CancellationToken,
+        mut f: F,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&[BitcoinNodeHash]) -> Result<()>,
+    {
+        anyhow::ensure!(chunk_rows > 0, "chunk size must be greater than zero");
+        let parquet = parquet.as_ref();
+        let conn = Connection::open_in_memory().context("open in-mem DuckDB")?;
+        let path_str = parquet.to_str().context("invalid UTF-8 in Parquet path")?;
+        let sql = format!(
+            "SELECT txid, amount, vout, height, script FROM {} WHERE coinbase = FALSE",
+            source(&conn, parquet)?,
+        );
+        let mut stmt = conn.prepare(&sql).context("prepare DuckDB query")?;
+        let mut scratch = Vec::new();
+        let rows = stmt.query_map([], |r| {
+            leaf_from_row(r, block_hashes, keep_op_return, &mut scratch)
+        })?;
+        let mut rows_in_chunk = 0;
+        let mut batch = Vec::new();
+        for row in rows {
+            if rows_in_chunk == 0 && cancel.is_cancelled() {
+                bail!("leaf extraction from {path_str} cancelled");
+            }
+            if let Some(leaf) = row.with_context(|| format!("bad UTXO row in {path_str}"))? {
+                batch.push(leaf);
+            }
+            rows_in_chunk += 1;
+            if rows_in_chunk == chunk_rows {
+                if !batch.is_empty() {
+                    f(&batch)?;
+                    batch.clear();
+                }
+                rows_in_chunk = 0;
+            }
+        }
+        if !batch.is_empty() {
+            f(&batch)?;
+        }
+        Ok(())
+    }
+
+    /// Number of non-coinbase rows in a UTXO dump, without extracting any
+    /// leaves.  Rows with an unspendable script are counted too, so this is
+    /// an upper bound on the leaves [`get_all_leaf_hashes`] returns.
+    pub fn count_rows<P: AsRef<Path>>(parquet: P) -> Result<u64> {
+        let parquet = parquet.as_ref();
+        let conn = Connection::open_in_memory().context("open in-mem DuckDB")?;
+        let rows = conn
+            .query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM {} WHERE coinbase = FALSE",
+                    source(&conn, parquet)?
+                ),
+                [],
+                |r| r.get(0),
+            )
+            .with_context(|| format!("failed to count rows in {parquet:?}"))?;
+        Ok(rows)
+    }
+
+    /// Creation heights of the UTXOs whose leaves [`get_all_leaf_hashes`]
+    /// builds, each once and in ascending order: the only heights a block
+    /// hash lookup for this dump needs.
+    pub fn distinct_heights<P: AsRef<Path>>(parquet: P) -> Result<Vec<u64>> {
+        let parquet = parquet.as_ref();
+        let conn = Connection::open_in_memory().context("open in-mem DuckDB")?;
+        let sql = format!(
+            "SELECT DISTINCT height FROM {} WHERE coinbase = FALSE ORDER BY height",
+            source(&conn, parquet)?,
+        );
+        let mut stmt = conn.prepare(&sql).context("prepare DuckDB query")?;
+        let heights = stmt
+            .query_map([], |r| r.get::<_, u64>(0))?
+            .collect::<duckdb::Result<Vec<_>>>()
+            .with_context(|| format!("failed to read heights from {parquet:?}"))?;
+        Ok(heights)
+    }
+
+    /// Highest creation height of any UTXO in the export, i.e. the height the dump was taken
+    /// at (its coinbase outputs are always unspent). `None` for an empty export.
+    pub fn max_height<P: AsRef<Path>>(parquet: P) -> Result<Option<u64>> {
+        let parquet = parquet.as_ref();
+        let conn = Connection::open_in_memory().context("open in-mem DuckDB")?;
+        let height = conn
+            .query_row(
+                &format!("SELECT max(height) FROM {}", source(&conn, parquet)?),
+                [],
+                |r| r.get(0),
+            )
+            .context("query max height")?;
+        Ok(height)
+    }
+
+    /// A UTXO as recorded in the dump.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct UtxoRow {
+        pub prevout: OutPoint,
+        pub amount: u64,
+        pub script: Vec<u8>,
+    }
+
+    /// Read up to `count` randomly sampled non-coinbase rows from a UTXO dump.
+    pub fn sample_rows<P: AsRef<Path>>(parquet: P, count: usize) -> Result<Vec<UtxoRow>> {
+        let parquet = parquet.as_ref();
+        let conn = Connection::open_in_memory().context("open in-mem DuckDB")?;
+        let path_str = parquet.to_str().context("invalid UTF-8 in Parquet path")?;
+        let sql = format!(
+            "SELECT txid, vout, amount, script FROM {} WHERE coinbase = FALSE \
+             USING SAMPLE {count} ROWS",
+            source(&conn, parquet)?,
+        );
+        let mut stmt = conn.prepare(&sql).context("prepare DuckDB query")?;
+        let mut rows = Vec::new();
         for row in stmt.query_map([], |r| {
-            let txid_hex: String = r.get(0)?;
-            let sats: u64 = r.get(1)?;
-            let vout: u32 = r.get(2)?;
-            let height: u64 = r.get(3)?;
-            let script_bytes: Vec<u8> = r.get(4)?;
-
-            let block_hash = BlockHash::from_raw_hash(Sha256dHash::all_zeros());
-            let txid = txid_hex.parse().unwrap();
-            let prevout = OutPoint { txid, vout };
-            let header_code = (height as u32) << 1;
-            let utxo = TxOut {
-                value: Amount::from_sat(sats),
-                script_pubkey: ScriptBuf::from_bytes(script_bytes),
-            };
-            let leaf = LeafData {
-                block_hash,
-                prevout,
-                header_code,
-                utxo,
-            };
-            Ok(leaf.get_leaf_hashes())
+            Ok((
+                r.get::<_, String>(0)?,
+                r.get::<_, u32>(1)?,
+                r.get::<_, u64>(2)?,
+                script_column(r, 3)?,
+            ))
         })? {
-            leaves.push(row?);
+            let (txid_hex, vout, amount, script) = row?;
+            let txid = txid_hex
+                .parse()
+                .with_context(|| format!("invalid txid {txid_hex:?} in {path_str}"))?;
+            rows.push(UtxoRow {
+                prevout: OutPoint { txid, vout },
+                amount,
+                script,
+            });
         }
-        Ok(leaves)
+        Ok(rows)
     }
 }
 
@@ -64,49 +407,248 @@ pub mod parquet {
 
 pub mod btc_rpc {
     use super::*;
+    use anyhow::bail;
     use bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf, TxOut};
     use rustreexo::accumulator::node_hash::BitcoinNodeHash;
-    use utreexo::LeafData;
+    use std::collections::{HashMap, HashSet};
+    use tokio_util::sync::CancellationToken;
+    use utreexo::{header_code, is_excluded, LeafData};
+
+    /// An output as the node returns it: its amount and script, and where it
+    /// was created, which its leaf commits to as well.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct TxOutInfo {
+        pub value: u64,
+        pub script: Vec<u8>,
+        /// Block the creating transaction was mined in.
+        pub block_hash: BlockHash,
+        /// Whether the creating transaction is a coinbase.
+        pub is_coinbase: bool,
+    }
 
     pub trait BitcoinRpc {
         fn get_block_hash(&self, height: u64) -> Result<BlockHash>;
         fn get_block(&self, hash: &BlockHash) -> Result<bitcoin::Block>;
-        fn get_txout(&self, prevout: &OutPoint) -> Result<(u64, Vec<u8>)>;
+        fn get_txout(&self, prevout: &OutPoint) -> Result<TxOutInfo>;
         fn get_block_height(&self, hash: &BlockHash) -> Result<u32>;
     }
 
-    /// Fetch all non-coinbase inputs of a block as leaf hashes.
+    /// Fetch all non-coinbase inputs of a block as leaf hashes.  Prevouts with
+    /// an unspendable script were never added as leaves, so they are skipped.
     pub fn get_block_leaf_hashes<R: BitcoinRpc>(
         rpc: &R,
         height: u64,
     ) -> Result<Vec<BitcoinNodeHash>> {
+        get_block_leaf_hashes_cancellable(rpc, height, &CancellationToken::new())
+    }
+
+    /// Like [`get_block_leaf_hashes`], but checks `cancel` before fetching
+    /// each transaction's prevouts and bails once it is cancelled.
+    pub fn get_block_leaf_hashes_cancellable<R: BitcoinRpc>(
+        rpc: &R,
+        height: u64,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<BitcoinNodeHash>> {
+        get_block_leaf_hashes_with(rpc, height, MissingPrevout::Fail, false, cancel)
+            .map(|leaves| leaves.hashes)
+    }
+
+    /// What to do when a prevout can't be fetched, e.g. from a pruned node
+    /// that no longer has the transaction.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum MissingPrevout {
+        /// Fail the whole block.
+        #[default]
+        Fail,
+        /// Log it, leave its leaf out and carry on.
+        Skip,
+        /// Ask again up to this many times before failing the block.
+        Retry(u32),
+    }
+
+    /// Parses `fail`, `skip` or `retry:N`.
+    impl std::str::FromStr for MissingPrevout {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> Result<Self> {
+            match s {
+                "fail" => Ok(MissingPrevout::Fail),
+                "skip" => Ok(MissingPrevout::Skip),
+                _ => match s.strip_prefix("retry:").map(str::parse) {
+                    Some(Ok(n)) => Ok(MissingPrevout::Retry(n)),
+                    _ => bail!(
+                        "unknown missing prevout policy {s:?}, expected fail, skip or retry:N"
+                    ),
+                },
+            }
+        }
+    }
+
+    /// Leaf hashes of a block's inputs, plus the prevouts that couldn't be
+    /// fetched under [`MissingPrevout::Skip`].
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct BlockLeafHashes {
+        pub hashes: Vec<BitcoinNodeHash>,
+        pub unresolved: Vec<OutPoint>,
+    }
+
+    fn get_txout_with<R: BitcoinRpc>(
+        rpc: &R,
+        prevout: &OutPoint,
+        policy: MissingPrevout,
+    ) -> Result<Option<TxOutInfo>> {
+        let retries = match policy {
+            MissingPrevout::Retry(n) => n,
+            _ => 0,
+        };
+        let mut attempt = 0;
+        loop {
+            match rpc.get_txout(prevout) {
+                Ok(txout) => return Ok(Some(txout)),
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    log::debug!("fetching prevout {prevout} failed ({e:#}), retry {attempt}");
+                }
+                Err(e) if policy == MissingPrevout::Skip => {
+                    log::warn!("skipping prevout {prevout}: {e:#}");
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.context(format!("failed to fetch prevout {prevout}"))),
+            }
+        }
+    }
+
+    /// Like [`get_block_leaf_hashes_cancellable`], with `policy` deciding
+    /// what happens to a prevout the node can't return. With
+    /// `keep_op_return`, spent OP_RETURN prevouts are leaves too (see
+    /// [`is_excluded`]).
+    pub fn get_block_leaf_hashes_with<R: BitcoinRpc>(
+        rpc: &R,
+        height: u64,
+        policy: MissingPrevout,
+        keep_op_return: bool,
+        cancel: &CancellationToken,
+    ) -> Result<BlockLeafHashes> {
         let block_hash = rpc.get_block_hash(height)?;
         let block = rpc.get_block(&block_hash)?;
-        let hdr_height = rpc.get_block_height(&block_hash)?;
+        block_input_leaves(rpc, &block, policy, keep_op_return, cancel)
+    }
 
-        let mut hashes = Vec::new();
+    /// Leaf hashes of the outputs `block`'s inputs spend, fetched from `rpc`
+    /// as in [`get_block_leaf_hashes_with`]. A leaf commits to the block and
+    /// height its output was created at and to whether a coinbase created
+    /// it, so all three come from the creating transaction, not `block`.
+    pub fn block_input_leaves<R: BitcoinRpc>(
+        rpc: &R,
+        block: &bitcoin::Block,
+        policy: MissingPrevout,
+        keep_op_return: bool,
+        cancel: &CancellationToken,
+    ) -> Result<BlockLeafHashes> {
+        // Inputs mostly spend outputs of a few recent blocks, so look each
+        // creating block's height up once
+        let mut heights: HashMap<BlockHash, u32> = HashMap::new();
+        let mut leaves = BlockLeafHashes::default();
         for tx in block.txdata.iter() {
             if tx.is_coinbase() {
                 continue;
             }
+            if cancel.is_cancelled() {
+                bail!("leaf fetch for block {} cancelled", block.block_hash());
+            }
             for txin in &tx.input {
                 let prev = &txin.previous_output;
-                let (value, script_bytes) = rpc.get_txout(prev)?;
-                let utxo = TxOut {
-                    value: Amount::from_sat(value),
-                    script_pubkey: ScriptBuf::from_bytes(script_bytes),
+                let Some(txout) = get_txout_with(rpc, prev, policy)? else {
+                    leaves.unresolved.push(*prev);
+                    continue;
+                };
+                let script_pubkey = ScriptBuf::from_bytes(txout.script);
+                if is_excluded(&script_pubkey, keep_op_return) {
+                    continue;
+                }
+                let height = match heights.get(&txout.block_hash) {
+                    Some(height) => *height,
+                    None => {
+                        let height = rpc.get_block_height(&txout.block_hash)?;
+                        heights.insert(txout.block_hash, height);
+                        height
+                    }
                 };
-                let header_code = hdr_height << 1;
+                let header_code = header_code(height, txout.is_coinbase).ok_or_else(|| {
+                    anyhow!("height {height} of prevout {prev} is too large for a header code")
+                })?;
                 let leaf = LeafData {
-                    block_hash,
+                    block_hash: txout.block_hash,
                     prevout: *prev,
                     header_code,
-                    utxo,
+                    utxo: TxOut {
+                        value: Amount::from_sat(txout.value),
+                        script_pubkey,
+                    },
+                };
+                leaves.hashes.push(leaf.get_leaf_hashes());
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// Leaf hashes of every output of `block`, mined at `height`, that is
+    /// not excluded under `keep_op_return`, in block order. These are the
+    /// leaves `process_block` adds, except that outputs spent within the
+    /// same block are not left out (see [`block_changes`]).
+    pub fn block_output_leaves(
+        block: &bitcoin::Block,
+        height: u32,
+        keep_op_return: bool,
+    ) -> Result<Vec<BitcoinNodeHash>> {
+        let block_hash = block.block_hash();
+        let mut leaves = Vec::new();
+        for tx in &block.txdata {
+            let txid = tx.compute_txid();
+            let header_code = header_code(height, tx.is_coinbase())
+                .ok_or_else(|| anyhow!("height {height} is too large for a header code"))?;
+            for (vout, out) in tx.output.iter().enumerate() {
+                if is_excluded(&out.script_pubkey, keep_op_return) {
+                    continue;
+                }
+                let leaf = LeafData {
+                    block_hash,
+                    prevout: OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    header_code,
+                    utxo: out.clone(),
                 };
-                hashes.push(leaf.get_leaf_hashes());
+                leaves.push(leaf.get_leaf_hashes());
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// The leaves applying `block`, mined at `height`, adds and deletes,
+    /// given the leaves `spent` by its inputs (see [`block_input_leaves`]).
+    /// Like `process_block`, an output spent within the block is neither
+    /// added nor deleted.
+    pub fn block_changes(
+        block: &bitcoin::Block,
+        height: u32,
+        spent: Vec<BitcoinNodeHash>,
+        keep_op_return: bool,
+    ) -> Result<(Vec<BitcoinNodeHash>, Vec<BitcoinNodeHash>)> {
+        let mut adds = block_output_leaves(block, height, keep_op_return)?;
+        let created: HashSet<_> = adds.iter().copied().collect();
+        let mut spent_here = HashSet::new();
+        let mut deletes = Vec::new();
+        for leaf in spent {
+            if created.contains(&leaf) {
+                spent_here.insert(leaf);
+            } else {
+                deletes.push(leaf);
             }
         }
-        Ok(hashes)
+        adds.retain(|leaf| !spent_here.contains(leaf));
+        Ok((adds, deletes))
     }
 }
 
@@ -116,11 +658,17 @@ pub mod btc_rpc {
 
 pub mod pollard_conv {
     use super::*;
+    use crate::pollard::from_forest_roots;
     use rustreexo::accumulator::mem_forest::MemForest;
     use rustreexo::accumulator::node_hash::BitcoinNodeHash;
     use rustreexo::accumulator::pollard::Pollard;
     use std::io::Cursor;
 
+    /// Deserialize a MemForest and prune it with [`prune_to`], remembering
+    /// `deletes`.  With no `deletes` the result is a roots-only Pollard: valid,
+    /// with the forest's roots and leaf count, able to take additions and
+    /// proof-carrying deletions, but it remembers no leaf and so can't prove
+    /// any.
     pub fn forest_to_pollard(
         bytes: &[u8],
         deletes: &[BitcoinNodeHash],
@@ -128,25 +676,70 @@ pub mod pollard_conv {
         let mut cursor = Cursor::new(bytes);
         let mem = MemForest::<BitcoinNodeHash>::deserialize(&mut cursor)
             .context("deserialize MemForest")?;
-        let proof = mem
-            .prove(deletes)
-            .map_err(|e| anyhow::anyhow!("prove: {e:?}"))?;
-        let remember = proof.targets.clone();
+        prune_to(&mem, deletes)
+    }
+
+    /// Build a Pollard holding only the branches needed to prove `keep`: the
+    /// roots plus the batch proof for those leaves, with nothing else from the
+    /// forest.
+    ///
+    /// An empty `keep` skips the proof entirely and yields just the roots.
+    pub fn prune_to(
+        mem: &MemForest<BitcoinNodeHash>,
+        keep: &[BitcoinNodeHash],
+    ) -> Result<Pollard<BitcoinNodeHash>> {
         let roots = mem
             .get_roots()
             .iter()
             .map(|r| r.get_data())
             .collect::<Vec<_>>();
-        let mut pollard = Pollard::from_roots(roots, mem.leaves);
-        pollard
-            .ingest_proof(proof, deletes, &remember)
-            .map_err(|e| anyhow::anyhow!("ingest: {e:?}"))?;
+        let mut pollard = from_forest_roots(&roots, mem.leaves);
+        if !keep.is_empty() {
+            let proof = mem
+                .prove(keep)
+                .map_err(|e| anyhow::anyhow!("prove: {e:?}"))?;
+            let remember = proof.targets.clone();
+            pollard
+                .ingest_proof(proof, keep, &remember)
+                .map_err(|e| anyhow::anyhow!("ingest: {e:?}"))?;
+        }
+        Ok(pollard)
+    }
+
+    /// Like [`forest_to_pollard`], but proves and ingests `deletes` in chunks of
+    /// `chunk_size` so only one chunk's batch proof is held in memory at a time.
+    /// The resulting Pollard has the same roots as the single-shot conversion.
+    pub fn forest_to_pollard_chunked(
+        bytes: &[u8],
+        deletes: &[BitcoinNodeHash],
+        chunk_size: usize,
+    ) -> Result<Pollard<BitcoinNodeHash>> {
+        anyhow::ensure!(chunk_size > 0, "chunk size must be greater than zero");
+        let mut cursor = Cursor::new(bytes);
+        let mem = MemForest::<BitcoinNodeHash>::deserialize(&mut cursor)
+            .context("deserialize MemForest")?;
+        let roots = mem
+            .get_roots()
+            .iter()
+            .map(|r| r.get_data())
+            .collect::<Vec<_>>();
+        let mut pollard = from_forest_roots(&roots, mem.leaves);
+        for chunk in deletes.chunks(chunk_size) {
+            let proof = mem
+                .prove(chunk)
+                .map_err(|e| anyhow::anyhow!("prove: {e:?}"))?;
+            let remember = proof.targets.clone();
+            pollard
+                .ingest_proof(proof, chunk, &remember)
+                .map_err(|e| anyhow::anyhow!("ingest: {e:?}"))?;
+        }
         Ok(pollard)
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
+        use crate::pollard::forest_roots;
         use rustreexo::accumulator::mem_forest::MemForest;
         use rustreexo::accumulator::node_hash::BitcoinNodeHash;
 
@@ -173,9 +766,76 @@ pub mod pollard_conv {
             let pollard = forest_to_pollard(&buf, &deletes).expect("should succeed");
             // Pollard roots match forest roots
             let orig_roots: Vec<_> = forest.get_roots().iter().map(|r| r.get_data()).collect();
-            let new_roots = pollard.roots();
+            let new_roots = forest_roots(&pollard);
             assert_eq!(orig_roots, new_roots);
         }
+
+        #[test]
+        fn empty_deletes_give_roots_only_pollard() {
+            let leaves: Vec<BitcoinNodeHash> = (0..5)
+                .map(|i| BitcoinNodeHash::new([i as u8; 32]))
+                .collect();
+            let mut forest = MemForest::<BitcoinNodeHash>::new();
+            forest.modify(&leaves, &[]).unwrap();
+            let mut buf = Vec::new();
+            forest.serialize(&mut buf).unwrap();
+
+            let pollard = forest_to_pollard(&buf, &[]).expect("empty deletes are fine");
+            let roots: Vec<_> = forest.get_roots().iter().map(|r| r.get_data()).collect();
+            assert_eq!(forest_roots(&pollard), roots);
+            assert_eq!(pollard.leaves(), 5);
+            // nothing is remembered, so proving any leaf is an error rather than a panic
+            for leaf in &leaves {
+                assert!(pollard.batch_proof(&[*leaf]).is_err());
+            }
+        }
+
+        #[test]
+        fn chunked_matches_single_shot() {
+            let leaves: Vec<BitcoinNodeHash> = (0..100)
+                .map(|i| BitcoinNodeHash::new([i as u8; 32]))
+                .collect();
+            let mut forest = MemForest::<BitcoinNodeHash>::new();
+            forest.modify(&leaves, &[]).unwrap();
+            let mut buf = Vec::new();
+            forest.serialize(&mut buf).unwrap();
+            // Every third leaf, so chunks don't line up with subtree boundaries
+            let deletes: Vec<_> = leaves.iter().step_by(3).copied().collect();
+            let single = forest_to_pollard(&buf, &deletes).unwrap();
+            for chunk_size in [1, 7, deletes.len()] {
+                let chunked = forest_to_pollard_chunked(&buf, &deletes, chunk_size).unwrap();
+                assert_eq!(single.roots(), chunked.roots());
+            }
+            assert!(forest_to_pollard_chunked(&buf, &deletes, 0).is_err());
+        }
+
+        #[test]
+        fn prune_to_keeps_only_requested_leaves() {
+            let leaves: Vec<BitcoinNodeHash> = (0..16)
+                .map(|i| BitcoinNodeHash::new([i as u8; 32]))
+                .collect();
+            let mut forest = MemForest::<BitcoinNodeHash>::new();
+            forest.modify(&leaves, &[]).unwrap();
+
+            let serialized_len = |pollard: &Pollard<BitcoinNodeHash>| {
+                let mut buf = Vec::new();
+                pollard.serialize(&mut buf).unwrap();
+                buf.len()
+            };
+            let full_size = serialized_len(&prune_to(&forest, &leaves).unwrap());
+            let keep = [leaves[3], leaves[12]];
+            let pruned = prune_to(&forest, &keep).unwrap();
+            let pruned_size = serialized_len(&pruned);
+            assert!(pruned_size < full_size, "{pruned_size} >= {full_size}");
+
+            // the kept leaves can still be proven against the unchanged roots
+            let proof = pruned
+                .batch_proof(&keep)
+                .expect("kept leaves should be provable");
+            assert!(proof
+                .verify(&keep, &forest_roots(&pruned), forest.leaves)
+                .unwrap());
+        }
     }
 }
 // -------------------------------------------------------------------
@@ -183,10 +843,20 @@ pub mod pollard_conv {
 // -------------------------------------------------------------------
 #[cfg(test)]
 mod parquet_tests {
-    use super::parquet::get_all_leaf_hashes;
-    use duckdb::Connection;
+    use super::parquet::{
+        detect_format, for_each_leaf_batch, for_each_leaf_batch_cancellable, get_all_leaf_hashes,
+        DumpFormat,
+    };
+    use crate::block_hashes::BlockHashes;
+    use crate::txoutset::{encode, Coin};
+    use bitcoin::hashes::Hash;
+    use bitcoin::hex::FromHex;
+    use bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf, TxOut};
+    use duckdb::{params, Connection};
+    use rustreexo::accumulator::mem_forest::MemForest;
     use rustreexo::accumulator::node_hash::BitcoinNodeHash;
     use tempfile::tempdir;
+    use tokio_util::sync::CancellationToken;
 
     #[test]
     fn test_get_all_leaf_hashes_filters_coinbase() {
@@ -229,8 +899,325 @@ mod parquet_tests {
         );
         conn.execute(&sql, []).unwrap();
         // Extract leaves
-        let leaves: Vec<BitcoinNodeHash> = get_all_leaf_hashes(&path).unwrap();
+        let leaves: Vec<BitcoinNodeHash> = get_all_leaf_hashes(&path, None).unwrap();
         // Should only include the two non-coinbase entries
         assert_eq!(leaves.len(), 2);
     }
+
+    #[test]
+    fn test_get_all_leaf_hashes_reports_bad_txid() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("utxos.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+            [],
+        ).unwrap();
+        let txid_ok = "a".repeat(64);
+        conn.execute(
+            &format!("INSERT INTO utxos VALUES ('{txid_ok}', 100, 0, 1, x'0102', FALSE)"),
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO utxos VALUES ('not-a-txid', 200, 7, 2, x'0304', FALSE)",
+            [],
+        )
+        .unwrap();
+        let sql = format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        );
+        conn.execute(&sql, []).unwrap();
+        // A malformed txid is a handled error naming the row, not a panic
+        let err = format!("{:#}", get_all_leaf_hashes(&path, None).unwrap_err());
+        assert!(err.contains("not-a-txid"), "{err}");
+        assert!(err.contains("vout 7"), "{err}");
+    }
+
+    #[test]
+    fn test_get_all_leaf_hashes_rejects_overflowing_height() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("utxos.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+            [],
+        ).unwrap();
+        // 2^31 shifted left by one no longer fits in a u32 header code
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params!["a".repeat(64), 100, 0, 1i64 << 31, vec![0x51u8], false],
+        )
+        .unwrap();
+        let sql = format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        );
+        conn.execute(&sql, []).unwrap();
+        let err = format!("{:#}", get_all_leaf_hashes(&path, None).unwrap_err());
+        assert!(err.contains("too large for a header code"), "{err}");
+    }
+
+    /// The same UTXO set in every dump format: `(txid, amount, vout, height, script, coinbase)`.
+    const UTXOS: [(char, i64, i32, i64, &str, bool); 3] = [
+        ('a', 50, 0, 0, "00", true),
+        ('b', 100, 1, 1, "0102", false),
+        (
+            'c',
+            200,
+            2,
+            2,
+            "0014abababababababababababababababababababab",
+            false,
+        ),
+    ];
+
+    #[test]
+    fn test_formats_yield_equal_roots() {
+        let tmp = tempdir().unwrap();
+        // a quote in the path must not end the SQL string it is read through
+        let dir = tmp.path().join("o'dump");
+        std::fs::create_dir(&dir).unwrap();
+
+        let parquet = dir.join("utxos.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+            [],
+        ).unwrap();
+        for (c, amount, vout, height, script, coinbase) in UTXOS {
+            let txid = c.to_string().repeat(64);
+            let script = Vec::<u8>::from_hex(script).unwrap();
+            conn.execute(
+                "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+                params![txid, amount, vout, height, script, coinbase],
+            )
+            .unwrap();
+        }
+        let sql = format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            parquet.to_string_lossy().replace('\'', "''")
+        );
+        conn.execute(&sql, []).unwrap();
+
+        let csv = dir.join("utxos.csv");
+        let mut text = String::from("txid,amount,vout,height,script,coinbase\n");
+        for (c, amount, vout, height, script, coinbase) in UTXOS {
+            let txid = c.to_string().repeat(64);
+            text += &format!("{txid},{amount},{vout},{height},{script},{coinbase}\n");
+        }
+        std::fs::write(&csv, text).unwrap();
+
+        let core = dir.join("utxo_snapshot");
+        let coins: Vec<_> = UTXOS
+            .iter()
+            .map(|&(c, amount, vout, height, script, coinbase)| Coin {
+                outpoint: OutPoint {
+                    txid: c.to_string().repeat(64).parse().unwrap(),
+                    vout: vout as u32,
+                },
+                height: height as u32,
+                coinbase,
+                txout: TxOut {
+                    value: Amount::from_sat(amount as u64),
+                    script_pubkey: ScriptBuf::from_hex(script).unwrap(),
+                },
+            })
+            .collect();
+        std::fs::write(&core, encode::snapshot(BlockHash::all_zeros(), &coins)).unwrap();
+
+        assert_eq!(detect_format(&parquet).unwrap(), DumpFormat::Parquet);
+        assert_eq!(detect_format(&csv).unwrap(), DumpFormat::Csv);
+        assert_eq!(detect_format(&core).unwrap(), DumpFormat::CoreSnapshot);
+        let roots = |path| {
+            let leaves = get_all_leaf_hashes(path, None).unwrap();
+            assert_eq!(leaves.len(), 2);
+            let mut forest = MemForest::<BitcoinNodeHash>::new();
+            forest.modify(&leaves, &[]).unwrap();
+            forest
+                .get_roots()
+                .iter()
+                .map(|root| root.get_data())
+                .collect::<Vec<_>>()
+        };
+        let from_parquet = roots(&parquet);
+        assert_eq!(roots(&csv), from_parquet);
+        assert_eq!(roots(&core), from_parquet);
+    }
+
+    #[test]
+    fn test_core_snapshot_is_detected() {
+        let dir = tempdir().unwrap();
+        let snapshot = dir.path().join("utxo_dump");
+        std::fs::write(&snapshot, b"utxo\xff\x02\x00truncated").unwrap();
+        assert_eq!(detect_format(&snapshot).unwrap(), DumpFormat::CoreSnapshot);
+        let err = format!("{:#}", get_all_leaf_hashes(&snapshot, None).unwrap_err());
+        assert!(err.contains("snapshot header"), "{err}");
+
+        // an empty snapshot without the header, told apart by its extension
+        let legacy = dir.path().join("utxo.dat");
+        std::fs::write(&legacy, [0u8; 40]).unwrap();
+        assert_eq!(detect_format(&legacy).unwrap(), DumpFormat::CoreSnapshot);
+        assert!(get_all_leaf_hashes(&legacy, None).unwrap().is_empty());
+
+        let unknown = dir.path().join("utxos.bin");
+        std::fs::write(&unknown, b"garbage").unwrap();
+        assert!(detect_format(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_batched_extraction_matches_collect_all() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("utxos.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+            [],
+        ).unwrap();
+        for vout in 0..10 {
+            conn.execute(
+                "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    "d".repeat(64),
+                    1_000 + vout,
+                    vout,
+                    vout,
+                    vec![0x51u8],
+                    false
+                ],
+            )
+            .unwrap();
+        }
+        let sql = format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        );
+        conn.execute(&sql, []).unwrap();
+
+        let all = get_all_leaf_hashes(&path, None).unwrap();
+        let mut scratch = MemForest::<BitcoinNodeHash>::new();
+        scratch.modify(&all, &[]).unwrap();
+
+        let mut streamed = MemForest::<BitcoinNodeHash>::new();
+        let mut sizes = Vec::new();
+        for_each_leaf_batch(&path, None, 4, |batch| {
+            sizes.push(batch.len());
+            streamed.modify(batch, &[]).map_err(|e| anyhow::anyhow!(e))
+        })
+        .unwrap();
+
+        assert_eq!(sizes, vec![4, 4, 2]);
+        assert_eq!(streamed.leaves, scratch.leaves);
+        let roots = |f: &MemForest<BitcoinNodeHash>| {
+            f.get_roots()
+                .iter()
+                .map(|r| r.get_data())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(roots(&streamed), roots(&scratch));
+        assert_eq!(super::parquet::max_height(&path).unwrap(), Some(9));
+    }
+
+    #[test]
+    fn test_leaves_use_real_block_hash() {
+        use bitcoin::hashes::Hash;
+        use bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf, TxOut, Txid};
+        use utreexo::LeafData;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("utxos.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+            [],
+        ).unwrap();
+        let txid = Txid::from_byte_array([0xee; 32]);
+        let script = vec![0x00, 0x14, 0x11, 0x22];
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                txid.to_string(),
+                5_000i64,
+                1i32,
+                2i64,
+                script.clone(),
+                false
+            ],
+        )
+        .unwrap();
+        let sql = format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        );
+        conn.execute(&sql, []).unwrap();
+
+        let hashes = BlockHashes::new(
+            (0..3u8)
+                .map(|i| BlockHash::from_byte_array([i + 1; 32]))
+                .collect(),
+        );
+        // the leaf the circuit builds for this output when processing block 2
+        let circuit_leaf = LeafData {
+            block_hash: BlockHash::from_byte_array([3; 32]),
+            prevout: OutPoint { txid, vout: 1 },
+            header_code: 2 << 1,
+            utxo: TxOut {
+                value: Amount::from_sat(5_000),
+                script_pubkey: ScriptBuf::from_bytes(script),
+            },
+        }
+        .get_leaf_hashes();
+
+        let leaves = get_all_leaf_hashes(&path, Some(&hashes)).unwrap();
+        assert_eq!(leaves, vec![circuit_leaf]);
+        // the zero placeholder does not match
+        assert_ne!(get_all_leaf_hashes(&path, None).unwrap(), leaves);
+
+        // a height past the lookup is an error, not a silent placeholder
+        let short = BlockHashes::new(vec![BlockHash::all_zeros()]);
+        let err = format!(
+            "{:#}",
+            get_all_leaf_hashes(&path, Some(&short)).unwrap_err()
+        );
+        assert!(err.contains("no block hash known for height 2"), "{err}");
+    }
+
+    #[test]
+    fn test_cancelled_extraction_stops_between_chunks() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("utxos.parquet");
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!(
+            "COPY (SELECT repeat('d', 64) AS txid, 1000::BIGINT AS amount, i::INTEGER AS vout, \
+             1::BIGINT AS height, '\\x51'::BLOB AS script, false AS coinbase \
+             FROM range(200000) t(i)) TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        );
+        conn.execute(&sql, []).unwrap();
+
+        let mut sizes = Vec::new();
+        let mut leaves = Vec::new();
+        let cancel = CancellationToken::new();
+        for_each_leaf_batch_cancellable(&path, None, false, 50_000, &cancel, |batch| {
+            sizes.push(batch.len());
+            leaves.extend_from_slice(batch);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(sizes, vec![50_000; 4]);
+        // the chunks are consecutive slices of one pass over the dump
+        assert_eq!(leaves, get_all_leaf_hashes(&path, None).unwrap());
+
+        // cancel from inside the first chunk: no further chunk is queried
+        let mut chunks = 0;
+        let err = for_each_leaf_batch_cancellable(&path, None, false, 1_000, &cancel, |_| {
+            chunks += 1;
+            cancel.cancel();
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(chunks, 1);
+        assert!(err.to_string().contains("cancelled"), "{err}");
+    }
 }
@@ -1,13 +1,23 @@
 use anyhow;
+use bitcoin::Block;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use rustreexo::accumulator::stump::Stump;
 use serde::Serialize;
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::select;
-use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 use tokio::task;
 use tokio_util::sync::CancellationToken;
 
-use crate::{builder, updater};
+use crate::builder;
+use crate::sync_state;
+use crate::updater::{self, ApplyBlockError};
+
+/// Commands that can wait for the background worker before [`Context::send`] reports
+/// [`DispatchError::Busy`], unless configured otherwise.
+pub const COMMAND_QUEUE_CAPACITY: usize = 8;
 
 /// Commands accepted by the service.
 #[derive(Debug, Clone)]
@@ -15,34 +25,164 @@ pub enum Command {
     Build {
         parquet: String,
         resume_from: Option<String>,
+        block_hash: Option<String>,
+        validate_sample: Option<usize>,
+        batch_size: Option<usize>,
+        /// Add OP_RETURN outputs as leaves (see [`builder::start_build`])
+        keep_op_return: bool,
     },
     Update(u64),
+    /// Apply a block submitted by the caller (see [`updater::apply_block`]); sent by
+    /// [`Context::submit_block`].
+    Block {
+        height: u64,
+        block: Arc<Block>,
+        input_leaves: Arc<Vec<BitcoinNodeHash>>,
+        reply: BlockReply,
+    },
     Pause,
     Resume,
     Stop,
+    /// Copy the working files to `dir`, relative to the data directory.
     Dump {
         dir: PathBuf,
     },
+    /// Replace the working files with the snapshot in `dir`, relative to the data directory;
+    /// sent by [`Context::restore`].
     Restore {
         dir: PathBuf,
+        reply: RestoreReply,
+    },
+    /// Seed a data directory that has no forest yet with another instance's `pollard.bin`;
+    /// sent by [`Context::import_snapshot`].
+    Import {
+        snapshot: Arc<Vec<u8>>,
+        reply: ImportReply,
     },
+    /// Drop every build or update still waiting behind the running job.
+    ClearQueue,
+    /// Drop the queue, cancel the running job and wait for it to finish, then stop the
+    /// background worker. No command is accepted afterwards.
+    Shutdown,
+}
+
+/// Outcome of a submitted block: the roots after applying it, or why it wasn't applied.
+pub type BlockOutcome = Result<Stump, ApplyBlockError>;
+
+/// Outcome of an imported snapshot.
+pub type ImportOutcome = Result<(), ImportError>;
+
+/// Why [`Context::import_snapshot`] didn't take a snapshot over.
+#[derive(Debug)]
+pub enum ImportError {
+    /// A job is running, paused or queued. Nothing was written.
+    Busy,
+    /// The data directory already has a forest; restore a snapshot instead. Nothing was
+    /// written.
+    HasForest,
+    /// The snapshot doesn't decode. Nothing was written.
+    Invalid(String),
+    /// The data directory couldn't be written.
+    Storage(anyhow::Error),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Busy => write!(f, "a job is running or queued"),
+            ImportError::HasForest => write!(f, "mem_forest.bin exists; use /restore instead"),
+            ImportError::Invalid(msg) => write!(f, "invalid snapshot: {msg}"),
+            ImportError::Storage(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// Outcome of a restore.
+pub type RestoreOutcome = Result<(), RestoreError>;
+
+/// Why [`Context::restore`] didn't replace the working files.
+#[derive(Debug)]
+pub enum RestoreError {
+    /// A job is running, paused or queued. Nothing was written.
+    Busy,
+    /// The snapshot is missing, fails validation or couldn't be copied. The service is left
+    /// in [`ServiceState::Error`].
+    Failed(std::io::Error),
+}
+
+impl std::fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestoreError::Busy => write!(f, "a job is running or queued"),
+            RestoreError::Failed(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// Where the worker sends the outcome of a command that answers, e.g. the [`BlockOutcome`] of
+/// a [`Command::Block`]. Only the first send goes anywhere.
+#[derive(Debug)]
+pub struct Reply<T>(Arc<std::sync::Mutex<Option<oneshot::Sender<T>>>>);
+
+// Not derived, which would require `T: Clone`
+impl<T> Clone for Reply<T> {
+    fn clone(&self) -> Self {
+        Reply(self.0.clone())
+    }
 }
 
+impl<T> Reply<T> {
+    fn new(tx: oneshot::Sender<T>) -> Self {
+        Reply(Arc::new(std::sync::Mutex::new(Some(tx))))
+    }
+
+    fn send(&self, outcome: T) {
+        if let Some(tx) = self.0.lock().unwrap().take() {
+            let _ = tx.send(outcome);
+        }
+    }
+}
+
+/// Reply to a [`Command::Block`].
+pub type BlockReply = Reply<BlockOutcome>;
+
+/// Reply to a [`Command::Import`].
+pub type ImportReply = Reply<ImportOutcome>;
+
+/// Reply to a [`Command::Restore`].
+pub type RestoreReply = Reply<RestoreOutcome>;
+
 /// Public state as exposed via the REST API.
 #[derive(Debug, Clone, Serialize, PartialEq, Eq)]
 #[serde(tag = "state", rename_all = "lowercase")]
 pub enum ServiceState {
     Idle,
     Building,
-    Updating { height: u64 },
+    Updating {
+        height: u64,
+    },
     Paused,
-    Error { msg: String },
+    /// A build or update is running and `pending` more are queued behind it.
+    Queued {
+        pending: usize,
+    },
+    Error {
+        msg: String,
+    },
 }
 
 #[derive(Clone, Serialize)]
 pub struct Status {
     pub state: ServiceState,
     pub uptime_secs: u64,
+    /// Spent prevouts updates skipped since the build (see
+    /// [`sync_state::SyncState::unresolved_prevouts`]); left out when there are none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unresolved_prevouts: Option<u64>,
 }
 
 /// Internally tracked long-running task so we can cancel / resume.
@@ -51,8 +191,18 @@ enum JobKind {
     Build {
         parquet: String,
         resume_from: Option<String>,
+        block_hash: Option<String>,
+        validate_sample: Option<usize>,
+        batch_size: Option<usize>,
+        keep_op_return: bool,
     },
     Update(u64),
+    Block {
+        height: u64,
+        block: Arc<Block>,
+        input_leaves: Arc<Vec<BitcoinNodeHash>>,
+        reply: BlockReply,
+    },
 }
 
 struct RunningJob {
@@ -61,92 +211,273 @@ struct RunningJob {
     kind: JobKind,
 }
 
+impl RunningJob {
+    fn spawn(kind: JobKind, data_dir: &Path) -> Self {
+        let cancel = CancellationToken::new();
+        let data_dir = data_dir.to_owned();
+        let join = match kind.clone() {
+            JobKind::Build {
+                parquet,
+                resume_from,
+                block_hash,
+                validate_sample,
+                batch_size,
+                keep_op_return,
+            } => {
+                // The build polls the token itself, so pausing waits until the
+                // extraction has actually stopped.
+                let task_cancel = cancel.clone();
+                task::spawn(async move {
+                    builder::start_build(
+                        &data_dir,
+                        &parquet,
+                        resume_from.as_deref(),
+                        block_hash.as_deref(),
+                        validate_sample,
+                        batch_size,
+                        keep_op_return,
+                        task_cancel,
+                    )
+                    .await
+                })
+            }
+            // Spawn a blocking task for update + prune since MemForest is !Send
+            JobKind::Update(h) => {
+                let task_cancel = cancel.clone();
+                task::spawn_blocking(move || updater::update_block_sync(&data_dir, h, task_cancel))
+            }
+            // Applying one block is quick, so it isn't cancellable. A refused block wrote
+            // nothing and doesn't stop the queue; only a storage failure is the job's error.
+            JobKind::Block {
+                height,
+                block,
+                input_leaves,
+                reply,
+            } => task::spawn_blocking(move || {
+                let outcome = updater::apply_block(&data_dir, height, &block, &input_leaves);
+                let res = match &outcome {
+                    Err(ApplyBlockError::Storage(e)) => Err(anyhow::anyhow!("{e:#}")),
+                    _ => Ok(()),
+                };
+                reply.send(outcome);
+                res
+            }),
+        };
+        RunningJob { cancel, join, kind }
+    }
+
+    /// State to report while this job runs with `pending` jobs queued behind it.
+    fn state(&self, pending: usize) -> ServiceState {
+        match &self.kind {
+            _ if pending > 0 => ServiceState::Queued { pending },
+            JobKind::Build { .. } => ServiceState::Building,
+            JobKind::Update(h) | JobKind::Block { height: h, .. } => {
+                ServiceState::Updating { height: *h }
+            }
+        }
+    }
+}
+
+/// Why a finished job failed, if it did.
+fn job_error(res: Result<anyhow::Result<()>, task::JoinError>) -> Option<String> {
+    match res {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(e) => Some(format!("join error: {e}")),
+    }
+}
+
+/// Start `kind` if no job is running and the worker isn't `paused`, otherwise queue it behind
+/// the running or paused one. Returns the state to report.
+fn start_or_queue(
+    kind: JobKind,
+    running: &mut Option<RunningJob>,
+    queue: &mut VecDeque<JobKind>,
+    paused: bool,
+    data_dir: &Path,
+) -> ServiceState {
+    match running {
+        _ if paused => {
+            queue.push_back(kind);
+            ServiceState::Paused
+        }
+        Some(job) => {
+            queue.push_back(kind);
+            job.state(queue.len())
+        }
+        None => running
+            .insert(RunningJob::spawn(kind, data_dir))
+            .state(queue.len()),
+    }
+}
+
 /// Main handle used by HTTP layer.
 #[derive(Clone)]
 pub struct Context {
     state: Arc<RwLock<ServiceState>>,
     start: std::time::Instant,
     tx: mpsc::Sender<Command>,
+    data_dir: PathBuf,
 }
 
 #[derive(Debug)]
 pub enum DispatchError {
     InvalidState,
+    /// The worker has fallen behind and its command queue is full; try again later.
+    Busy,
     ChannelClosed,
 }
 
 impl Context {
+    /// A context keeping its files in the current directory.
     pub fn new() -> Self {
-        let (tx, mut rx) = mpsc::channel::<Command>(8);
-        let tx_bg = tx.clone();
+        Self::with_capacity(COMMAND_QUEUE_CAPACITY)
+    }
+
+    /// Like [`Context::new`], but with room for `capacity` commands waiting for the worker.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_in(capacity, ".")
+    }
+
+    /// A context keeping `mem_forest.bin`, `pollard.bin` and the rest of its files in
+    /// `data_dir` instead of the current directory, so several can run in one process.
+    pub fn in_dir(data_dir: impl Into<PathBuf>) -> Self {
+        Self::with_capacity_in(COMMAND_QUEUE_CAPACITY, data_dir)
+    }
+
+    /// [`Context::with_capacity`] and [`Context::in_dir`] combined.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn with_capacity_in(capacity: usize, data_dir: impl Into<PathBuf>) -> Self {
+        let data_dir = data_dir.into();
+        let data_dir_bg = data_dir.clone();
+        let (tx, mut rx) = mpsc::channel::<Command>(capacity);
         let state = Arc::new(RwLock::new(ServiceState::Idle));
         let state_bg = state.clone();
-        let fs_lock = Arc::new(Mutex::new(()));
+        // Held while the working files are copied by a dump or replaced by a restore or import
+        let fs_lock_bg = Arc::new(Mutex::new(()));
 
         task::spawn(async move {
             let mut running: Option<RunningJob> = None;
-            while let Some(cmd) = rx.recv().await {
+            // Builds and updates waiting for the running job, in arrival order.
+            let mut queue: VecDeque<JobKind> = VecDeque::new();
+            // The job a pause stopped, started again on resume before the queue.
+            let mut paused: Option<JobKind> = None;
+            loop {
+                // Wait for the next command, but also notice as soon as the running job
+                // finishes so its outcome is reflected in the state right away.
+                let cmd = select! {
+                    cmd = rx.recv() => match cmd {
+                        Some(cmd) => cmd,
+                        None => break,
+                    },
+                    res = async { (&mut running.as_mut().unwrap().join).await },
+                        if running.is_some() =>
+                    {
+                        running = None;
+                        *state_bg.write().await = match (job_error(res), queue.pop_front()) {
+                            // A failed job leaves the files as they were, so the jobs queued
+                            // behind it (e.g. later blocks) must not run on top of them.
+                            (Some(msg), _) => {
+                                queue.clear();
+                                ServiceState::Error { msg }
+                            }
+                            (None, Some(next)) => start_or_queue(
+                                next,
+                                &mut running,
+                                &mut queue,
+                                false,
+                                &data_dir_bg,
+                            ),
+                            (None, None) => ServiceState::Idle,
+                        };
+                        continue;
+                    }
+                };
                 match cmd {
-                    // =========== BUILD ============
+                    // =========== BUILD / UPDATE ============
                     Command::Build {
                         parquet,
                         resume_from,
+                        block_hash,
+                        validate_sample,
+                        batch_size,
+                        keep_op_return,
                     } => {
-                        if running.is_some() {
-                            // reject – already busy
-                            continue;
-                        }
-                        *state_bg.write().await = ServiceState::Building;
-                        let cancel = CancellationToken::new();
-                        let task_cancel = cancel.clone();
-
-                        // clone for storage & move into async
-                        let parquet_clone = parquet.clone();
-                        let resume_clone = resume_from.clone();
-
-                        let handle = task::spawn(async move {
-                            run_with_cancel(task_cancel, async move {
-                                builder::start_build(&parquet, resume_from.as_deref()).await
-                            })
-                            .await
-                        });
-                        running = Some(RunningJob {
-                            cancel,
-                            join: handle,
-                            kind: JobKind::Build {
-                                parquet: parquet_clone,
-                                resume_from: resume_clone,
-                            },
-                        });
+                        let kind = JobKind::Build {
+                            parquet,
+                            resume_from,
+                            block_hash,
+                            validate_sample,
+                            batch_size,
+                            keep_op_return,
+                        };
+                        *state_bg.write().await = start_or_queue(
+                            kind,
+                            &mut running,
+                            &mut queue,
+                            paused.is_some(),
+                            &data_dir_bg,
+                        );
                     }
-                    // =========== UPDATE ============
                     Command::Update(h) => {
-                        if running.is_some() {
-                            continue;
-                        }
-                        *state_bg.write().await = ServiceState::Updating { height: h };
-                        // Spawn a blocking task for update + prune since MemForest is !Send
-                        let cancel = CancellationToken::new();
-                        let handle = task::spawn_blocking(move || {
-                            // Perform async update in sync context
-                            updater::update_block_sync(h)
-                        });
-                        running = Some(RunningJob {
-                            cancel,
-                            join: handle,
-                            kind: JobKind::Update(h),
-                        });
+                        *state_bg.write().await = start_or_queue(
+                            JobKind::Update(h),
+                            &mut running,
+                            &mut queue,
+                            paused.is_some(),
+                            &data_dir_bg,
+                        );
+                    }
+                    Command::Block {
+                        height,
+                        block,
+                        input_leaves,
+                        reply,
+                    } => {
+                        let kind = JobKind::Block {
+                            height,
+                            block,
+                            input_leaves,
+                            reply,
+                        };
+                        *state_bg.write().await = start_or_queue(
+                            kind,
+                            &mut running,
+                            &mut queue,
+                            paused.is_some(),
+                            &data_dir_bg,
+                        );
                     }
                     // =========== PAUSE ============
                     Command::Pause => {
                         if let Some(job) = running.take() {
-                            // Signal cancellation and wait until task observes it.
+                            // Wait until the job has observed the cancellation, so a resume
+                            // can't start it again while it is still running. The queue stays
+                            // as it is.
                             job.cancel.cancel();
-                            let st = state_bg.clone();
-                            task::spawn(async move {
-                                let _ = job.join.await; // ignore result – will be handled by loop once finished
-                                *st.write().await = ServiceState::Paused;
-                            });
+                            let res = job.join.await;
+                            *state_bg.write().await = match job.kind {
+                                // A block job ignores the pause and has run to completion, so
+                                // only the jobs queued behind it are left to resume
+                                JobKind::Block { .. } => match job_error(res) {
+                                    Some(msg) => {
+                                        queue.clear();
+                                        ServiceState::Error { msg }
+                                    }
+                                    None => ServiceState::Paused,
+                                },
+                                kind => {
+                                    paused = Some(kind);
+                                    ServiceState::Paused
+                                }
+                            };
                         }
                     }
                     // =========== RESUME ============
@@ -154,87 +485,102 @@ impl Context {
                         if *state_bg.read().await != ServiceState::Paused {
                             continue;
                         }
-                        if let Some(prev) = running.take() {
-                            match prev.kind.clone() {
-                                JobKind::Build {
-                                    parquet,
-                                    resume_from,
-                                } => {
-                                    let _ = tx_bg
-                                        .send(Command::Build {
-                                            parquet,
-                                            resume_from,
-                                        })
-                                        .await;
-                                }
-                                JobKind::Update(h) => {
-                                    let _ = tx_bg.send(Command::Update(h)).await;
-                                }
+                        // Start the paused job over, then the jobs queued behind it
+                        let next = paused.take().or_else(|| queue.pop_front());
+                        *state_bg.write().await = match next {
+                            Some(kind) => {
+                                start_or_queue(kind, &mut running, &mut queue, false, &data_dir_bg)
                             }
+                            None => ServiceState::Idle,
+                        };
+                    }
+                    // =========== CLEAR QUEUE ============
+                    Command::ClearQueue => {
+                        queue.clear();
+                        if let Some(job) = &running {
+                            *state_bg.write().await = job.state(0);
                         }
                     }
+                    // =========== SHUTDOWN ============
+                    Command::Shutdown => {
+                        queue.clear();
+                        if let Some(job) = running.take() {
+                            // Jobs only write once they have finished, or not at all when
+                            // cancelled, so waiting for the job leaves the files consistent.
+                            job.cancel.cancel();
+                            let _ = job.join.await;
+                        }
+                        // Let a dump or restore in progress finish first
+                        let _g = fs_lock_bg.lock().await;
+                        *state_bg.write().await = ServiceState::Idle;
+                        break;
+                    }
                     // =========== STOP ============
                     Command::Stop => {
-                        if let Some(job) = &running {
-                            // cancel the running job
+                        queue.clear();
+                        paused = None;
+                        if let Some(job) = running.take() {
+                            // Wait for the cancelled job, so nothing is still writing once the
+                            // state says Idle and the next build or update can start
                             job.cancel.cancel();
+                            let _ = job.join.await;
                         }
-                        running = None;
                         *state_bg.write().await = ServiceState::Idle;
                     }
                     // =========== DUMP ============
                     Command::Dump { dir } => {
                         // Run dump synchronously (block on dump completion) under fs_lock
-                        let lock = fs_lock.clone();
+                        let lock = fs_lock_bg.clone();
                         let st = state_bg.clone();
                         let dir_clone = dir.clone();
                         // Acquire lock
                         let _g = lock.lock().await;
                         // Perform dump
-                        if let Err(e) = state_helpers::perform_dump(dir_clone).await {
+                        if let Err(e) =
+                            state_helpers::perform_dump(data_dir_bg.clone(), dir_clone).await
+                        {
                             *st.write().await = ServiceState::Error { msg: e.to_string() };
                         }
                     }
-                    // =========== RESTORE ============
-                    Command::Restore { dir } => {
-                        // Cancel any running job and mark as restoring
-                        if let Some(job) = &running {
-                            job.cancel.cancel();
-                            running = None;
-                        }
-                        // Mark service busy for restore so wait_until_idle blocks until complete
-                        *state_bg.write().await = ServiceState::Updating { height: 0 };
-                        let lock = fs_lock.clone();
-                        let st = state_bg.clone();
-                        // Execute restore synchronously under lock
-                        let _g = lock.lock().await;
-                        match state_helpers::perform_restore(dir).await {
-                            Ok(_) => *st.write().await = ServiceState::Idle,
-                            Err(e) => {
-                                *st.write().await = ServiceState::Error { msg: e.to_string() }
-                            }
+                    // =========== IMPORT ============
+                    Command::Import { snapshot, reply } => {
+                        // Nothing may run, or wait to run, on top of the imported state
+                        if running.is_some() || paused.is_some() || !queue.is_empty() {
+                            reply.send(Err(ImportError::Busy));
+                            continue;
                         }
+                        let _g = fs_lock_bg.lock().await;
+                        let data = data_dir_bg.clone();
+                        let outcome = task::spawn_blocking(move || {
+                            state_helpers::import_sync(&data, &snapshot)
+                        })
+                        .await
+                        .unwrap_or_else(|e| {
+                            Err(ImportError::Storage(anyhow::anyhow!("join error: {e}")))
+                        });
+                        reply.send(outcome);
                     }
-                }
-
-                // poll finished job (non-blocking)
-                if running
-                    .as_ref()
-                    .map(|j| j.join.is_finished())
-                    .unwrap_or(false)
-                {
-                    // Safe to unwrap because checked above
-                    let job = running.take().unwrap();
-                    match job.join.await {
-                        Ok(Ok(_)) => *state_bg.write().await = ServiceState::Idle,
-                        Ok(Err(e)) => {
-                            *state_bg.write().await = ServiceState::Error { msg: e.to_string() }
-                        }
-                        Err(e) => {
-                            *state_bg.write().await = ServiceState::Error {
-                                msg: format!("join error: {e}"),
-                            }
+                    // =========== RESTORE ============
+                    Command::Restore { dir, reply } => {
+                        // Nothing may run, or wait to run, on top of the restored files
+                        if running.is_some() || paused.is_some() || !queue.is_empty() {
+                            reply.send(Err(RestoreError::Busy));
+                            continue;
                         }
+                        let _g = fs_lock_bg.lock().await;
+                        *state_bg.write().await = ServiceState::Updating { height: 0 };
+                        let data = data_dir_bg.clone();
+                        let outcome =
+                            task::spawn_blocking(move || state_helpers::restore_sync(&data, &dir))
+                                .await
+                                .unwrap_or_else(|e| {
+                                    Err(std::io::Error::other(format!("join error: {e}")))
+                                });
+                        *state_bg.write().await = match &outcome {
+                            Ok(()) => ServiceState::Idle,
+                            Err(e) => ServiceState::Error { msg: e.to_string() },
+                        };
+                        reply.send(outcome.map_err(RestoreError::Failed));
                     }
                 }
             }
@@ -244,6 +590,7 @@ impl Context {
             state,
             start: std::time::Instant::now(),
             tx,
+            data_dir,
         }
     }
 
@@ -254,44 +601,114 @@ impl Context {
             return Err(DispatchError::InvalidState);
         }
 
-        // For commands that will certainly move us out of Idle immediately, update
-        // the shared state *before* we enqueue so that concurrent calls see the
-        // new state right away and can be rejected.
-        {
-            let mut st = self.state.write().await;
-            match (&cmd, &*st) {
-                (Command::Build { .. }, ServiceState::Idle) => {
-                    *st = ServiceState::Building;
-                }
-                (Command::Update(h), ServiceState::Idle) => {
-                    *st = ServiceState::Updating { height: *h };
-                }
-                _ => {}
+        // For commands that will certainly move us out of Idle immediately, update the shared
+        // state while still holding the lock we enqueue under, so that concurrent calls see the
+        // new state right away and can be rejected. A command that couldn't be enqueued leaves
+        // the state alone.
+        let mut st = self.state.write().await;
+        let next = match (&cmd, &*st) {
+            (Command::Build { .. }, ServiceState::Idle) => Some(ServiceState::Building),
+            (Command::Update(h), ServiceState::Idle)
+            | (Command::Block { height: h, .. }, ServiceState::Idle) => {
+                Some(ServiceState::Updating { height: *h })
             }
+            _ => None,
+        };
+        // Dispatch other commands to the background worker without waiting for room, so a
+        // burst of requests can't hold up the HTTP workers
+        self.tx.try_send(cmd).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => DispatchError::Busy,
+            mpsc::error::TrySendError::Closed(_) => DispatchError::ChannelClosed,
+        })?;
+        if let Some(next) = next {
+            *st = next;
         }
+        Ok(())
+    }
 
-        // Handle Restore synchronously: apply snapshot immediately
-        if let Command::Restore { dir } = &cmd {
-            // mark service busy for restore
-            *self.state.write().await = ServiceState::Updating { height: 0 };
-            // perform restore from snapshot directory
-            match state_helpers::restore_sync(dir.clone()) {
-                Ok(_) => *self.state.write().await = ServiceState::Idle,
-                Err(e) => *self.state.write().await = ServiceState::Error { msg: e.to_string() },
-            }
-            return Ok(());
-        }
-        // Dispatch other commands to the background worker
-        self.tx
-            .send(cmd)
-            .await
-            .map_err(|_| DispatchError::ChannelClosed)
+    /// Queue `block` at `height` behind any running build or update (see
+    /// [`updater::apply_block`]). The returned receiver gets the outcome once the worker has
+    /// applied or refused the block; it is dropped unanswered if the block is cleared from the
+    /// queue or the service stops first.
+    pub async fn submit_block(
+        &self,
+        height: u64,
+        block: Block,
+        input_leaves: Vec<BitcoinNodeHash>,
+    ) -> Result<oneshot::Receiver<BlockOutcome>, DispatchError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Block {
+            height,
+            block: Arc::new(block),
+            input_leaves: Arc::new(input_leaves),
+            reply: Reply::new(tx),
+        })
+        .await?;
+        Ok(rx)
+    }
+
+    /// Take over `snapshot`, another instance's `pollard.bin` as served by `GET /snapshot`:
+    /// write it and, from its envelope, the sync state. Only an idle service without a
+    /// `mem_forest.bin` takes it; the worker checks that under the same lock as dumps and
+    /// restores, so no job can start on top of it. The returned receiver gets the outcome.
+    pub async fn import_snapshot(
+        &self,
+        snapshot: Vec<u8>,
+    ) -> Result<oneshot::Receiver<ImportOutcome>, DispatchError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Import {
+            snapshot: Arc::new(snapshot),
+            reply: Reply::new(tx),
+        })
+        .await?;
+        Ok(rx)
+    }
+
+    /// Replace the working files with the snapshot in `dir`, relative to the data directory.
+    /// Only an idle or failed service restores; the worker checks that nothing runs or waits
+    /// to run and replaces the files under the same lock as dumps and imports. The service is
+    /// [`ServiceState::Updating`] meanwhile, then idle, or failed if the snapshot didn't
+    /// validate. The returned receiver gets the outcome.
+    pub async fn restore(
+        &self,
+        dir: impl Into<PathBuf>,
+    ) -> Result<oneshot::Receiver<RestoreOutcome>, DispatchError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Restore {
+            dir: dir.into(),
+            reply: Reply::new(tx),
+        })
+        .await?;
+        Ok(rx)
+    }
+
+    /// Shut the background worker down (see [`Command::Shutdown`]) and wait until it has
+    /// stopped.
+    pub async fn shutdown(&self) -> Result<(), DispatchError> {
+        self.send(Command::Shutdown).await?;
+        self.tx.closed().await;
+        Ok(())
+    }
+
+    /// Directory holding the service's files.
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Whether the background worker is still running and accepting commands.
+    pub fn is_alive(&self) -> bool {
+        !self.tx.is_closed()
     }
 
     pub async fn status(&self) -> Status {
         Status {
             uptime_secs: self.start.elapsed().as_secs(),
             state: self.state.read().await.clone(),
+            unresolved_prevouts: sync_state::read(&self.data_dir)
+                .ok()
+                .flatten()
+                .map(|state| state.unresolved_prevouts)
+                .filter(|&n| n > 0),
         }
     }
 
@@ -299,16 +716,32 @@ impl Context {
         let state = self.state.read().await.clone();
         matches!(
             (state, cmd),
-            (ServiceState::Idle, Command::Build { .. })
+            (_, Command::ClearQueue)
+                | (_, Command::Shutdown)
+                | (ServiceState::Idle, Command::Build { .. })
                 | (ServiceState::Idle, Command::Update(_))
+                | (ServiceState::Idle, Command::Block { .. })
                 | (ServiceState::Idle, Command::Dump { .. })
                 | (ServiceState::Idle, Command::Restore { .. })
+                | (ServiceState::Idle, Command::Import { .. })
+                | (ServiceState::Building, Command::Build { .. })
+                | (ServiceState::Building, Command::Update(_))
+                | (ServiceState::Building, Command::Block { .. })
                 | (ServiceState::Building, Command::Pause)
                 | (ServiceState::Building, Command::Stop)
                 | (ServiceState::Building, Command::Dump { .. })
+                | (ServiceState::Updating { .. }, Command::Build { .. })
+                | (ServiceState::Updating { .. }, Command::Update(_))
+                | (ServiceState::Updating { .. }, Command::Block { .. })
                 | (ServiceState::Updating { .. }, Command::Pause)
                 | (ServiceState::Updating { .. }, Command::Stop)
                 | (ServiceState::Updating { .. }, Command::Dump { .. })
+                | (ServiceState::Queued { .. }, Command::Build { .. })
+                | (ServiceState::Queued { .. }, Command::Update(_))
+                | (ServiceState::Queued { .. }, Command::Block { .. })
+                | (ServiceState::Queued { .. }, Command::Pause)
+                | (ServiceState::Queued { .. }, Command::Stop)
+                | (ServiceState::Queued { .. }, Command::Dump { .. })
                 | (ServiceState::Paused, Command::Resume)
                 | (ServiceState::Paused, Command::Stop)
                 | (ServiceState::Paused, Command::Dump { .. })
@@ -323,47 +756,47 @@ impl Default for Context {
     }
 }
 
-// ------------------------------------------------------------------
-// helper util fn: run future until cancel fires
-// ------------------------------------------------------------------
-async fn run_with_cancel<F>(cancel: CancellationToken, fut: F) -> anyhow::Result<()>
-where
-    F: std::future::Future<Output = anyhow::Result<()>>,
-{
-    select! {
-        _ = cancel.cancelled() => Ok(()),
-        res = fut => res,
-    }
-}
-
 // ------------------------------------------------------------------
 // extract dump / restore helpers from earlier phase (reuse)
 // ------------------------------------------------------------------
 
 mod state_helpers {
+    use super::{ImportError, ImportOutcome};
+    use crate::delta::{self, DELTA_FILE};
+    use crate::sync_state::{self, SyncState, SYNC_STATE_FILE};
+    use crate::{forest, pollard};
     use std::io::{Error, ErrorKind};
-    use std::path::PathBuf;
-
-    /// Copy snapshot plus derive pollard (same as Phase-A implementation).
-    pub fn dump_sync(dir: PathBuf) -> std::io::Result<()> {
-        use std::path::Path;
+    use std::path::{Path, PathBuf};
 
+    /// Copy snapshot plus derive pollard (same as Phase-A implementation). `dir` is relative to
+    /// the data directory `data`.
+    pub fn dump_sync(data: &Path, dir: &Path) -> std::io::Result<()> {
+        let dir = data.join(dir);
         // Ensure target directory exists
         std::fs::create_dir_all(&dir)?;
 
         // Required: mem_forest.bin
-        std::fs::copy("mem_forest.bin", dir.join("mem_forest.bin"))?;
+        std::fs::copy(data.join("mem_forest.bin"), dir.join("mem_forest.bin"))?;
+
+        // Blocks applied since mem_forest.bin was last written, and which of them it contains
+        copy_or_remove(&data.join(DELTA_FILE), &dir.join(DELTA_FILE))?;
+        copy_or_remove(&forest_meta(data), &forest_meta(&dir))?;
 
         // Optional: block_hashes.bin (produced during initial build)
-        if Path::new("block_hashes.bin").exists() {
-            let _ = std::fs::copy("block_hashes.bin", dir.join("block_hashes.bin"));
+        if data.join("block_hashes.bin").exists() {
+            let _ = std::fs::copy(data.join("block_hashes.bin"), dir.join("block_hashes.bin"));
+        }
+
+        // Optional: which block the forest is synced to
+        if data.join(SYNC_STATE_FILE).exists() {
+            let _ = std::fs::copy(data.join(SYNC_STATE_FILE), dir.join(SYNC_STATE_FILE));
         }
 
         // Optional but recommended: pollard.bin.  If it does not exist yet we
         // create a trivial stub so that `restore_sync` will succeed.  (Proper
         // Pollard export will be added in the next phase.)
-        if Path::new("pollard.bin").exists() {
-            let _ = std::fs::copy("pollard.bin", dir.join("pollard.bin"));
+        if data.join("pollard.bin").exists() {
+            let _ = std::fs::copy(data.join("pollard.bin"), dir.join("pollard.bin"));
         } else {
             // create empty placeholder
             std::fs::File::create(dir.join("pollard.bin"))?;
@@ -372,7 +805,10 @@ mod state_helpers {
         Ok(())
     }
 
-    pub fn restore_sync(dir: PathBuf) -> std::io::Result<()> {
+    /// Restore the working files in the data directory `data` from the snapshot in `dir`,
+    /// relative to `data`.
+    pub fn restore_sync(data: &Path, dir: &Path) -> std::io::Result<()> {
+        let dir = data.join(dir);
         let forest_src = dir.join("mem_forest.bin");
         if !forest_src.exists() {
             return Err(Error::new(
@@ -384,23 +820,97 @@ mod state_helpers {
         // pollard.bin is optional for now (may be empty placeholder)
         let pollard_src = dir.join("pollard.bin");
 
-        std::fs::copy(&forest_src, "mem_forest.bin")?;
+        // Check the snapshot before touching the working files, so a corrupt one can't
+        // replace good state
+        validate_snapshot(&forest_src, &pollard_src)?;
+
+        std::fs::copy(&forest_src, data.join("mem_forest.bin"))?;
+        // The local delta log and its snapshot's sidecar belong to the old snapshot; replace
+        // them with the restored ones
+        copy_or_remove(&dir.join(DELTA_FILE), &data.join(DELTA_FILE))?;
+        copy_or_remove(&forest_meta(&dir), &forest_meta(data))?;
         if pollard_src.exists() {
-            let _ = std::fs::copy(&pollard_src, "pollard.bin");
+            let _ = std::fs::copy(&pollard_src, data.join("pollard.bin"));
         }
 
         let bh = dir.join("block_hashes.bin");
         if bh.exists() {
-            let _ = std::fs::copy(bh, "block_hashes.bin");
+            let _ = std::fs::copy(bh, data.join("block_hashes.bin"));
+        }
+
+        let sync = dir.join(SYNC_STATE_FILE);
+        if sync.exists() {
+            let _ = std::fs::copy(sync, data.join(SYNC_STATE_FILE));
+        }
+        Ok(())
+    }
+
+    /// The `mem_forest.bin` sidecar in `dir` (see [`delta::meta_path`]).
+    fn forest_meta(dir: &Path) -> PathBuf {
+        delta::meta_path(&dir.join("mem_forest.bin"))
+    }
+
+    /// Copy `src` to `dst`, or remove a stale `dst` if there is no `src`.
+    fn copy_or_remove(src: &Path, dst: &Path) -> std::io::Result<()> {
+        if src.exists() {
+            std::fs::copy(src, dst)?;
+        } else if dst.exists() {
+            std::fs::remove_file(dst)?;
         }
         Ok(())
     }
 
-    pub async fn perform_dump(dir: PathBuf) -> std::io::Result<()> {
-        tokio::task::spawn_blocking(move || dump_sync(dir)).await?
+    /// Check that `forest` is a consistent MemForest and `pollard`, if present and non-empty,
+    /// a readable Pollard.
+    fn validate_snapshot(forest: &Path, pollard: &Path) -> std::io::Result<()> {
+        let bytes = std::fs::read(forest)?;
+        forest::deserialize_verified(&bytes).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid mem_forest.bin in snapshot: {e:#}"),
+            )
+        })?;
+        let bytes = match std::fs::read(pollard) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if !bytes.is_empty() {
+            pollard::decode(&bytes).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid pollard.bin in snapshot: {e:#}"),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Write `snapshot`, a `pollard.bin`, into the data directory `data` that has no forest
+    /// yet, and the sync state from its envelope.
+    pub fn import_sync(data: &Path, snapshot: &[u8]) -> ImportOutcome {
+        if data.join("mem_forest.bin").exists() {
+            return Err(ImportError::HasForest);
+        }
+        let (_, meta) =
+            pollard::decode(snapshot).map_err(|e| ImportError::Invalid(format!("{e:#}")))?;
+        pollard::save(data, snapshot).map_err(ImportError::Storage)?;
+        if let Some(meta) = meta {
+            sync_state::write(
+                data,
+                &SyncState {
+                    height: Some(meta.block_height),
+                    block_hash: meta.block_hash,
+                    keep_op_return: meta.keep_op_return.unwrap_or(false),
+                    unresolved_prevouts: 0,
+                },
+            )
+            .map_err(ImportError::Storage)?;
+        }
+        Ok(())
     }
 
-    pub async fn perform_restore(dir: PathBuf) -> std::io::Result<()> {
-        tokio::task::spawn_blocking(move || restore_sync(dir)).await?
+    pub async fn perform_dump(data: PathBuf, dir: PathBuf) -> std::io::Result<()> {
+        tokio::task::spawn_blocking(move || dump_sync(&data, &dir)).await?
     }
 }
@@ -0,0 +1,51 @@
+//! Records which block the on-disk forest corresponds to, so callers know what to `/update` next.
+use anyhow::{Context, Result};
+use bitcoin::BlockHash;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Written next to `mem_forest.bin` by every successful build or update.
+pub const SYNC_STATE_FILE: &str = "sync_state.json";
+
+/// The block the forest is synced to. Either field is `None` when it could not be determined,
+/// e.g. an update run without Bitcoin Core RPC has no block hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncState {
+    pub height: Option<u64>,
+    pub block_hash: Option<BlockHash>,
+    /// Whether the forest was built with OP_RETURN outputs as leaves (see
+    /// [`utreexo::is_excluded`]); every update on top of it follows the same policy. Left out
+    /// of the file when unset.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keep_op_return: bool,
+    /// Spent prevouts the node couldn't return and updates skipped under
+    /// [`MissingPrevout::Skip`], since the build. Their leaves are still in the forest. Left out
+    /// of the file when zero.
+    ///
+    /// [`MissingPrevout::Skip`]: crate::script_utils::btc_rpc::MissingPrevout::Skip
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub unresolved_prevouts: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Read the sync state from the data directory `dir`, or `None` if nothing was built yet.
+pub fn read(dir: &Path) -> Result<Option<SyncState>> {
+    let path = dir.join(SYNC_STATE_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read(&path).with_context(|| format!("failed to read {path:?}"))?;
+    let state =
+        serde_json::from_slice(&data).with_context(|| format!("failed to parse {path:?}"))?;
+    Ok(Some(state))
+}
+
+/// Overwrite the sync state in the data directory `dir`.
+pub fn write(dir: &Path, state: &SyncState) -> Result<()> {
+    let path = dir.join(SYNC_STATE_FILE);
+    std::fs::write(&path, serde_json::to_vec(state)?)
+        .with_context(|| format!("failed to write {path:?}"))
+}
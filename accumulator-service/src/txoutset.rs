@@ -0,0 +1,477 @@
+//! Reader for the UTXO snapshots Bitcoin Core writes with `dumptxoutset`.
+//!
+//! Core 28 and later start a snapshot with the magic `utxo\xff`, a format version, the
+//! network magic, the hash of the block the snapshot was taken at and the number of coins.
+//! Coins follow grouped by txid: the txid, the number of coins in the group, then each coin's
+//! vout and the coin itself. Older versions have no magic or version and write every coin as a
+//! full outpoint (txid and a 4-byte vout) followed by the coin.
+//!
+//! A coin is Core's compact serialization: a VARINT of `height * 2 + coinbase`, the
+//! compressed amount and the compressed script (see [`Dump`]).
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf, TxOut, Txid};
+use std::io::{self, Read};
+
+/// Bytes a snapshot with a header starts with.
+pub const MAGIC: [u8; 5] = *b"utxo\xff";
+
+/// The only format version after the magic this reader understands.
+pub const VERSION: u16 = 2;
+
+/// Core's `MAX_SCRIPT_SIZE`; longer scripts are not stored, only skipped.
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+/// Number of special script encodings, which raw script sizes are offset by.
+const SPECIAL_SCRIPTS: u64 = 6;
+
+/// What a snapshot says about itself before its coins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Network magic, `None` for a snapshot without the `utxo\xff` header.
+    pub network_magic: Option<[u8; 4]>,
+    /// Block the snapshot was taken at.
+    pub base_block: BlockHash,
+    /// Number of coins that follow.
+    pub coins: u64,
+}
+
+/// One unspent output as recorded in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub outpoint: OutPoint,
+    /// Height of the block that created the output.
+    pub height: u32,
+    pub coinbase: bool,
+    pub txout: TxOut,
+}
+
+/// Streams the coins of a snapshot, in file order.
+///
+/// Reading stops after the number of coins the header announces; the first error ends the
+/// iteration too.
+pub struct Dump<R> {
+    reader: R,
+    header: Header,
+    /// Whether coins are grouped by txid, as in snapshots with a header.
+    grouped: bool,
+    /// Coins read so far.
+    read: u64,
+    /// Txid of the current group and how many of its coins are left.
+    group: Option<(Txid, u64)>,
+}
+
+impl<R: Read> Dump<R> {
+    /// Read the snapshot's header, leaving `reader` at its first coin.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut start = [0u8; 5];
+        reader.read_exact(&mut start)?;
+        let mut block = [0u8; 32];
+        let (network_magic, grouped) = if start == MAGIC {
+            let version = u16::from_le_bytes(read_array(&mut reader)?);
+            if version != VERSION {
+                return Err(invalid(format!(
+                    "unsupported snapshot version {version} (expected {VERSION})"
+                )));
+            }
+            let network_magic = read_array(&mut reader)?;
+            reader.read_exact(&mut block)?;
+            (Some(network_magic), true)
+        } else {
+            // no header: the bytes read are the start of the block hash
+            block[..5].copy_from_slice(&start);
+            reader.read_exact(&mut block[5..])?;
+            (None, false)
+        };
+        let coins = u64::from_le_bytes(read_array(&mut reader)?);
+        Ok(Self {
+            reader,
+            header: Header {
+                network_magic,
+                base_block: BlockHash::from_byte_array(block),
+                coins,
+            },
+            grouped,
+            read: 0,
+            group: None,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn read_coin(&mut self) -> io::Result<Coin> {
+        let outpoint = if self.grouped {
+            let (txid, left) = match self.group {
+                Some((txid, left)) if left > 0 => (txid, left),
+                _ => {
+                    let txid = Txid::from_byte_array(read_array(&mut self.reader)?);
+                    let count = read_compact_size(&mut self.reader)?;
+                    if count == 0 {
+                        return Err(invalid(format!("txid {txid} has no coins")));
+                    }
+                    (txid, count)
+                }
+            };
+            self.group = Some((txid, left - 1));
+            let vout = read_compact_size(&mut self.reader)?;
+            let vout = u32::try_from(vout)
+                .map_err(|_| invalid(format!("vout {vout} of {txid} is out of range")))?;
+            OutPoint { txid, vout }
+        } else {
+            let txid = Txid::from_byte_array(read_array(&mut self.reader)?);
+            let vout = u32::from_le_bytes(read_array(&mut self.reader)?);
+            OutPoint { txid, vout }
+        };
+
+        let code = read_varint(&mut self.reader)?;
+        let height = u32::try_from(code >> 1)
+            .map_err(|_| invalid(format!("height of {outpoint} is out of range")))?;
+        let value = Amount::from_sat(decompress_amount(read_varint(&mut self.reader)?));
+        let script_pubkey = read_script(&mut self.reader)?;
+        Ok(Coin {
+            outpoint,
+            height,
+            coinbase: code & 1 == 1,
+            txout: TxOut {
+                value,
+                script_pubkey,
+            },
+        })
+    }
+}
+
+impl<R: Read> Iterator for Dump<R> {
+    type Item = io::Result<Coin>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read == self.header.coins {
+            return None;
+        }
+        let coin = self.read_coin();
+        self.read = match coin {
+            Ok(_) => self.read + 1,
+            Err(_) => self.header.coins,
+        };
+        Some(coin)
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Bitcoin's CompactSize, as used for counts in the P2P protocol.
+fn read_compact_size(reader: &mut impl Read) -> io::Result<u64> {
+    Ok(match read_array::<1>(reader)?[0] {
+        0xfd => u16::from_le_bytes(read_array(reader)?).into(),
+        0xfe => u32::from_le_bytes(read_array(reader)?).into(),
+        0xff => u64::from_le_bytes(read_array(reader)?),
+        n => n.into(),
+    })
+}
+
+/// Core's VARINT: big-endian base-128 where each continuation byte adds one, so every value
+/// has a single encoding.
+fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut n = 0u64;
+    loop {
+        let byte = read_array::<1>(reader)?[0];
+        if n > u64::MAX >> 7 {
+            return Err(invalid("VARINT is too large".into()));
+        }
+        n = (n << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n
+            .checked_add(1)
+            .ok_or_else(|| invalid("VARINT is too large".into()))?;
+    }
+}
+
+/// Inverse of Core's `CompressAmount`, which drops trailing decimal zeros.
+fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let exponent = x % 10;
+    x /= 10;
+    let mut n = if exponent < 9 {
+        let digit = x % 9 + 1;
+        x /= 9;
+        x * 10 + digit
+    } else {
+        x + 1
+    };
+    // wraps like Core's uint64_t arithmetic on amounts no real coin has
+    for _ in 0..exponent {
+        n = n.wrapping_mul(10);
+    }
+    n
+}
+
+/// Core's `ScriptCompression`: sizes below 6 stand for standard scripts stored as just their
+/// hash or key, anything else is a raw script of `size - 6` bytes.
+fn read_script(reader: &mut impl Read) -> io::Result<ScriptBuf> {
+    let size = read_varint(reader)?;
+    let script = match size {
+        // OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+        0 => [
+            &[0x76, 0xa9, 20][..],
+            &read_array::<20>(reader)?,
+            &[0x88, 0xac],
+        ]
+        .concat(),
+        // OP_HASH160 <20 bytes> OP_EQUAL
+        1 => [&[0xa9, 20][..], &read_array::<20>(reader)?, &[0x87]].concat(),
+        // <compressed pubkey> OP_CHECKSIG
+        2 | 3 => [&[33, size as u8][..], &read_array::<32>(reader)?, &[0xac]].concat(),
+        // <uncompressed pubkey> OP_CHECKSIG, stored compressed
+        4 | 5 => {
+            let x = read_array::<32>(reader)?;
+            let compressed = [&[size as u8 - 2][..], &x].concat();
+            match PublicKey::from_slice(&compressed) {
+                Ok(key) => [&[65][..], &key.serialize_uncompressed(), &[0xac]].concat(),
+                // Core stores an x off the curve the same way and reads it back as no script
+                Err(_) => Vec::new(),
+            }
+        }
+        _ => {
+            let len = size - SPECIAL_SCRIPTS;
+            if len > MAX_SCRIPT_SIZE {
+                // Core keeps oversized scripts as a bare OP_RETURN: they are unspendable
+                let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+                if skipped != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                vec![0x6a]
+            } else {
+                let mut script = vec![0u8; len as usize];
+                reader.read_exact(&mut script)?;
+                script
+            }
+        }
+    };
+    Ok(ScriptBuf::from_bytes(script))
+}
+
+/// Writers for test snapshots; scripts are always stored raw.
+#[cfg(test)]
+pub(crate) mod encode {
+    use super::*;
+
+    pub(crate) fn varint(mut n: u64, out: &mut Vec<u8>) {
+        let mut bytes = vec![(n & 0x7f) as u8];
+        while n > 0x7f {
+            n = (n >> 7) - 1;
+            bytes.push((n & 0x7f) as u8 | 0x80);
+        }
+        out.extend(bytes.iter().rev());
+    }
+
+    pub(crate) fn compress_amount(mut n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        let mut exponent = 0;
+        while n.is_multiple_of(10) && exponent < 9 {
+            n /= 10;
+            exponent += 1;
+        }
+        if exponent < 9 {
+            let digit = n % 10;
+            n /= 10;
+            1 + (n * 9 + digit - 1) * 10 + exponent
+        } else {
+            1 + (n - 1) * 10 + 9
+        }
+    }
+
+    /// A coin without its outpoint.
+    pub(crate) fn coin(coin: &Coin, out: &mut Vec<u8>) {
+        varint(u64::from(coin.height) * 2 + u64::from(coin.coinbase), out);
+        varint(compress_amount(coin.txout.value.to_sat()), out);
+        let script = coin.txout.script_pubkey.as_bytes();
+        varint(script.len() as u64 + SPECIAL_SCRIPTS, out);
+        out.extend_from_slice(script);
+    }
+
+    /// A snapshot with a header, one group per run of coins sharing a txid.
+    pub(crate) fn snapshot(base_block: BlockHash, coins: &[Coin]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_le_bytes());
+        out.extend([0xf9, 0xbe, 0xb4, 0xd9]);
+        out.extend(base_block.to_byte_array());
+        out.extend((coins.len() as u64).to_le_bytes());
+        for group in coins.chunk_by(|a, b| a.outpoint.txid == b.outpoint.txid) {
+            out.extend(group[0].outpoint.txid.to_byte_array());
+            // counts and vouts here stay below 0xfd, where a CompactSize is one byte
+            out.push(group.len() as u8);
+            for c in group {
+                out.push(c.outpoint.vout as u8);
+                coin(c, &mut out);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode;
+    use super::*;
+    use bitcoin::hex::FromHex;
+
+    fn coin(txid: u8, vout: u32, height: u32, sats: u64, script: &[u8]) -> Coin {
+        Coin {
+            outpoint: OutPoint {
+                txid: Txid::from_byte_array([txid; 32]),
+                vout,
+            },
+            height,
+            coinbase: height == 0,
+            txout: TxOut {
+                value: Amount::from_sat(sats),
+                script_pubkey: ScriptBuf::from_bytes(script.to_vec()),
+            },
+        }
+    }
+
+    #[test]
+    fn reads_back_a_snapshot() {
+        let coins = [
+            coin(1, 0, 0, 5_000_000_000, &[0x51]),
+            coin(1, 3, 0, 0, &[0x6a, 0x01, 0x02]),
+            coin(2, 300, 840_000, 123_456_789, &[0x00, 0x14, 0xab]),
+        ];
+        let block = BlockHash::from_byte_array([7; 32]);
+        let bytes = encode::snapshot(block, &coins[..2]);
+        let dump = Dump::new(&bytes[..]).unwrap();
+        assert_eq!(
+            *dump.header(),
+            Header {
+                network_magic: Some([0xf9, 0xbe, 0xb4, 0xd9]),
+                base_block: block,
+                coins: 2,
+            }
+        );
+        let read: Vec<Coin> = dump.collect::<io::Result<_>>().unwrap();
+        assert_eq!(read, coins[..2]);
+
+        // a vout of 300 takes a three-byte CompactSize
+        let mut bytes = encode::snapshot(block, &[]);
+        bytes[43..51].copy_from_slice(&1u64.to_le_bytes());
+        bytes.extend([2; 32]);
+        bytes.extend([1, 0xfd, 0x2c, 0x01]);
+        encode::coin(&coins[2], &mut bytes);
+        let read: Vec<Coin> = Dump::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read, coins[2..]);
+    }
+
+    #[test]
+    fn reads_a_snapshot_without_header() {
+        let coins = [coin(4, 1, 10, 1_000, &[0x51]), coin(5, 70_000, 11, 1, &[])];
+        let mut bytes = [9u8; 32].to_vec();
+        bytes.extend(2u64.to_le_bytes());
+        for c in &coins {
+            bytes.extend(c.outpoint.txid.to_byte_array());
+            bytes.extend(c.outpoint.vout.to_le_bytes());
+            encode::coin(c, &mut bytes);
+        }
+        let dump = Dump::new(&bytes[..]).unwrap();
+        assert_eq!(dump.header().network_magic, None);
+        assert_eq!(
+            dump.header().base_block,
+            BlockHash::from_byte_array([9; 32])
+        );
+        let read: Vec<Coin> = dump.collect::<io::Result<_>>().unwrap();
+        assert_eq!(read, coins);
+    }
+
+    #[test]
+    fn decompresses_special_scripts() {
+        let script = |bytes: &[u8]| read_script(&mut &bytes[..]).unwrap().to_bytes();
+        let hash = [0x11; 20];
+
+        let p2pkh = script(&[&[0][..], &hash].concat());
+        assert_eq!(
+            p2pkh,
+            [&[0x76, 0xa9, 20][..], &hash, &[0x88, 0xac]].concat()
+        );
+        let p2sh = script(&[&[1][..], &hash].concat());
+        assert_eq!(p2sh, [&[0xa9, 20][..], &hash, &[0x87]].concat());
+        let p2pk = script(&[&[3][..], &[0x22; 32]].concat());
+        assert_eq!(p2pk, [&[33, 3][..], &[0x22; 32], &[0xac]].concat());
+
+        // the generator point, whose y is even
+        let x = <[u8; 32]>::from_hex(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let y = <[u8; 32]>::from_hex(
+            "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8",
+        )
+        .unwrap();
+        let uncompressed = script(&[&[4][..], &x].concat());
+        assert_eq!(
+            uncompressed,
+            [&[65, 4][..], &x, &y, &[0xac]].concat(),
+            "uncompressed key"
+        );
+        // x = 5 is not on the curve
+        let mut off_curve = [0u8; 32];
+        off_curve[31] = 5;
+        assert_eq!(script(&[&[4][..], &off_curve].concat()), Vec::<u8>::new());
+
+        let mut long = Vec::new();
+        encode::varint(MAX_SCRIPT_SIZE + 1 + SPECIAL_SCRIPTS, &mut long);
+        long.extend(vec![0x51; MAX_SCRIPT_SIZE as usize + 1]);
+        assert_eq!(script(&long), [0x6a]);
+    }
+
+    #[test]
+    fn amounts_and_varints_round_trip() {
+        for sats in [
+            0,
+            1,
+            9,
+            10,
+            50,
+            100,
+            123_456_789,
+            5_000_000_000,
+            21_000_000 * 100_000_000,
+        ] {
+            assert_eq!(decompress_amount(encode::compress_amount(sats)), sats);
+        }
+        for n in [0, 1, 0x7f, 0x80, 0x407f, 0x4080, u32::MAX.into(), u64::MAX] {
+            let mut bytes = Vec::new();
+            encode::varint(n, &mut bytes);
+            assert_eq!(read_varint(&mut &bytes[..]).unwrap(), n);
+        }
+        // every continuation adds one, so 0x80 0x00 is 128 rather than 0
+        assert_eq!(read_varint(&mut &[0x80, 0x00][..]).unwrap(), 0x80);
+    }
+
+    #[test]
+    fn stops_at_truncated_coin() {
+        let coins = [coin(1, 0, 1, 1, &[0x51]), coin(2, 0, 1, 1, &[0x51])];
+        let bytes = encode::snapshot(BlockHash::all_zeros(), &coins);
+        let mut dump = Dump::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(dump.next().unwrap().is_ok());
+        assert!(dump.next().unwrap().is_err());
+        assert!(dump.next().is_none());
+    }
+}
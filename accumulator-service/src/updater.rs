@@ -1,83 +1,381 @@
-//! Updater logic: fetch spent UTXO leaf hashes from a block via RPC and apply deletions to the MemForest snapshot.
-use crate::script_utils::btc_rpc::{get_block_leaf_hashes, BitcoinRpc};
-use anyhow::{anyhow, Context, Result};
-use bitcoincore_rpc::{Auth, Client, RpcApi};
+//! Updater logic: fetch a block and the leaf hashes of the UTXOs it spends via RPC and apply it
+//! to the MemForest snapshot, or apply a whole block submitted by the caller.
+use crate::delta::{self, DeltaRecord, DELTA_FILE, SNAPSHOT_INTERVAL};
+use crate::pollard;
+use crate::rpc::CoreRpcClient;
+use crate::script_utils::btc_rpc::{
+    block_changes, block_input_leaves, BitcoinRpc, BlockLeafHashes, MissingPrevout,
+};
+use crate::script_utils::pollard_conv::prune_to;
+use crate::sync_state::{self, SyncState};
+use crate::verify;
+use anyhow::{anyhow, ensure, Context, Result};
+use bitcoin::{Block, OutPoint, TxIn};
+use log::{info, warn};
 use rustreexo::accumulator::mem_forest::MemForest;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use rustreexo::accumulator::proof::Proof;
+use rustreexo::accumulator::stump::Stump;
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
+use std::fmt;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+use utreexo::{process_block_changes, ProcessOptions};
 
-/// RPC wrapper for the BitcoinRpc trait using bitcoincore_rpc::Client.
-struct RpcClient(Client);
-impl BitcoinRpc for RpcClient {
-    fn get_block_hash(&self, height: u64) -> Result<bitcoin::BlockHash> {
-        Ok(self.0.get_block_hash(height)?)
+/// Environment variable choosing what [`update_block`] does with a spent prevout Bitcoin Core
+/// can't return: `fail` (the default), `skip` or `retry:N` (see [`MissingPrevout`]).
+pub const MISSING_PREVOUT_ENV: &str = "ACC_SERVICE_MISSING_PREVOUT";
+
+/// Update the accumulator in the data directory `dir` with block `height`: add the leaves of its
+/// outputs and delete the leaves of the UTXOs it spends, as [`apply_block`] does.
+///
+/// A height at or below the one in `sync_state.json` has already been applied, so it is
+/// skipped and reported as success; a retried request must not delete the same leaves twice.
+/// Any other height must be the next one after it.
+///
+/// `cancel` is checked between the per-transaction RPC fetches and again before anything is
+/// written; a cancelled update returns `Ok` and leaves the files as they were.
+///
+/// A prevout the node can't return fails the update unless [`MISSING_PREVOUT_ENV`] says
+/// otherwise. Prevouts skipped under `skip` are counted in the sync state.
+pub async fn update_block(dir: &Path, height: u64, cancel: CancellationToken) -> Result<()> {
+    // Fetch the block over Bitcoin RPC if it is configured; a node that is configured but
+    // can't be reached fails the update rather than applying nothing
+    let rpc = if let (Ok(rpc_url), Ok(cookie)) = (
+        env::var("BITCOIN_CORE_RPC_URL"),
+        env::var("BITCOIN_CORE_COOKIE_FILE"),
+    ) {
+        Some(CoreRpcClient::new(&rpc_url, &cookie)?)
+    } else {
+        None
+    };
+    // Skipping a prevout leaves its leaf in the forest for good, so only do it when asked to
+    let policy = match env::var(MISSING_PREVOUT_ENV) {
+        Ok(policy) => policy
+            .parse()
+            .with_context(|| format!("invalid {MISSING_PREVOUT_ENV}"))?,
+        Err(_) => MissingPrevout::Fail,
+    };
+    let unresolved = update_block_with(dir, rpc.as_ref(), height, policy, &cancel)?;
+    if !unresolved.is_empty() {
+        warn!(
+            "block {height}: {} spent prevouts could not be fetched and were not deleted: {:?}",
+            unresolved.len(),
+            unresolved
+        );
+    }
+    Ok(())
+}
+
+/// [`update_block`] against the given node; without one, nothing is applied. `policy` decides
+/// what happens to a prevout the node can't return. Returns the prevouts that were skipped
+/// under [`MissingPrevout::Skip`], whose leaves are still in the forest; their number is added
+/// to the sync state's `unresolved_prevouts`.
+pub fn update_block_with<R: BitcoinRpc>(
+    dir: &Path,
+    rpc: Option<&R>,
+    height: u64,
+    policy: MissingPrevout,
+    cancel: &CancellationToken,
+) -> Result<Vec<OutPoint>> {
+    let synced = sync_state::read(dir)?;
+    if let Some(synced) = synced.as_ref().and_then(|state| state.height) {
+        if height <= synced {
+            info!("block {height} already applied (synced to {synced}), skipping");
+            return Ok(Vec::new());
+        }
+        ensure!(
+            height == synced + 1,
+            "forest is synced to {synced}, block {height} can't be applied before {}",
+            synced + 1
+        );
     }
-    fn get_block(&self, hash: &bitcoin::BlockHash) -> Result<bitcoin::Block> {
-        Ok(self.0.get_block(hash)?)
+    // Follow the leaf policy the forest was built with
+    let keep_op_return = synced.as_ref().is_some_and(|state| state.keep_op_return);
+    let unresolved_before = synced.map_or(0, |state| state.unresolved_prevouts);
+    let fetched = match rpc {
+        Some(rpc) => fetch_block(rpc, height, policy, keep_op_return, cancel).map(Some),
+        None => Ok(None),
+    };
+    if cancel.is_cancelled() {
+        info!("update of block {height} cancelled, nothing written");
+        return Ok(Vec::new());
     }
-    fn get_txout(&self, prevout: &bitcoin::OutPoint) -> Result<(u64, Vec<u8>)> {
-        let info = self.0.get_raw_transaction_info(&prevout.txid, None)?;
-        let out = info
-            .vout
-            .into_iter()
-            .find(|v| v.n == prevout.vout)
-            .context("vout not found")?;
-        Ok((out.value.to_sat(), hex::decode(out.script_pub_key.hex)?))
+    // A block whose spent leaves couldn't all be fetched must not advance the sync height, or
+    // a retry would skip it and its leaves would never be deleted
+    let fetched =
+        fetched.with_context(|| format!("failed to fetch the leaves spent in block {height}"))?;
+    let header_height = u32::try_from(height)
+        .map_err(|_| anyhow!("height {height} is too large for a header code"))?;
+    let (adds, deletes, unresolved, block_hash) = match fetched {
+        Some((block, leaves)) => {
+            let (adds, deletes) =
+                block_changes(&block, header_height, leaves.hashes, keep_op_return)?;
+            (adds, deletes, leaves.unresolved, Some(block.block_hash()))
+        }
+        None => Default::default(),
+    };
+    let state = SyncState {
+        height: Some(height),
+        block_hash,
+        keep_op_return,
+        unresolved_prevouts: unresolved_before + unresolved.len() as u64,
+    };
+    // Load the last snapshot with the delta log replayed on top
+    let (mut forest, contained) =
+        delta::load_forest_at(&dir.join("mem_forest.bin"), &dir.join(DELTA_FILE))?;
+    if contained.is_some_and(|contained| contained >= height) {
+        info!("block {height} already in the forest, catching up the sync state");
+        publish(dir, &forest, height, &state)?;
+        return Ok(unresolved);
     }
-    fn get_block_height(&self, hash: &bitcoin::BlockHash) -> Result<u32> {
-        let hdr = self.0.get_block_header_info(hash)?;
-        Ok(hdr.height as u32)
+
+    forest
+        .modify(&adds, &deletes)
+        .map_err(|e| anyhow!("failed to apply block {height} to MemForest: {}", e))?;
+    if cancel.is_cancelled() {
+        info!("update of block {height} cancelled, nothing written");
+        return Ok(Vec::new());
     }
+
+    let record = DeltaRecord {
+        height,
+        adds,
+        dels: deletes,
+    };
+    persist(dir, &forest, &record, &state)?;
+    Ok(unresolved)
 }
 
-/// Update the accumulator by deleting all spent UTXO leaves in block `height`.
-pub async fn update_block(height: u64) -> Result<()> {
-    // Determine delete list: try Bitcoin RPC if env vars set, else default to empty
-    let deletes = if let (Ok(rpc_url), Ok(cookie)) = (
-        env::var("BITCOIN_CORE_RPC_URL"),
-        env::var("BITCOIN_CORE_COOKIE_FILE"),
-    ) {
-        if let Ok(client) = Client::new(&rpc_url, Auth::CookieFile(cookie.into())) {
-            let rpc = RpcClient(client);
-            get_block_leaf_hashes(&rpc, height).unwrap_or_default()
-        } else {
-            Vec::new()
+/// Fetch block `height` and the leaf hashes of the UTXOs it spends.
+fn fetch_block<R: BitcoinRpc>(
+    rpc: &R,
+    height: u64,
+    policy: MissingPrevout,
+    keep_op_return: bool,
+    cancel: &CancellationToken,
+) -> Result<(Block, BlockLeafHashes)> {
+    let block = rpc.get_block(&rpc.get_block_hash(height)?)?;
+    let leaves = block_input_leaves(rpc, &block, policy, keep_op_return, cancel)?;
+    Ok((block, leaves))
+}
+
+/// Write out `forest`, which `record` was just applied to: log the block (rewriting the full
+/// snapshot only every [`SNAPSHOT_INTERVAL`] blocks), then [`publish`] it.
+///
+/// A crash after the log is committed leaves the sync state a block behind the forest. The
+/// retry finds the block in the forest (see [`delta::load_forest_at`]) and only publishes it.
+fn persist(
+    dir: &Path,
+    forest: &MemForest<BitcoinNodeHash>,
+    record: &DeltaRecord,
+    state: &SyncState,
+) -> Result<()> {
+    let snapshot = dir.join("mem_forest.bin");
+    let delta_log = dir.join(DELTA_FILE);
+    delta::commit(forest, record, &snapshot, &delta_log, SNAPSHOT_INTERVAL)?;
+    publish(dir, forest, record.height, state)
+}
+
+/// Regenerate `pollard.bin` from `forest`, the state after block `height`, and move
+/// `sync_state.json` to `state`, the block's.
+fn publish(
+    dir: &Path,
+    forest: &MemForest<BitcoinNodeHash>,
+    height: u64,
+    state: &SyncState,
+) -> Result<()> {
+    // Generate a fresh pruned Pollard from the in-memory forest, since the snapshot on disk
+    // may lag behind the log
+    let pollard = prune_to(forest, &[]).context("failed to prune forest to Pollard")?;
+    let bytes =
+        pollard::encode_with_meta(&pollard, height, state.block_hash, state.keep_op_return)?;
+    pollard::save(dir, &bytes)?;
+    sync_state::write(dir, state)
+}
+
+/// Why [`apply_block`] didn't apply a block. Nothing is written in any case.
+#[derive(Debug)]
+pub enum ApplyBlockError {
+    /// The block doesn't extend the block the forest is synced to.
+    NotOnTip(String),
+    /// The block doesn't fit the forest, e.g. an input's leaf hash is missing or not a leaf.
+    Invalid(String),
+    /// The data directory couldn't be read or written.
+    Storage(anyhow::Error),
+}
+
+impl fmt::Display for ApplyBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyBlockError::NotOnTip(msg) => write!(f, "block does not extend the tip: {msg}"),
+            ApplyBlockError::Invalid(msg) => write!(f, "invalid block: {msg}"),
+            ApplyBlockError::Storage(e) => write!(f, "{e:#}"),
         }
-    } else {
-        Vec::new()
+    }
+}
+
+impl std::error::Error for ApplyBlockError {}
+
+/// Apply a block supplied by the caller instead of fetched over RPC: add its spendable outputs
+/// and delete the leaves its inputs spend, exactly as the zkVM program does with
+/// [`process_block_changes`]. Returns the forest's leaf count and roots afterwards.
+///
+/// The forest has no prevouts to hash, so `input_leaves` must hold the leaf hash of every
+/// non-coinbase input, in block order. `height` must be the one after the synced height and
+/// the block's `prev_blockhash` the synced block hash, when that is known. OP_RETURN outputs
+/// are kept if the forest was built that way.
+pub fn apply_block(
+    dir: &Path,
+    height: u64,
+    block: &Block,
+    input_leaves: &[BitcoinNodeHash],
+) -> Result<Stump, ApplyBlockError> {
+    let tip = sync_state::read(dir)
+        .map_err(ApplyBlockError::Storage)?
+        .ok_or_else(|| ApplyBlockError::NotOnTip("nothing has been built yet".into()))?;
+    if let Some(synced) = tip.height {
+        if height != synced + 1 {
+            return Err(ApplyBlockError::NotOnTip(format!(
+                "forest is synced to {synced}, got block {height}"
+            )));
+        }
+    }
+    if let Some(tip_hash) = tip.block_hash {
+        if block.header.prev_blockhash != tip_hash {
+            return Err(ApplyBlockError::NotOnTip(format!(
+                "block builds on {}, forest is synced to {tip_hash}",
+                block.header.prev_blockhash
+            )));
+        }
+    }
+    let header_height = u32::try_from(height).map_err(|_| {
+        ApplyBlockError::Invalid(format!("height {height} is too large for a header code"))
+    })?;
+
+    let inputs: Vec<&TxIn> = block
+        .txdata
+        .iter()
+        .filter(|tx| !tx.is_coinbase())
+        .flat_map(|tx| &tx.input)
+        .collect();
+    if inputs.len() != input_leaves.len() {
+        return Err(ApplyBlockError::Invalid(format!(
+            "block has {} non-coinbase inputs, got {} leaf hashes",
+            inputs.len(),
+            input_leaves.len()
+        )));
+    }
+    let input_leaf_hashes: BTreeMap<TxIn, BitcoinNodeHash> = inputs
+        .into_iter()
+        .cloned()
+        .zip(input_leaves.iter().copied())
+        .collect();
+
+    let options = ProcessOptions {
+        keep_op_return: tip.keep_op_return,
+        ..Default::default()
+    };
+    let state = SyncState {
+        height: Some(height),
+        block_hash: Some(block.block_hash()),
+        ..tip
     };
-    // Load existing MemForest snapshot
-    let mut f = File::open("mem_forest.bin").context("failed to open mem_forest.bin")?;
-    let mut forest = MemForest::<BitcoinNodeHash>::deserialize(&mut f)
-        .context("failed to deserialize MemForest")?;
+    let (mut forest, contained) =
+        delta::load_forest_at(&dir.join("mem_forest.bin"), &dir.join(DELTA_FILE))
+            .map_err(ApplyBlockError::Storage)?;
+    if contained.is_some_and(|contained| contained >= height) {
+        info!("block {height} already in the forest, catching up the sync state");
+        publish(dir, &forest, height, &state).map_err(ApplyBlockError::Storage)?;
+        return Ok(stump_of(&forest));
+    }
+    let changes = process_block_changes(
+        block,
+        header_height,
+        &mut forest,
+        input_leaf_hashes,
+        options,
+    )
+    .map_err(|e| ApplyBlockError::Invalid(e.to_string()))?;
 
-    // Apply deletions
-    forest
-        .modify(&[], &deletes)
-        .map_err(|e| anyhow!("failed to delete leaves in MemForest: {}", e))?;
+    let record = DeltaRecord {
+        height,
+        adds: changes.added,
+        dels: changes.deleted,
+    };
+    persist(dir, &forest, &record, &state).map_err(ApplyBlockError::Storage)?;
+    Ok(stump_of(&forest))
+}
 
-    // Serialize updated forest
-    let mut out =
-        File::create("mem_forest.bin").context("failed to open mem_forest.bin for write")?;
-    forest
-        .serialize(&mut out)
-        .context("failed to serialize MemForest")?;
-    // After updating the forest, generate a fresh pruned Pollard and write pollard.bin
-    // offload pruning to blocking thread since Pollard sync conversion is not Send-safe
-    tokio::task::spawn_blocking(|| crate::pollard::prune_forest_sync("mem_forest.bin", ""))
-        .await
-        .context("prune_forest task join failed")?
-        .context("failed to prune forest to Pollard")?;
+fn stump_of(forest: &MemForest<BitcoinNodeHash>) -> Stump {
+    Stump {
+        leaves: forest.leaves,
+        roots: forest.get_roots().iter().map(|r| r.get_data()).collect(),
+    }
+}
+
+/// Apply a block's changes to roots-only state: add `adds` and delete `dels`, which `proof`
+/// must prove against `stump`'s current roots. This needs neither the forest nor a Pollard,
+/// only the block's batch proof. `stump` is left unchanged if the proof doesn't verify.
+pub fn update_stump(
+    stump: &mut Stump,
+    proof: &Proof<BitcoinNodeHash>,
+    adds: &[BitcoinNodeHash],
+    dels: &[BitcoinNodeHash],
+) -> Result<()> {
+    verify::verify_deletions(stump, proof, dels)?;
+    let (updated, _) = stump
+        .modify(adds, dels, proof)
+        .map_err(|e| anyhow!("failed to apply block to Stump: {e:?}"))?;
+    *stump = updated;
     Ok(())
 }
+
 /// Synchronous helper for `update_block`, suitable for blocking contexts.
-pub fn update_block_sync(height: u64) -> Result<()> {
+pub fn update_block_sync(dir: &Path, height: u64, cancel: CancellationToken) -> Result<()> {
     // Build a local runtime and execute the async update
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .context("failed to create runtime for update_block_sync")?;
-    rt.block_on(update_block(height))
+    rt.block_on(update_block(dir, height, cancel))
         .context("error running update_block")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(range: std::ops::Range<u8>) -> Vec<BitcoinNodeHash> {
+        range.map(|i| BitcoinNodeHash::new([i; 32])).collect()
+    }
+
+    #[test]
+    fn stump_update_matches_forest() {
+        let mut forest = MemForest::new();
+        forest.modify(&hashes(0..8), &[]).unwrap();
+        let mut stump = stump_of(&forest);
+
+        let adds = hashes(8..11);
+        let dels = vec![BitcoinNodeHash::new([2; 32]), BitcoinNodeHash::new([5; 32])];
+        let proof = forest.prove(&dels).unwrap();
+        update_stump(&mut stump, &proof, &adds, &dels).unwrap();
+        forest.modify(&adds, &dels).unwrap();
+        assert_eq!(stump, stump_of(&forest));
+    }
+
+    #[test]
+    fn stump_rejects_proof_for_other_roots() {
+        let mut other = MemForest::new();
+        other.modify(&hashes(1..9), &[]).unwrap();
+        let dels = vec![BitcoinNodeHash::new([2; 32])];
+        let proof = other.prove(&dels).unwrap();
+
+        let mut forest = MemForest::new();
+        forest.modify(&hashes(0..8), &[]).unwrap();
+        let mut stump = stump_of(&forest);
+        assert!(update_stump(&mut stump, &proof, &[], &dels).is_err());
+        assert_eq!(stump, stump_of(&forest));
+    }
+}
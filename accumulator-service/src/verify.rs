@@ -0,0 +1,326 @@
+//! Consistency checks between accumulator snapshots.
+use crate::delta::{self, DELTA_FILE};
+use crate::pollard;
+use anyhow::{anyhow, ensure, Context, Result};
+use bitcoin::CompactTarget;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use rustreexo::accumulator::pollard::{Pollard, PollardAddition};
+use rustreexo::accumulator::proof::Proof;
+use rustreexo::accumulator::stump::Stump;
+use serde::Serialize;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Indices at which two root lists differ. Indices past the end of the shorter list count as
+/// differing.
+pub fn diff_roots(a: &[BitcoinNodeHash], b: &[BitcoinNodeHash]) -> Vec<usize> {
+    (0..a.len().max(b.len()))
+        .filter(|&i| a.get(i) != b.get(i))
+        .collect()
+}
+
+/// Blocks between difficulty retargets.
+pub const RETARGET_INTERVAL: u64 = 2016;
+
+/// Network whose consensus rules decide how adjacent blocks' difficulty is compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+/// Outcome of comparing the difficulty targets of two adjacent blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DifficultyCheck {
+    /// The targets agree, or the network doesn't constrain them.
+    Consistent,
+    /// The targets differ, which the network allows but is worth noting.
+    Unusual,
+    /// The targets differ where the network forbids it.
+    Mismatch,
+}
+
+/// Compare the `bits` of the block at `height` with those of the block before it.
+///
+/// Away from a retarget boundary mainnet requires them to be equal. Testnet and testnet4 let a
+/// block drop to minimum difficulty after 20 minutes without one, and a signet may run its own
+/// rules, so a difference there is only [`DifficultyCheck::Unusual`]. Regtest isn't checked.
+pub fn check_difficulty(
+    network: Network,
+    height: u64,
+    prev_bits: CompactTarget,
+    bits: CompactTarget,
+) -> DifficultyCheck {
+    if network == Network::Regtest || prev_bits == bits || height.is_multiple_of(RETARGET_INTERVAL)
+    {
+        return DifficultyCheck::Consistent;
+    }
+    match network {
+        Network::Mainnet => DifficultyCheck::Mismatch,
+        _ => DifficultyCheck::Unusual,
+    }
+}
+
+/// Result of comparing a pruned Pollard against the MemForest it should mirror.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SnapshotReport {
+    pub forest_leaves: u64,
+    pub pollard_leaves: u64,
+    /// Root indices whose hashes differ (see [`diff_roots`]).
+    pub differing_roots: Vec<usize>,
+}
+
+impl SnapshotReport {
+    pub fn is_consistent(&self) -> bool {
+        self.forest_leaves == self.pollard_leaves && self.differing_roots.is_empty()
+    }
+}
+
+/// Compare a Pollard's roots and leaf count with the MemForest it should mirror.
+pub fn compare(
+    forest: &MemForest<BitcoinNodeHash>,
+    pollard: &Pollard<BitcoinNodeHash>,
+) -> SnapshotReport {
+    let forest_roots = forest
+        .get_roots()
+        .iter()
+        .map(|r| r.get_data())
+        .collect::<Vec<_>>();
+    SnapshotReport {
+        forest_leaves: forest.leaves,
+        pollard_leaves: pollard.leaves(),
+        differing_roots: diff_roots(&forest_roots, &pollard::forest_roots(pollard)),
+    }
+}
+
+/// Deserialize a `mem_forest.bin` and a `pollard.bin` and compare their roots and leaf counts.
+pub fn verify_snapshot(forest_bytes: &[u8], pollard_bytes: &[u8]) -> Result<SnapshotReport> {
+    let forest = MemForest::<BitcoinNodeHash>::deserialize(Cursor::new(forest_bytes))
+        .context("failed to deserialize MemForest")?;
+    let (pollard, _) = pollard::decode(pollard_bytes)?;
+    Ok(compare(&forest, &pollard))
+}
+
+/// Compare the service's working `pollard.bin` with `mem_forest.bin` plus the delta log, all
+/// in the data directory `dir`. Only reads the files.
+pub fn verify_working_state(dir: &Path) -> Result<SnapshotReport> {
+    let forest = delta::load_forest(&dir.join("mem_forest.bin"), &dir.join(DELTA_FILE))?;
+    let pollard = std::fs::read(dir.join("pollard.bin")).context("failed to read pollard.bin")?;
+    let (pollard, _) = pollard::decode(&pollard)?;
+    Ok(compare(&forest, &pollard))
+}
+
+/// Result of comparing a Pollard against an expected Stump, such as the one a proof committed to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StumpReport {
+    pub expected_leaves: u64,
+    pub actual_leaves: u64,
+    /// Root indices whose hashes differ (see [`diff_roots`]).
+    pub differing_roots: Vec<usize>,
+}
+
+impl StumpReport {
+    pub fn is_consistent(&self) -> bool {
+        self.expected_leaves == self.actual_leaves && self.differing_roots.is_empty()
+    }
+}
+
+/// Compare `pollard`'s roots and leaf count with `expected`.
+pub fn verify_roots_against(pollard: &Pollard<BitcoinNodeHash>, expected: &Stump) -> StumpReport {
+    StumpReport {
+        expected_leaves: expected.leaves,
+        actual_leaves: pollard.leaves(),
+        differing_roots: diff_roots(&expected.roots, &pollard::forest_roots(pollard)),
+    }
+}
+
+/// Check that `proof` proves `deletes` against `stump`'s roots. Nothing to prove passes.
+pub fn verify_deletions(
+    stump: &Stump,
+    proof: &Proof<BitcoinNodeHash>,
+    deletes: &[BitcoinNodeHash],
+) -> Result<()> {
+    if deletes.is_empty() {
+        return Ok(());
+    }
+    let valid = stump
+        .verify(proof, deletes)
+        .map_err(|e| anyhow!("failed to verify deletion proof: {e}"))?;
+    ensure!(
+        valid,
+        "deletion proof does not match the roots before the block"
+    );
+    Ok(())
+}
+
+/// Apply a block to `pollard` only once `proof` checks out for `deletes` against the roots the
+/// Pollard has before the block. `Pollard::modify` trusts the proof it's given, so a proof
+/// for other roots could otherwise move the Pollard to roots no honest update produces.
+/// `pollard` is left unchanged if the proof doesn't verify.
+pub fn modify_verified(
+    pollard: &mut Pollard<BitcoinNodeHash>,
+    adds: &[PollardAddition<BitcoinNodeHash>],
+    deletes: &[BitcoinNodeHash],
+    proof: Proof<BitcoinNodeHash>,
+) -> Result<()> {
+    let prev = Stump {
+        leaves: pollard.leaves(),
+        roots: pollard::forest_roots(pollard),
+    };
+    verify_deletions(&prev, &proof, deletes)?;
+    pollard
+        .modify(adds, deletes, proof)
+        .map_err(|e| anyhow!("pollard.modify failed: {e:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script_utils::pollard_conv::forest_to_pollard;
+
+    fn forest_bytes(n: u8) -> Vec<u8> {
+        let leaves: Vec<_> = (0..n).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&leaves, &[]).unwrap();
+        let mut buf = Vec::new();
+        forest.serialize(&mut buf).unwrap();
+        buf
+    }
+
+    fn pollard_bytes(forest: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        forest_to_pollard(forest, &[])
+            .unwrap()
+            .serialize(&mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn diff_roots_reports_changed_and_missing_indices() {
+        let a = [BitcoinNodeHash::new([1; 32]), BitcoinNodeHash::new([2; 32])];
+        let b = [BitcoinNodeHash::new([1; 32]), BitcoinNodeHash::new([3; 32])];
+        assert!(diff_roots(&a, &a).is_empty());
+        assert_eq!(diff_roots(&a, &b), vec![1]);
+        assert_eq!(diff_roots(&a, &a[..1]), vec![1]);
+    }
+
+    #[test]
+    fn mainnet_difficulty_only_changes_at_retarget() {
+        let (a, b) = (
+            CompactTarget::from_consensus(0x1703_4e7c),
+            CompactTarget::from_consensus(0x1703_2f1c),
+        );
+        let check = |height, prev, bits| check_difficulty(Network::Mainnet, height, prev, bits);
+        assert_eq!(check(800_001, a, a), DifficultyCheck::Consistent);
+        assert_eq!(check(800_001, a, b), DifficultyCheck::Mismatch);
+        assert_eq!(
+            check(RETARGET_INTERVAL * 400, a, b),
+            DifficultyCheck::Consistent
+        );
+    }
+
+    #[test]
+    fn testnet_difficulty_may_drop_to_minimum() {
+        let normal = CompactTarget::from_consensus(0x1903_a30c);
+        let minimum = CompactTarget::from_consensus(0x1d00_ffff);
+        for network in [Network::Testnet, Network::Testnet4] {
+            assert_eq!(
+                check_difficulty(network, 50_001, normal, minimum),
+                DifficultyCheck::Unusual
+            );
+            assert_eq!(
+                check_difficulty(network, 50_001, normal, normal),
+                DifficultyCheck::Consistent
+            );
+        }
+    }
+
+    #[test]
+    fn regtest_difficulty_is_not_checked() {
+        let check = check_difficulty(
+            Network::Regtest,
+            5,
+            CompactTarget::from_consensus(0x207f_ffff),
+            CompactTarget::from_consensus(0x1d00_ffff),
+        );
+        assert_eq!(check, DifficultyCheck::Consistent);
+    }
+
+    #[test]
+    fn matching_pair_is_consistent() {
+        let forest = forest_bytes(7);
+        let report = verify_snapshot(&forest, &pollard_bytes(&forest)).unwrap();
+        assert!(report.is_consistent(), "{report:?}");
+    }
+
+    #[test]
+    fn pollard_is_compared_against_expected_stump() {
+        let pollard = forest_to_pollard(&forest_bytes(7), &[]).unwrap();
+        let matching = Stump {
+            leaves: 7,
+            roots: pollard::forest_roots(&pollard),
+        };
+        assert!(verify_roots_against(&pollard, &matching).is_consistent());
+
+        let mut roots = matching.roots.clone();
+        roots[1] = BitcoinNodeHash::new([0xff; 32]);
+        let report = verify_roots_against(&pollard, &Stump { leaves: 8, roots });
+        assert!(!report.is_consistent());
+        assert_eq!(report.expected_leaves, 8);
+        assert_eq!(report.actual_leaves, 7);
+        assert_eq!(report.differing_roots, vec![1]);
+    }
+
+    #[test]
+    fn tampered_deletion_proof_is_rejected() {
+        let leaves: Vec<_> = (0..8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+        let mut forest = MemForest::<BitcoinNodeHash>::new();
+        forest.modify(&leaves, &[]).unwrap();
+        let roots: Vec<_> = forest.get_roots().iter().map(|r| r.get_data()).collect();
+        let mut pollard = pollard::from_forest_roots(&roots, 8);
+        let deletes = [leaves[2], leaves[5]];
+        let adds = [PollardAddition {
+            hash: BitcoinNodeHash::new([0xaa; 32]),
+            remember: false,
+        }];
+
+        let proof = forest.prove(&deletes).unwrap();
+        let mut tampered = proof.clone();
+        tampered.hashes[0] = BitcoinNodeHash::new([0xff; 32]);
+        let err = modify_verified(&mut pollard, &adds, &deletes, tampered).unwrap_err();
+        assert!(err.to_string().contains("deletion proof"), "{err}");
+        assert_eq!(pollard::forest_roots(&pollard), roots);
+        assert_eq!(pollard.leaves(), 8);
+
+        modify_verified(&mut pollard, &adds, &deletes, proof).unwrap();
+        forest.modify(&[adds[0].hash], &deletes).unwrap();
+        let expected: Vec<_> = forest.get_roots().iter().map(|r| r.get_data()).collect();
+        assert_eq!(pollard::forest_roots(&pollard), expected);
+    }
+
+    #[test]
+    fn mismatched_pair_is_reported() {
+        let forest = forest_bytes(7);
+        let other = forest_bytes(6);
+        let report = verify_snapshot(&forest, &pollard_bytes(&other)).unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(report.forest_leaves, 7);
+        assert_eq!(report.pollard_leaves, 6);
+        assert!(!report.differing_roots.is_empty());
+    }
+
+    #[test]
+    fn pollard_with_misplaced_root_is_rejected() {
+        let forest = forest_bytes(8);
+        let mut pollard = pollard_bytes(&forest);
+        assert!(verify_snapshot(&forest, &pollard).unwrap().is_consistent());
+        // 8 leaves are one tree on row 3; mark a root on row 0 as well
+        pollard[8] = 1;
+        assert!(verify_snapshot(&forest, &pollard).is_err());
+    }
+}
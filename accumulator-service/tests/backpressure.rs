@@ -0,0 +1,36 @@
+//! Integration test: a full command queue is reported as busy instead of blocking the caller.
+use accumulator_service::state_machine::{Command, DispatchError};
+use accumulator_service::{api, Context};
+use actix_web::{test, web::Data, App};
+use std::time::Duration;
+
+#[tokio::test]
+async fn full_queue_is_busy() {
+    let ctx = Context::with_capacity(2);
+    // The worker runs on this same thread, so it can't take anything off the queue until the
+    // test yields.
+    ctx.send(Command::ClearQueue).await.unwrap();
+    ctx.send(Command::ClearQueue).await.unwrap();
+    let flooded = tokio::time::timeout(Duration::from_secs(5), ctx.send(Command::ClearQueue))
+        .await
+        .expect("send must not wait for room");
+    assert!(matches!(flooded, Err(DispatchError::Busy)));
+
+    // once the worker has caught up there is room again
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    ctx.send(Command::ClearQueue).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn busy_maps_to_503() {
+    let ctx = Context::with_capacity(1);
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(ctx.clone()))
+            .configure(api::configure),
+    )
+    .await;
+    ctx.send(Command::ClearQueue).await.unwrap();
+    let req = test::TestRequest::delete().uri("/queue").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 503);
+}
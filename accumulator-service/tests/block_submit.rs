@@ -0,0 +1,148 @@
+//! POST /block applies a block submitted by the caller and returns the new roots.
+use accumulator_service::sync_state::{self, SyncState};
+use accumulator_service::{api, Context};
+use actix_web::{test, web::Data, App};
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::{
+    transaction, Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness,
+};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use utreexo::{process_block_changes, ProcessOptions};
+
+fn tx(previous_output: OutPoint, outputs: usize) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: (0..outputs)
+            .map(|_| TxOut {
+                value: Amount::from_sat(1_000),
+                script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+            })
+            .collect(),
+    }
+}
+
+/// A block on top of `prev` whose second transaction spends `spent`.
+fn block(prev: BlockHash, spent: OutPoint) -> Block {
+    Block {
+        header: Header {
+            version: Version::TWO,
+            prev_blockhash: prev,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: vec![tx(OutPoint::null(), 1), tx(spent, 2)],
+    }
+}
+
+#[actix_rt::test]
+async fn submitted_block_advances_roots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+
+    // one pre-existing UTXO, synced to block 100
+    let spent = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+    let spent_leaf = BitcoinNodeHash::new([7; 32]);
+    let mut forest = MemForest::<BitcoinNodeHash>::new();
+    forest.modify(&[spent_leaf], &[]).unwrap();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+    let tip = BlockHash::from_byte_array([9; 32]);
+    sync_state::write(
+        dir,
+        &SyncState {
+            height: Some(100),
+            block_hash: Some(tip),
+            keep_op_return: false,
+            unresolved_prevouts: 0,
+        },
+    )
+    .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(ctx.clone()))
+            .configure(api::configure),
+    )
+    .await;
+
+    // a block on another tip is refused and changes nothing
+    let req = test::TestRequest::post()
+        .uri("/block")
+        .set_json(json!({
+            "height": 101,
+            "block": serialize_hex(&block(BlockHash::all_zeros(), spent)),
+            "input_leaves": ["07".repeat(32)],
+        }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 409);
+
+    let next = block(tip, spent);
+    let body = json!({
+        "height": 101,
+        "block": serialize_hex(&next),
+        "input_leaves": ["07".repeat(32)],
+    });
+    let req = test::TestRequest::post()
+        .uri("/block")
+        .set_json(&body)
+        .to_request();
+    let resp: Value = test::call_and_read_body_json(&app, req).await;
+
+    // the same block applied to the same forest by the shared block processing
+    let mut expected = forest;
+    process_block_changes(
+        &next,
+        101,
+        &mut expected,
+        BTreeMap::from([(next.txdata[1].input[0].clone(), spent_leaf)]),
+        ProcessOptions::default(),
+    )
+    .unwrap();
+    let roots: Vec<String> = utreexo::forest_roots_bytes(&expected)
+        .unwrap()
+        .iter()
+        .map(|root| root.to_lower_hex_string())
+        .collect();
+    assert_eq!(expected.leaves, 4);
+    assert_eq!(resp, json!({ "leaves": 4, "roots": roots }));
+
+    // /roots and /height follow the block
+    let req = test::TestRequest::get().uri("/roots").to_request();
+    let body_roots: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body_roots, resp);
+    let req = test::TestRequest::get().uri("/height").to_request();
+    let height: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        height,
+        json!({ "height": 101, "block_hash": next.block_hash() })
+    );
+
+    // submitting it again no longer extends the tip
+    let req = test::TestRequest::post()
+        .uri("/block")
+        .set_json(&body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 409);
+}
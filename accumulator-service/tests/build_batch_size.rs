@@ -0,0 +1,72 @@
+//! Integration test: a build honours a custom batch size and rejects a zero one.
+use accumulator_service::block_hashes::{BlockHashes, BLOCK_HASHES_FILE};
+use accumulator_service::builder::{BuildCheckpoint, CHECKPOINT_FILE};
+use accumulator_service::state_machine::ServiceState;
+use accumulator_service::{api, Context};
+use actix_web::{test, web::Data, App};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use duckdb::{params, Connection};
+use serde_json::json;
+use std::time::Duration;
+
+#[actix_rt::test]
+async fn build_with_small_batches() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let parquet = dir.join("utxos.parquet").to_string_lossy().into_owned();
+
+    // five spendable rows, one coinbase row that is skipped
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    for vout in 0..6 {
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params!["e".repeat(64), 1_000, vout, 1, vec![0x51u8], vout == 5],
+        )
+        .unwrap();
+    }
+    conn.execute(&format!("COPY utxos TO '{parquet}' (FORMAT 'parquet')"), [])
+        .unwrap();
+    // zero block hashes, so the leaves need no node
+    BlockHashes::new(vec![BlockHash::all_zeros(); 2])
+        .save(&dir.join(BLOCK_HASHES_FILE))
+        .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(ctx.clone()))
+            .configure(api::configure),
+    )
+    .await;
+    let build = |batch_size: usize| {
+        test::TestRequest::post()
+            .uri("/build")
+            .set_json(json!({ "parquet": parquet, "batch_size": batch_size }))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, build(0)).await;
+    assert_eq!(resp.status(), 400);
+
+    let resp = test::call_service(&app, build(2)).await;
+    assert_eq!(resp.status(), 202);
+    let mut state = ctx.status().await.state;
+    for _ in 0..100 {
+        if !matches!(state, ServiceState::Building) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state = ctx.status().await.state;
+    }
+    assert_eq!(state, ServiceState::Idle);
+
+    let checkpoint: BuildCheckpoint =
+        serde_json::from_slice(&std::fs::read(dir.join(CHECKPOINT_FILE)).unwrap()).unwrap();
+    assert_eq!(checkpoint.leaves, 5);
+}
@@ -0,0 +1,53 @@
+//! A build needs the block hash of every height in the dump; with neither `block_hashes.bin`
+//! nor Bitcoin Core to look them up, it fails instead of committing leaves to a zero hash.
+use accumulator_service::state_machine::{Command, ServiceState};
+use accumulator_service::Context;
+use duckdb::{params, Connection};
+use std::time::Duration;
+
+#[tokio::test]
+async fn build_without_block_hashes_fails() {
+    std::env::remove_var("BITCOIN_CORE_RPC_URL");
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let parquet = dir.join("utxos.parquet").to_string_lossy().into_owned();
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+        params!["d".repeat(64), 1_000, 0, 1, vec![0x51u8], false],
+    )
+    .unwrap();
+    conn.execute(&format!("COPY utxos TO '{parquet}' (FORMAT 'parquet')"), [])
+        .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    ctx.send(Command::Build {
+        parquet,
+        resume_from: None,
+        block_hash: None,
+        validate_sample: None,
+        batch_size: None,
+        keep_op_return: false,
+    })
+    .await
+    .unwrap();
+
+    let mut state = ctx.status().await.state;
+    for _ in 0..100 {
+        if !matches!(state, ServiceState::Building) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state = ctx.status().await.state;
+    }
+    match state {
+        ServiceState::Error { msg } => assert!(msg.contains("block_hashes.bin"), "{msg}"),
+        other => panic!("expected error state, got {other:?}"),
+    }
+    assert!(!dir.join("mem_forest.bin").exists());
+}
@@ -0,0 +1,61 @@
+//! Integration test: a dry-run build reports the dump's size without building anything.
+use accumulator_service::builder::BuildEstimate;
+use accumulator_service::forest;
+use accumulator_service::state_machine::ServiceState;
+use accumulator_service::{api, Context};
+use actix_web::{test, web::Data, App};
+use duckdb::{params, Connection};
+use serde_json::json;
+
+#[actix_rt::test]
+async fn dry_run_counts_non_coinbase_rows() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let parquet = dir.join("utxos.parquet").to_string_lossy().into_owned();
+
+    // four spendable rows and two coinbase rows
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    for vout in 0..6 {
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params!["d".repeat(64), 1_000, vout, 1, vec![0x51u8], vout >= 4],
+        )
+        .unwrap();
+    }
+    conn.execute(&format!("COPY utxos TO '{parquet}' (FORMAT 'parquet')"), [])
+        .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(ctx.clone()))
+            .configure(api::configure),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri("/build")
+        .set_json(json!({ "parquet": parquet, "dry_run": true }))
+        .to_request();
+    let estimate: BuildEstimate = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        estimate,
+        BuildEstimate {
+            rows: 4,
+            forest_bytes: forest::serialized_size(4),
+        }
+    );
+
+    assert_eq!(ctx.status().await.state, ServiceState::Idle);
+    assert!(!dir.join("mem_forest.bin").exists());
+
+    let req = test::TestRequest::post()
+        .uri("/build")
+        .set_json(json!({ "parquet": dir.join("missing.parquet"), "dry_run": true }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
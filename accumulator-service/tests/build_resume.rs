@@ -0,0 +1,57 @@
+//! Resuming a build by block hash must refuse a snapshot recorded for another block.
+
+use accumulator_service::builder::{BuildCheckpoint, CHECKPOINT_FILE};
+use accumulator_service::state_machine::{Command, Context, ServiceState};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::fs::File;
+use std::time::Duration;
+
+#[tokio::test]
+async fn resume_with_mismatched_block_hash_errors() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+
+    // existing snapshot, checkpointed as built from block [1; 32]
+    let forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    let mut f = File::create(dir.join("mem_forest.bin")).unwrap();
+    forest.serialize(&mut f).unwrap();
+    let checkpoint = BuildCheckpoint {
+        block_hash: Some(BlockHash::from_byte_array([1; 32])),
+        leaves: 0,
+    };
+    std::fs::write(
+        dir.join(CHECKPOINT_FILE),
+        serde_json::to_vec(&checkpoint).unwrap(),
+    )
+    .unwrap();
+
+    // ask to resume the build for a different block
+    let other = BlockHash::from_byte_array([2; 32]);
+    let ctx = Context::in_dir(dir);
+    ctx.send(Command::Build {
+        parquet: "utxos.parquet".into(),
+        resume_from: Some(other.to_string()),
+        block_hash: None,
+        validate_sample: None,
+        batch_size: None,
+        keep_op_return: false,
+    })
+    .await
+    .unwrap();
+
+    let mut state = ctx.status().await.state;
+    for _ in 0..40 {
+        if matches!(state, ServiceState::Error { .. }) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state = ctx.status().await.state;
+    }
+    match state {
+        ServiceState::Error { msg } => assert!(msg.contains("no build checkpoint"), "{msg}"),
+        other => panic!("expected error state, got {other:?}"),
+    }
+}
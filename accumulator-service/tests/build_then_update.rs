@@ -0,0 +1,252 @@
+//! A forest built from a UTXO dump, then advanced over RPC by a block spending some of the
+//! dump's outputs, ends up with the same roots as the circuit's block processing gives.
+use accumulator_service::block_hashes::{BlockHashes, BLOCK_HASHES_FILE};
+use accumulator_service::builder::start_build;
+use accumulator_service::delta::{self, DELTA_FILE};
+use accumulator_service::pollard::{decode, forest_roots};
+use accumulator_service::script_utils::btc_rpc::{
+    block_output_leaves, BitcoinRpc, MissingPrevout, TxOutInfo,
+};
+use accumulator_service::{sync_state, updater};
+use anyhow::{anyhow, Result};
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute, transaction, Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness,
+};
+use duckdb::{params, Connection};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+use utreexo::{process_block_changes, ProcessOptions};
+
+/// Height of the block whose outputs make up the dump.
+const DUMP_HEIGHT: u32 = 5;
+
+fn tx(input: Vec<OutPoint>, outputs: u8) -> Transaction {
+    Transaction {
+        version: transaction::Version::ONE,
+        lock_time: absolute::LockTime::ZERO,
+        input: input
+            .into_iter()
+            .map(|previous_output| TxIn {
+                previous_output,
+                script_sig: ScriptBuf::from_bytes(vec![0x51]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: (0..outputs)
+            .map(|i| {
+                let mut script = vec![0x00, 0x14];
+                script.extend_from_slice(&[i; 20]);
+                TxOut {
+                    value: Amount::from_sat(1_000 + u64::from(i)),
+                    script_pubkey: ScriptBuf::from_bytes(script),
+                }
+            })
+            .collect(),
+    }
+}
+
+fn block(prev_blockhash: BlockHash, txdata: Vec<Transaction>) -> Block {
+    Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata,
+    }
+}
+
+/// A coinbase with one output; `coinbase(n)` differ in their input so their txids do.
+fn coinbase(n: u8) -> Transaction {
+    let mut coinbase = tx(vec![OutPoint::null()], 1);
+    coinbase.input[0].script_sig = ScriptBuf::from_bytes(vec![0x01, n]);
+    coinbase
+}
+
+/// The block the dump was taken after: one transaction creating four outputs.
+fn dump_block() -> Block {
+    let funding = OutPoint::new(Txid::from_byte_array([9; 32]), 0);
+    block(
+        BlockHash::all_zeros(),
+        vec![coinbase(0), tx(vec![funding], 4)],
+    )
+}
+
+/// The dump's non-coinbase UTXOs, as a UTXO set export would list them.
+fn write_dump(path: &Path, block: &Block) {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    let creator = &block.txdata[1];
+    let txid = creator.compute_txid().to_string();
+    for (vout, out) in creator.output.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                txid,
+                out.value.to_sat() as i64,
+                vout as i32,
+                DUMP_HEIGHT as i64,
+                out.script_pubkey.to_bytes(),
+                false
+            ],
+        )
+        .unwrap();
+    }
+    conn.execute(
+        &format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        ),
+        [],
+    )
+    .unwrap();
+}
+
+/// Serves the block after the dump and the transactions of both blocks.
+struct MockRpc {
+    dump: Block,
+    next: Block,
+}
+
+impl BitcoinRpc for MockRpc {
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash> {
+        match height {
+            h if h == u64::from(DUMP_HEIGHT) => Ok(self.dump.block_hash()),
+            h if h == u64::from(DUMP_HEIGHT) + 1 => Ok(self.next.block_hash()),
+            _ => Err(anyhow!("no block at height {height}")),
+        }
+    }
+    fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+        [&self.dump, &self.next]
+            .into_iter()
+            .find(|block| block.block_hash() == *hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown block {hash}"))
+    }
+    fn get_txout(&self, prevout: &OutPoint) -> Result<TxOutInfo> {
+        for block in [&self.dump, &self.next] {
+            for tx in &block.txdata {
+                if tx.compute_txid() != prevout.txid {
+                    continue;
+                }
+                let out = tx
+                    .output
+                    .get(prevout.vout as usize)
+                    .ok_or_else(|| anyhow!("no output {prevout}"))?;
+                return Ok(TxOutInfo {
+                    value: out.value.to_sat(),
+                    script: out.script_pubkey.to_bytes(),
+                    block_hash: block.block_hash(),
+                    is_coinbase: tx.is_coinbase(),
+                });
+            }
+        }
+        Err(anyhow!("unknown prevout {prevout}"))
+    }
+    fn get_block_height(&self, hash: &BlockHash) -> Result<u32> {
+        if *hash == self.dump.block_hash() {
+            Ok(DUMP_HEIGHT)
+        } else if *hash == self.next.block_hash() {
+            Ok(DUMP_HEIGHT + 1)
+        } else {
+            Err(anyhow!("unknown block {hash}"))
+        }
+    }
+}
+
+fn roots(forest: &MemForest<BitcoinNodeHash>) -> Vec<BitcoinNodeHash> {
+    forest.get_roots().iter().map(|r| r.get_data()).collect()
+}
+
+#[tokio::test]
+async fn update_after_build_matches_process_block() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let dump = dump_block();
+    let parquet = dir.join("utxos.parquet");
+    write_dump(&parquet, &dump);
+    let mut hashes = vec![BlockHash::all_zeros(); DUMP_HEIGHT as usize];
+    hashes.push(dump.block_hash());
+    BlockHashes::new(hashes)
+        .save(&dir.join(BLOCK_HASHES_FILE))
+        .unwrap();
+
+    start_build(
+        dir,
+        &parquet.to_string_lossy(),
+        None,
+        None,
+        None,
+        None,
+        false,
+        CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    let synced = sync_state::read(dir).unwrap().unwrap();
+    assert_eq!(synced.height, Some(u64::from(DUMP_HEIGHT)));
+
+    // The next block spends two of the dump's outputs, and one output of a transaction in
+    // the same block, which is never added
+    let creator = dump.txdata[1].compute_txid();
+    let spender = tx(
+        vec![OutPoint::new(creator, 1), OutPoint::new(creator, 3)],
+        2,
+    );
+    let chained = tx(vec![OutPoint::new(spender.compute_txid(), 0)], 1);
+    let next = block(dump.block_hash(), vec![coinbase(1), spender, chained]);
+    let rpc = MockRpc {
+        dump: dump.clone(),
+        next: next.clone(),
+    };
+    let unresolved = updater::update_block_with(
+        dir,
+        Some(&rpc),
+        u64::from(DUMP_HEIGHT) + 1,
+        MissingPrevout::Fail,
+        &CancellationToken::new(),
+    )
+    .unwrap();
+    assert!(unresolved.is_empty());
+
+    // The same two blocks through the circuit's block processing, starting from the dump's
+    // leaves
+    let mut expected = MemForest::new();
+    let dump_leaves = block_output_leaves(&dump, DUMP_HEIGHT, false).unwrap();
+    // the dump leaves out the coinbase's output
+    expected.modify(&dump_leaves[1..], &[]).unwrap();
+    let next_outputs = block_output_leaves(&next, DUMP_HEIGHT + 1, false).unwrap();
+    let input_leaves: BTreeMap<TxIn, BitcoinNodeHash> = BTreeMap::from([
+        (next.txdata[1].input[0].clone(), dump_leaves[2]),
+        (next.txdata[1].input[1].clone(), dump_leaves[4]),
+        (next.txdata[2].input[0].clone(), next_outputs[1]),
+    ]);
+    process_block_changes(
+        &next,
+        DUMP_HEIGHT + 1,
+        &mut expected,
+        input_leaves,
+        ProcessOptions::default(),
+    )
+    .unwrap();
+
+    let forest = delta::load_forest(&dir.join("mem_forest.bin"), &dir.join(DELTA_FILE)).unwrap();
+    assert_eq!(forest.leaves, expected.leaves);
+    assert_eq!(roots(&forest), roots(&expected));
+    let (pollard, _) = decode(&std::fs::read(dir.join("pollard.bin")).unwrap()).unwrap();
+    assert_eq!(forest_roots(&pollard), roots(&expected));
+}
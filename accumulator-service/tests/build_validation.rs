@@ -0,0 +1,116 @@
+//! Sampled validation of a Parquet export against Bitcoin Core must flag a corrupted row.
+
+use accumulator_service::builder::validate_sample;
+use accumulator_service::script_utils::btc_rpc::{BitcoinRpc, TxOutInfo};
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::Hash;
+use bitcoin::{Block, BlockHash, OutPoint, Txid};
+use duckdb::{params, Connection};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Node view of the outputs: `(txid, vout) -> (amount, script)`.
+struct MockRpc(HashMap<OutPoint, (u64, Vec<u8>)>);
+
+impl BitcoinRpc for MockRpc {
+    fn get_block_hash(&self, _height: u64) -> Result<BlockHash> {
+        Err(anyhow!("not used"))
+    }
+    fn get_block(&self, _hash: &BlockHash) -> Result<Block> {
+        Err(anyhow!("not used"))
+    }
+    fn get_txout(&self, prevout: &OutPoint) -> Result<TxOutInfo> {
+        let (value, script) = self
+            .0
+            .get(prevout)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown prevout {prevout}"))?;
+        Ok(TxOutInfo {
+            value,
+            script,
+            block_hash: BlockHash::all_zeros(),
+            is_coinbase: false,
+        })
+    }
+    fn get_block_height(&self, _hash: &BlockHash) -> Result<u32> {
+        Err(anyhow!("not used"))
+    }
+}
+
+fn utxos() -> Vec<(OutPoint, u64, Vec<u8>)> {
+    (0..4u8)
+        .map(|i| {
+            let outpoint = OutPoint {
+                txid: Txid::from_byte_array([i + 1; 32]),
+                vout: u32::from(i),
+            };
+            let mut script = vec![0x00, 0x14];
+            script.extend_from_slice(&[i; 20]);
+            (outpoint, 1_000 * (u64::from(i) + 1), script)
+        })
+        .collect()
+}
+
+fn write_parquet(path: &Path, rows: &[(OutPoint, u64, Vec<u8>)]) {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    for (outpoint, amount, script) in rows {
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                outpoint.txid.to_string(),
+                *amount as i64,
+                outpoint.vout as i32,
+                1i64,
+                script,
+                false
+            ],
+        )
+        .unwrap();
+    }
+    conn.execute(
+        &format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        ),
+        [],
+    )
+    .unwrap();
+}
+
+fn rpc() -> MockRpc {
+    MockRpc(
+        utxos()
+            .into_iter()
+            .map(|(outpoint, amount, script)| (outpoint, (amount, script)))
+            .collect(),
+    )
+}
+
+#[test]
+fn clean_export_passes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("utxos.parquet");
+    write_parquet(&path, &utxos());
+    validate_sample(&rpc(), path.to_str().unwrap(), 10).unwrap();
+}
+
+#[test]
+fn corrupted_row_is_detected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("utxos.parquet");
+    let mut rows = utxos();
+    rows[2].1 += 1;
+    write_parquet(&path, &rows);
+
+    // sample every row so the corrupted one is always picked
+    let err = validate_sample(&rpc(), path.to_str().unwrap(), rows.len())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains(&rows[2].0.to_string()), "{err}");
+    assert!(err.contains("does not match Bitcoin Core"), "{err}");
+}
@@ -0,0 +1,85 @@
+//! Integration test: contexts with their own data directories build side by side without
+//! seeing each other's files.
+use accumulator_service::block_hashes::{BlockHashes, BLOCK_HASHES_FILE};
+use accumulator_service::builder::{BuildCheckpoint, CHECKPOINT_FILE};
+use accumulator_service::state_machine::{Command, ServiceState};
+use accumulator_service::{sync_state, Context};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use duckdb::{params, Connection};
+use std::path::Path;
+use std::time::Duration;
+
+/// Write a dump with `rows` spendable outputs created at `height` to `dir/utxos.parquet`, with
+/// zero block hashes for it in `dir/block_hashes.bin`.
+fn write_dump(dir: &Path, rows: i32, height: i64) -> String {
+    let parquet = dir.join("utxos.parquet").to_string_lossy().into_owned();
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    for vout in 0..rows {
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params!["f".repeat(64), 1_000, vout, height, vec![0x51u8], false],
+        )
+        .unwrap();
+    }
+    conn.execute(&format!("COPY utxos TO '{parquet}' (FORMAT 'parquet')"), [])
+        .unwrap();
+    BlockHashes::new(vec![BlockHash::all_zeros(); height as usize + 1])
+        .save(&dir.join(BLOCK_HASHES_FILE))
+        .unwrap();
+    parquet
+}
+
+async fn wait_until_built(ctx: &Context) -> ServiceState {
+    let mut state = ctx.status().await.state;
+    for _ in 0..100 {
+        if !matches!(state, ServiceState::Building) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state = ctx.status().await.state;
+    }
+    state
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn concurrent_builds_in_separate_dirs() {
+    std::env::remove_var("BITCOIN_CORE_RPC_URL");
+    let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let dumps = [
+        (a.path(), write_dump(a.path(), 3, 10)),
+        (b.path(), write_dump(b.path(), 5, 20)),
+    ];
+
+    let contexts: Vec<_> = dumps.iter().map(|(dir, _)| Context::in_dir(*dir)).collect();
+    for (ctx, (_, parquet)) in contexts.iter().zip(&dumps) {
+        ctx.send(Command::Build {
+            parquet: parquet.clone(),
+            resume_from: None,
+            block_hash: None,
+            validate_sample: None,
+            batch_size: Some(1),
+            keep_op_return: false,
+        })
+        .await
+        .unwrap();
+    }
+    for ctx in &contexts {
+        assert_eq!(wait_until_built(ctx).await, ServiceState::Idle);
+    }
+
+    for ((dir, _), (leaves, height)) in dumps.iter().zip([(3, 10), (5, 20)]) {
+        let checkpoint: BuildCheckpoint =
+            serde_json::from_slice(&std::fs::read(dir.join(CHECKPOINT_FILE)).unwrap()).unwrap();
+        assert_eq!(checkpoint.leaves, leaves);
+        let synced = sync_state::read(dir)
+            .unwrap()
+            .and_then(|state| state.height);
+        assert_eq!(synced, Some(height));
+    }
+}
@@ -1,5 +1,6 @@
 //! Integration-ish tests for the Dump / Restore implementation (phase-A).
 
+use accumulator_service::delta::{self, DELTA_FILE};
 use accumulator_service::state_machine::{Command, Context, ServiceState};
 use rustreexo::accumulator::mem_forest::MemForest;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
@@ -17,19 +18,19 @@ async fn wait_until_idle(ctx: &Context) {
 #[tokio::test]
 async fn dump_and_restore_roundtrip() {
     let workdir = tempfile::tempdir().unwrap();
-    std::env::set_current_dir(&workdir).unwrap();
+    let dir = workdir.path();
 
     // create minimal mem_forest.bin
     let forest: MemForest<BitcoinNodeHash> = MemForest::new();
-    let mut f = File::create("mem_forest.bin").unwrap();
+    let mut f = File::create(dir.join("mem_forest.bin")).unwrap();
     forest.serialize(&mut f).unwrap();
 
     // touch block_hashes.bin to ensure it is included in snapshot
-    std::fs::write("block_hashes.bin", b"dummy").unwrap();
+    std::fs::write(dir.join("block_hashes.bin"), b"dummy").unwrap();
 
     // create context & issue dump
-    let ctx = Context::new();
-    let snapshot_dir = workdir.path().join("snap");
+    let ctx = Context::in_dir(dir);
+    let snapshot_dir = dir.join("snap");
     ctx.send(Command::Dump {
         dir: snapshot_dir.clone(),
     })
@@ -51,20 +52,74 @@ async fn dump_and_restore_roundtrip() {
     wait_until_idle(&ctx).await;
 
     // remove mem_forest.bin to simulate missing/invalid state
-    std::fs::remove_file("mem_forest.bin").unwrap();
+    std::fs::remove_file(dir.join("mem_forest.bin")).unwrap();
 
     // restore
-    ctx.send(Command::Restore {
-        dir: snapshot_dir.clone(),
-    })
-    .await
-    .unwrap();
+    ctx.restore(&snapshot_dir)
+        .await
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
     wait_until_idle(&ctx).await;
 
     // after restore mem_forest.bin contents should equal snapshot copy
     for f in ["mem_forest.bin", "block_hashes.bin"].iter() {
         let orig = std::fs::read(snapshot_dir.join(f)).unwrap();
-        let new = std::fs::read(f).unwrap();
+        let new = std::fs::read(dir.join(f)).unwrap();
         assert_eq!(orig, new, "{} differs after restore", f);
     }
 }
+
+#[tokio::test]
+async fn dump_and_restore_carry_the_forest_sidecar() {
+    let workdir = tempfile::tempdir().unwrap();
+    let dir = workdir.path();
+    let forest_path = dir.join("mem_forest.bin");
+    let meta = delta::meta_path(&forest_path);
+    let leaves: Vec<_> = (0..6u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    forest.modify(&leaves[..4], &[]).unwrap();
+    delta::write_snapshot(&forest, Some(10), &forest_path, &dir.join(DELTA_FILE)).unwrap();
+
+    let ctx = Context::in_dir(dir);
+    let snapshot_dir = dir.join("snap");
+    ctx.send(Command::Dump {
+        dir: snapshot_dir.clone(),
+    })
+    .await
+    .unwrap();
+    let snapshot_meta = delta::meta_path(&snapshot_dir.join("mem_forest.bin"));
+    for _ in 0..20 {
+        if snapshot_meta.exists() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let dumped = std::fs::read(&snapshot_meta).unwrap();
+    assert_eq!(dumped, std::fs::read(&meta).unwrap());
+    wait_until_idle(&ctx).await;
+
+    // the working forest moves on, with a sidecar of its own
+    forest.modify(&leaves[4..], &[]).unwrap();
+    delta::write_snapshot(&forest, Some(11), &forest_path, &dir.join(DELTA_FILE)).unwrap();
+    assert_ne!(std::fs::read(&meta).unwrap(), dumped);
+
+    ctx.restore(&snapshot_dir)
+        .await
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(&meta).unwrap(), dumped);
+
+    // a snapshot without one leaves no stale sidecar behind
+    std::fs::remove_file(&snapshot_meta).unwrap();
+    ctx.restore(&snapshot_dir)
+        .await
+        .unwrap()
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!meta.exists());
+}
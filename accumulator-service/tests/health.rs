@@ -0,0 +1,49 @@
+//! Liveness / readiness probes in the Idle state, with and without a valid forest on disk.
+
+use accumulator_service::{api, Context};
+use actix_web::{test, web, App};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::fs::File;
+
+#[actix_rt::test]
+async fn healthz_and_readyz() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+
+    let ctx = Context::in_dir(dir);
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(ctx.clone()))
+            .configure(api::configure),
+    )
+    .await;
+
+    let get = |uri: &'static str| test::TestRequest::get().uri(uri).to_request();
+
+    // alive regardless of on-disk state
+    let resp = test::call_service(&app, get("/healthz")).await;
+    assert_eq!(resp.status(), 200);
+
+    // no forest yet: not ready
+    let resp = test::call_service(&app, get("/readyz")).await;
+    assert_eq!(resp.status(), 503);
+
+    // garbage forest: not ready
+    std::fs::write(dir.join("mem_forest.bin"), b"garbage").unwrap();
+    let resp = test::call_service(&app, get("/readyz")).await;
+    assert_eq!(resp.status(), 503);
+
+    // valid forest: ready
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    forest
+        .modify(&[BitcoinNodeHash::new([1; 32])], &[])
+        .unwrap();
+    let mut f = File::create(dir.join("mem_forest.bin")).unwrap();
+    forest.serialize(&mut f).unwrap();
+    let resp = test::call_service(&app, get("/readyz")).await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = test::call_service(&app, get("/healthz")).await;
+    assert_eq!(resp.status(), 200);
+}
@@ -0,0 +1,192 @@
+//! Integration test: each `MissingPrevout` policy when the node can't return one prevout, and
+//! an update that couldn't fetch its prevouts.
+use accumulator_service::script_utils::btc_rpc::{
+    get_block_leaf_hashes_with, BitcoinRpc, MissingPrevout, TxOutInfo,
+};
+use accumulator_service::{sync_state, updater, Context};
+use anyhow::{anyhow, Result};
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute, transaction, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, Txid, Witness,
+};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::cell::Cell;
+use std::fs::File;
+use tokio_util::sync::CancellationToken;
+
+fn outpoint(i: u8) -> OutPoint {
+    OutPoint::new(Txid::from_byte_array([i; 32]), 0)
+}
+
+/// A coinbase and one transaction spending `outpoint(1)` and `outpoint(2)`.
+fn block() -> Block {
+    let txin = |previous_output| TxIn {
+        previous_output,
+        script_sig: ScriptBuf::from_bytes(vec![0x51]),
+        sequence: Sequence::MAX,
+        witness: Witness::new(),
+    };
+    let tx = |input| Transaction {
+        version: transaction::Version::ONE,
+        lock_time: absolute::LockTime::ZERO,
+        input,
+        output: vec![],
+    };
+    Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: vec![
+            tx(vec![txin(OutPoint::null())]),
+            tx(vec![txin(outpoint(1)), txin(outpoint(2))]),
+        ],
+    }
+}
+
+/// Fails the first `failures` requests for `outpoint(2)`.
+struct FlakyRpc {
+    failures: Cell<u32>,
+}
+
+impl FlakyRpc {
+    fn new(failures: u32) -> Self {
+        FlakyRpc {
+            failures: Cell::new(failures),
+        }
+    }
+}
+
+impl BitcoinRpc for FlakyRpc {
+    fn get_block_hash(&self, _height: u64) -> Result<BlockHash> {
+        Ok(BlockHash::from_byte_array([3; 32]))
+    }
+    fn get_block(&self, _hash: &BlockHash) -> Result<Block> {
+        Ok(block())
+    }
+    fn get_txout(&self, prevout: &OutPoint) -> Result<TxOutInfo> {
+        if *prevout == outpoint(2) && self.failures.get() > 0 {
+            self.failures.set(self.failures.get() - 1);
+            return Err(anyhow!("no such transaction (pruned)"));
+        }
+        Ok(TxOutInfo {
+            value: 1_000,
+            script: vec![0x51],
+            block_hash: BlockHash::from_byte_array([2; 32]),
+            is_coinbase: false,
+        })
+    }
+    fn get_block_height(&self, _hash: &BlockHash) -> Result<u32> {
+        Ok(2)
+    }
+}
+
+fn fetch(rpc: &FlakyRpc, policy: MissingPrevout) -> Result<(usize, Vec<OutPoint>)> {
+    let leaves = get_block_leaf_hashes_with(rpc, 3, policy, false, &CancellationToken::new())?;
+    Ok((leaves.hashes.len(), leaves.unresolved))
+}
+
+#[test]
+fn fail_fast_reports_the_prevout() {
+    let err = fetch(&FlakyRpc::new(1), MissingPrevout::Fail).unwrap_err();
+    assert!(
+        format!("{err:#}").contains(&outpoint(2).to_string()),
+        "{err:#}"
+    );
+}
+
+#[test]
+fn skip_missing_lists_unresolved_inputs() {
+    let fetched = fetch(&FlakyRpc::new(u32::MAX), MissingPrevout::Skip).unwrap();
+    assert_eq!(fetched, (1, vec![outpoint(2)]));
+}
+
+#[test]
+fn retry_recovers_from_transient_failures() {
+    let fetched = fetch(&FlakyRpc::new(2), MissingPrevout::Retry(2)).unwrap();
+    assert_eq!(fetched, (2, vec![]));
+    // more failures than retries still fails the block
+    assert!(fetch(&FlakyRpc::new(3), MissingPrevout::Retry(2)).is_err());
+}
+
+#[test]
+fn failed_fetch_does_not_advance_the_sync_height() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    // a forest holding both spent leaves
+    let spent = get_block_leaf_hashes_with(
+        &FlakyRpc::new(0),
+        3,
+        MissingPrevout::Fail,
+        false,
+        &CancellationToken::new(),
+    )
+    .unwrap();
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    forest.modify(&spent.hashes, &[]).unwrap();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+
+    let rpc = FlakyRpc::new(1);
+    let cancel = CancellationToken::new();
+    assert!(updater::update_block_with(dir, Some(&rpc), 3, MissingPrevout::Fail, &cancel).is_err());
+    assert!(!dir.join("pollard.bin").exists());
+    assert_eq!(sync_state::read(dir).unwrap(), None);
+
+    // so the retry isn't skipped as already applied
+    updater::update_block_with(dir, Some(&rpc), 3, MissingPrevout::Fail, &cancel).unwrap();
+    assert_eq!(sync_state::read(dir).unwrap().unwrap().height, Some(3));
+}
+
+#[test]
+fn policy_parses() {
+    assert_eq!(
+        "fail".parse::<MissingPrevout>().unwrap(),
+        MissingPrevout::Fail
+    );
+    assert_eq!(
+        "skip".parse::<MissingPrevout>().unwrap(),
+        MissingPrevout::Skip
+    );
+    assert_eq!(
+        "retry:3".parse::<MissingPrevout>().unwrap(),
+        MissingPrevout::Retry(3)
+    );
+    for bad in ["", "retry", "retry:x", "Skip"] {
+        assert!(bad.parse::<MissingPrevout>().is_err(), "{bad:?}");
+    }
+}
+
+#[tokio::test]
+async fn skipped_prevouts_are_counted_in_status() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let rpc = FlakyRpc::new(u32::MAX);
+    let cancel = CancellationToken::new();
+    // a forest holding the leaf the node can still return
+    let found = get_block_leaf_hashes_with(&rpc, 3, MissingPrevout::Skip, false, &cancel).unwrap();
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    forest.modify(&found.hashes, &[]).unwrap();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    assert_eq!(ctx.status().await.unresolved_prevouts, None);
+    let skipped =
+        updater::update_block_with(dir, Some(&rpc), 3, MissingPrevout::Skip, &cancel).unwrap();
+    assert_eq!(skipped, vec![outpoint(2)]);
+    assert_eq!(
+        sync_state::read(dir).unwrap().unwrap().unresolved_prevouts,
+        1
+    );
+    assert_eq!(ctx.status().await.unresolved_prevouts, Some(1));
+}
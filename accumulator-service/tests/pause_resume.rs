@@ -0,0 +1,79 @@
+//! Integration test: a paused build writes nothing, and starts over and completes on resume.
+use accumulator_service::block_hashes::{BlockHashes, BLOCK_HASHES_FILE};
+use accumulator_service::builder::{BuildCheckpoint, CHECKPOINT_FILE};
+use accumulator_service::state_machine::{Command, ServiceState};
+use accumulator_service::{forest, Context};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use duckdb::{params, Connection};
+use std::time::Duration;
+
+const ROWS: i64 = 2_000;
+
+async fn wait_while(ctx: &Context, busy: impl Fn(&ServiceState) -> bool) -> ServiceState {
+    let mut state = ctx.status().await.state;
+    for _ in 0..200 {
+        if !busy(&state) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state = ctx.status().await.state;
+    }
+    state
+}
+
+#[tokio::test]
+async fn paused_build_completes_after_resume() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let parquet = dir.join("utxos.parquet").to_string_lossy().into_owned();
+    std::env::remove_var("BITCOIN_CORE_RPC_URL");
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO utxos SELECT repeat('d', 64), 1000, range, 1, '\\x51'::BLOB, false FROM range(?)",
+        params![ROWS],
+    )
+    .unwrap();
+    conn.execute(&format!("COPY utxos TO '{parquet}' (FORMAT 'parquet')"), [])
+        .unwrap();
+    // zero block hashes, so the leaves need no node
+    BlockHashes::new(vec![BlockHash::all_zeros(); 2])
+        .save(&dir.join(BLOCK_HASHES_FILE))
+        .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    let build = Command::Build {
+        parquet,
+        resume_from: None,
+        block_hash: None,
+        validate_sample: None,
+        batch_size: Some(1),
+        keep_op_return: false,
+    };
+    ctx.send(build).await.unwrap();
+    ctx.send(Command::Pause).await.unwrap();
+    assert_eq!(
+        wait_while(&ctx, |s| *s == ServiceState::Building).await,
+        ServiceState::Paused
+    );
+    // the cancelled build wrote nothing
+    assert!(!dir.join("mem_forest.bin").exists());
+
+    ctx.send(Command::Resume).await.unwrap();
+    assert_eq!(
+        wait_while(&ctx, |s| *s != ServiceState::Idle).await,
+        ServiceState::Idle
+    );
+    let bytes = std::fs::read(dir.join("mem_forest.bin")).unwrap();
+    let forest = forest::deserialize_verified(&bytes).unwrap();
+    let checkpoint: BuildCheckpoint =
+        serde_json::from_slice(&std::fs::read(dir.join(CHECKPOINT_FILE)).unwrap()).unwrap();
+    assert_eq!(checkpoint.leaves, ROWS as u64);
+    assert_eq!(forest.leaves, ROWS as u64);
+}
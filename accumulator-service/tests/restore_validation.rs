@@ -0,0 +1,48 @@
+//! Restoring from a corrupt snapshot must fail without touching the working files.
+use accumulator_service::state_machine::{Context, ServiceState};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+
+#[tokio::test]
+async fn corrupt_snapshot_is_not_restored() {
+    let workdir = tempfile::tempdir().unwrap();
+    let dir = workdir.path();
+
+    // working forest with a few leaves
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    let leaves: Vec<_> = (0..5u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+    forest.modify(&leaves, &[]).unwrap();
+    let mut good = Vec::new();
+    forest.serialize(&mut good).unwrap();
+    std::fs::write(dir.join("mem_forest.bin"), &good).unwrap();
+
+    let snapshot_dir = dir.join("snap");
+    std::fs::create_dir(&snapshot_dir).unwrap();
+    let ctx = Context::in_dir(dir);
+
+    // garbage forest, then a valid forest next to a garbage pollard
+    let empty = {
+        let mut buf = Vec::new();
+        MemForest::<BitcoinNodeHash>::new()
+            .serialize(&mut buf)
+            .unwrap();
+        buf
+    };
+    for (forest_bytes, pollard_bytes, bad) in [
+        (b"not a forest".to_vec(), Vec::new(), "mem_forest.bin"),
+        (empty, b"not a pollard".to_vec(), "pollard.bin"),
+    ] {
+        std::fs::write(snapshot_dir.join("mem_forest.bin"), forest_bytes).unwrap();
+        std::fs::write(snapshot_dir.join("pollard.bin"), pollard_bytes).unwrap();
+        let outcome = ctx.restore(&snapshot_dir).await.unwrap().await.unwrap();
+        let err = outcome.unwrap_err().to_string();
+        assert!(err.contains(bad), "{err}");
+
+        match ctx.status().await.state {
+            ServiceState::Error { msg } => assert!(msg.contains(bad), "{msg}"),
+            other => panic!("restore of a corrupt {bad} should fail, got {other:?}"),
+        }
+        assert_eq!(std::fs::read(dir.join("mem_forest.bin")).unwrap(), good);
+        assert!(!dir.join("pollard.bin").exists());
+    }
+}
@@ -0,0 +1,115 @@
+//! GET /roots reports the roots of `pollard.bin` as hex.
+use accumulator_service::block_hashes::{BlockHashes, BLOCK_HASHES_FILE};
+use accumulator_service::builder::start_build;
+use accumulator_service::pollard::{decode, roots_hex};
+use accumulator_service::script_utils::pollard_conv::prune_to;
+use accumulator_service::{api, Context};
+use actix_web::{test, web::Data, App};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde_json::{json, Value};
+use std::fs::File;
+use tokio_util::sync::CancellationToken;
+
+#[actix_rt::test]
+async fn roots_of_pollard_as_hex() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(Context::in_dir(dir)))
+            .configure(api::configure),
+    )
+    .await;
+    let req = test::TestRequest::get().uri("/roots").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    // two trees, the second emptied by deleting its only leaf
+    let mut forest = MemForest::<BitcoinNodeHash>::new();
+    let leaves: Vec<_> = (1..=3u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+    forest.modify(&leaves, &[]).unwrap();
+    forest.modify(&[], &leaves[2..]).unwrap();
+    let pollard = prune_to(&forest, &[]).unwrap();
+    pollard
+        .serialize(&mut File::create(dir.join("pollard.bin")).unwrap())
+        .unwrap();
+
+    let req = test::TestRequest::get().uri("/roots").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body,
+        json!({
+            "leaves": 3,
+            "roots": [
+                "3d69cccb1da0a704005bad797bf7e9f7d1df28d04741df3ca8a7c18a80bf71b3",
+                "00".repeat(32),
+            ],
+        })
+    );
+}
+
+#[actix_rt::test]
+async fn roots_are_served_after_a_build() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let csv = dir.join("utxos.csv");
+    let txid = "11".repeat(32);
+    let script = format!("0014{}", "22".repeat(20));
+    let rows: String = [(1000, 0, 2), (2000, 1, 2), (3000, 2, 3)]
+        .iter()
+        .map(|(amount, vout, height)| format!("{txid},{amount},{vout},{height},{script},false\n"))
+        .collect();
+    std::fs::write(
+        &csv,
+        format!("txid,amount,vout,height,script,coinbase\n{rows}"),
+    )
+    .unwrap();
+    let dump_block = BlockHash::from_byte_array([3; 32]);
+    BlockHashes::new(
+        (0..=3u8)
+            .map(|i| BlockHash::from_byte_array([i; 32]))
+            .collect(),
+    )
+    .save(&dir.join(BLOCK_HASHES_FILE))
+    .unwrap();
+
+    start_build(
+        dir,
+        &csv.to_string_lossy(),
+        None,
+        Some(&dump_block.to_string()),
+        None,
+        None,
+        false,
+        CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    let forest =
+        MemForest::<BitcoinNodeHash>::deserialize(File::open(dir.join("mem_forest.bin")).unwrap())
+            .unwrap();
+    assert_eq!(forest.leaves, 3);
+    let expected = roots_hex(&prune_to(&forest, &[]).unwrap()).unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(Context::in_dir(dir)))
+            .configure(api::configure),
+    )
+    .await;
+    let req = test::TestRequest::get().uri("/roots").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({ "leaves": 3, "roots": expected }));
+
+    let req = test::TestRequest::get().uri("/snapshot").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let (pollard, meta) = decode(&test::read_body(resp).await).unwrap();
+    assert_eq!(roots_hex(&pollard).unwrap(), expected);
+    let meta = meta.unwrap();
+    assert_eq!(meta.block_height, 3);
+    assert_eq!(meta.block_hash, Some(dump_block));
+}
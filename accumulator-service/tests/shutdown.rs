@@ -0,0 +1,70 @@
+//! Integration test: shutting down during a build leaves either no forest or a complete one,
+//! and drops the jobs queued behind the build.
+use accumulator_service::block_hashes::{BlockHashes, BLOCK_HASHES_FILE};
+use accumulator_service::builder::{BuildCheckpoint, CHECKPOINT_FILE};
+use accumulator_service::state_machine::{Command, DispatchError};
+use accumulator_service::{forest, sync_state, Context};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use duckdb::{params, Connection};
+
+#[tokio::test]
+async fn shutdown_during_build_leaves_consistent_files() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let parquet = dir.join("utxos.parquet").to_string_lossy().into_owned();
+    std::env::remove_var("BITCOIN_CORE_RPC_URL");
+
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    for vout in 0..200 {
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params!["c".repeat(64), 1_000, vout, 1, vec![0x51u8], false],
+        )
+        .unwrap();
+    }
+    conn.execute(&format!("COPY utxos TO '{parquet}' (FORMAT 'parquet')"), [])
+        .unwrap();
+    // zero block hashes, so the leaves need no node
+    BlockHashes::new(vec![BlockHash::all_zeros(); 2])
+        .save(&dir.join(BLOCK_HASHES_FILE))
+        .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    ctx.send(Command::Build {
+        parquet,
+        resume_from: None,
+        block_hash: None,
+        validate_sample: None,
+        batch_size: Some(1),
+        keep_op_return: false,
+    })
+    .await
+    .unwrap();
+    ctx.send(Command::Update(5)).await.unwrap();
+    ctx.shutdown().await.unwrap();
+
+    assert!(!ctx.is_alive());
+    assert!(matches!(
+        ctx.send(Command::Update(6)).await,
+        Err(DispatchError::ChannelClosed)
+    ));
+    // the build either stopped before writing or finished; a finished one is complete
+    if dir.join("mem_forest.bin").exists() {
+        let bytes = std::fs::read(dir.join("mem_forest.bin")).unwrap();
+        let forest = forest::deserialize_verified(&bytes).unwrap();
+        let checkpoint: BuildCheckpoint =
+            serde_json::from_slice(&std::fs::read(dir.join(CHECKPOINT_FILE)).unwrap()).unwrap();
+        assert_eq!(checkpoint.leaves, forest.leaves);
+    }
+    // the queued update never ran
+    let synced = sync_state::read(dir)
+        .unwrap()
+        .and_then(|state| state.height);
+    assert_ne!(synced, Some(5));
+}
@@ -0,0 +1,126 @@
+//! GET /snapshot serves `pollard.bin` with a checksum that POST /snapshot on another instance
+//! checks before taking it over.
+use accumulator_service::api::{self, SNAPSHOT_CHECKSUM_HEADER};
+use accumulator_service::pollard::encode_with_meta;
+use accumulator_service::script_utils::pollard_conv::prune_to;
+use accumulator_service::Context;
+use actix_web::{test, web::Data, App};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::BlockHash;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde_json::{json, Value};
+
+#[actix_rt::test]
+async fn served_snapshot_restores_same_roots() {
+    let source_dir = tempfile::tempdir().unwrap();
+    let target_dir = tempfile::tempdir().unwrap();
+
+    let mut forest = MemForest::<BitcoinNodeHash>::new();
+    let leaves: Vec<_> = (1..=11u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+    forest.modify(&leaves, &[]).unwrap();
+    forest.modify(&[], &leaves[3..5]).unwrap();
+    let pollard = prune_to(&forest, &[]).unwrap();
+    let block_hash = BlockHash::from_byte_array([5; 32]);
+    let bytes = encode_with_meta(&pollard, 680_000, Some(block_hash), true).unwrap();
+    std::fs::write(source_dir.path().join("pollard.bin"), &bytes).unwrap();
+
+    let source = test::init_service(
+        App::new()
+            .app_data(Data::new(Context::in_dir(source_dir.path())))
+            .configure(api::configure),
+    )
+    .await;
+    let target = test::init_service(
+        App::new()
+            .app_data(Data::new(Context::in_dir(target_dir.path())))
+            .configure(api::configure),
+    )
+    .await;
+
+    // nothing to serve yet
+    let req = test::TestRequest::get().uri("/snapshot").to_request();
+    assert_eq!(test::call_service(&target, req).await.status(), 404);
+
+    let req = test::TestRequest::get().uri("/snapshot").to_request();
+    let resp = test::call_service(&source, req).await;
+    assert_eq!(resp.status(), 200);
+    let checksum = resp
+        .headers()
+        .get(SNAPSHOT_CHECKSUM_HEADER)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let snapshot = test::read_body(resp).await;
+    assert_eq!(snapshot, bytes);
+    assert_eq!(checksum, sha256::Hash::hash(&snapshot).to_string());
+
+    // a corrupted download is refused
+    let mut corrupted = snapshot.to_vec();
+    *corrupted.last_mut().unwrap() ^= 1;
+    let req = test::TestRequest::post()
+        .uri("/snapshot")
+        .insert_header((SNAPSHOT_CHECKSUM_HEADER, checksum.as_str()))
+        .set_payload(corrupted)
+        .to_request();
+    assert_eq!(test::call_service(&target, req).await.status(), 400);
+    assert!(!target_dir.path().join("pollard.bin").exists());
+
+    let req = test::TestRequest::post()
+        .uri("/snapshot")
+        .insert_header((SNAPSHOT_CHECKSUM_HEADER, checksum.as_str()))
+        .set_payload(snapshot)
+        .to_request();
+    assert_eq!(test::call_service(&target, req).await.status(), 201);
+
+    let req = test::TestRequest::get().uri("/roots").to_request();
+    let source_roots: Value = test::call_and_read_body_json(&source, req).await;
+    let req = test::TestRequest::get().uri("/roots").to_request();
+    let target_roots: Value = test::call_and_read_body_json(&target, req).await;
+    assert_eq!(target_roots, source_roots);
+    assert_eq!(target_roots["leaves"], 11);
+
+    let req = test::TestRequest::get().uri("/height").to_request();
+    let height: Value = test::call_and_read_body_json(&target, req).await;
+    assert_eq!(
+        height,
+        json!({ "height": 680_000, "block_hash": block_hash, "keep_op_return": true })
+    );
+}
+
+#[actix_rt::test]
+async fn hostile_snapshot_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(Context::in_dir(dir.path())))
+            .configure(api::configure),
+    )
+    .await;
+    let post = |body: Vec<u8>| {
+        test::TestRequest::post()
+            .uri("/snapshot")
+            .insert_header((
+                SNAPSHOT_CHECKSUM_HEADER,
+                sha256::Hash::hash(&body).to_string(),
+            ))
+            .set_payload(body)
+            .to_request()
+    };
+
+    // one leaf whose root claims thousands of levels of branches, which a recursive decoder
+    // would follow until the stack ran out
+    let mut nested = 1u64.to_be_bytes().to_vec();
+    nested.push(1);
+    for _ in 0..10_000 {
+        nested.extend([0, 0]);
+    }
+    let resp = test::call_service(&app, post(nested)).await;
+    assert_eq!(resp.status(), 400);
+
+    let oversized = vec![0; api::SNAPSHOT_REQUEST_LIMIT + 1];
+    let resp = test::call_service(&app, post(oversized)).await;
+    assert_eq!(resp.status(), 413);
+    assert!(!dir.path().join("pollard.bin").exists());
+}
@@ -0,0 +1,51 @@
+//! GET /height reports the block the forest was last updated to.
+use accumulator_service::{api, Context};
+use actix_web::{test, web::Data, App};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::time::Duration;
+
+#[actix_rt::test]
+async fn height_follows_update() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    // no RPC: the update applies no deletions and records no block hash
+    std::env::remove_var("BITCOIN_CORE_RPC_URL");
+
+    let forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    let mut f = File::create(dir.join("mem_forest.bin")).unwrap();
+    forest.serialize(&mut f).unwrap();
+
+    let ctx = Context::in_dir(dir);
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(ctx.clone()))
+            .configure(api::configure),
+    )
+    .await;
+
+    // nothing synced yet
+    let req = test::TestRequest::get().uri("/height").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+
+    let req = test::TestRequest::post()
+        .uri("/update")
+        .set_json(json!({ "height": 42 }))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 202);
+
+    for _ in 0..40 {
+        if dir.join("sync_state.json").exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let req = test::TestRequest::get().uri("/height").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({ "height": 42, "block_hash": null }));
+}
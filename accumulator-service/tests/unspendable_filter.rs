@@ -0,0 +1,284 @@
+//! All leaf-building paths must agree on which outputs become leaves: an OP_RETURN output and
+//! an oversized script are skipped, a normal output is kept. With `keep_op_return`, the
+//! OP_RETURN output is kept too. The paths must also agree on the leaf hashes themselves, so
+//! a forest built from a dump has the same roots as one built block by block.
+
+use accumulator_service::block_hashes::BlockHashes;
+use accumulator_service::script_utils::btc_rpc::{
+    block_output_leaves, get_block_leaf_hashes_with, BitcoinRpc, MissingPrevout, TxOutInfo,
+};
+use accumulator_service::script_utils::parquet::{
+    for_each_leaf_batch_cancellable, get_all_leaf_hashes,
+};
+use anyhow::{anyhow, Result};
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute, transaction, Amount, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness,
+};
+use duckdb::{params, Connection};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+use utreexo::{process_block_changes, ProcessOptions, MAX_SCRIPT_SIZE};
+
+/// Height the block creating the outputs is mined at.
+const HEIGHT: u32 = 2;
+
+/// OP_RETURN with data, a script over the consensus size limit, and a plain P2WPKH-like script.
+fn scripts() -> Vec<Vec<u8>> {
+    let mut op_return = vec![0x6a, 0x04];
+    op_return.extend_from_slice(b"data");
+    let oversized = vec![0x51; MAX_SCRIPT_SIZE + 1];
+    let mut normal = vec![0x00, 0x14];
+    normal.extend_from_slice(&[0xab; 20]);
+    vec![op_return, oversized, normal]
+}
+
+fn tx(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: transaction::Version::ONE,
+        lock_time: absolute::LockTime::ZERO,
+        input,
+        output,
+    }
+}
+
+fn txin(previous_output: OutPoint) -> TxIn {
+    TxIn {
+        previous_output,
+        script_sig: ScriptBuf::from_bytes(vec![0x51]),
+        sequence: Sequence::MAX,
+        witness: Witness::new(),
+    }
+}
+
+/// Blocks only differ in their transactions, so they all share one hash.
+fn block(txdata: Vec<Transaction>) -> Block {
+    Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata,
+    }
+}
+
+/// The UTXO the creating transaction spends, and its leaf.
+fn funding() -> (OutPoint, BitcoinNodeHash) {
+    (
+        OutPoint::new(Txid::from_byte_array([7; 32]), 0),
+        BitcoinNodeHash::new([9; 32]),
+    )
+}
+
+/// A non-coinbase transaction creating one output per script in [`scripts`].
+fn creator() -> Transaction {
+    let outputs = scripts()
+        .into_iter()
+        .map(|script| TxOut {
+            value: Amount::from_sat(1_000),
+            script_pubkey: ScriptBuf::from_bytes(script),
+        })
+        .collect();
+    tx(vec![txin(funding().0)], outputs)
+}
+
+fn coinbase() -> Transaction {
+    tx(vec![txin(OutPoint::null())], vec![])
+}
+
+fn options(keep_op_return: bool) -> ProcessOptions {
+    ProcessOptions {
+        keep_op_return,
+        ..Default::default()
+    }
+}
+
+fn roots(forest: &MemForest<BitcoinNodeHash>) -> Vec<BitcoinNodeHash> {
+    forest.get_roots().iter().map(|r| r.get_data()).collect()
+}
+
+/// The forest holding the funding leaf, before the creating block.
+fn funded_forest() -> MemForest<BitcoinNodeHash> {
+    let mut forest = MemForest::new();
+    forest.modify(&[funding().1], &[]).unwrap();
+    forest
+}
+
+/// Apply the creating block with the circuit's block processing: the leaves it added and
+/// the roots after it.
+fn processed(keep_op_return: bool) -> (Vec<BitcoinNodeHash>, Vec<BitcoinNodeHash>) {
+    let creator = creator();
+    let input_leaves = BTreeMap::from([(creator.input[0].clone(), funding().1)]);
+    let mut forest = funded_forest();
+    let changes = process_block_changes(
+        &block(vec![coinbase(), creator]),
+        HEIGHT,
+        &mut forest,
+        input_leaves,
+        options(keep_op_return),
+    )
+    .unwrap();
+    (changes.added, roots(&forest))
+}
+
+#[test]
+fn process_block_skips_unspendable_outputs() {
+    assert_eq!(processed(false).0.len(), 1);
+    assert_eq!(processed(true).0.len(), 2);
+}
+
+/// Dump the creating transaction's outputs as they'd appear in a UTXO set export.
+fn write_dump(path: &Path) {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(
+        "CREATE TABLE utxos (txid VARCHAR, amount BIGINT, vout INTEGER, height BIGINT, script BLOB, coinbase BOOLEAN)",
+        [],
+    )
+    .unwrap();
+    let txid = creator().compute_txid().to_string();
+    for (vout, script) in scripts().into_iter().enumerate() {
+        conn.execute(
+            "INSERT INTO utxos VALUES (?, ?, ?, ?, ?, ?)",
+            params![txid, 1_000i64, vout as i32, HEIGHT as i64, script, false],
+        )
+        .unwrap();
+    }
+    conn.execute(
+        &format!(
+            "COPY utxos TO '{}' (FORMAT 'parquet')",
+            path.to_string_lossy()
+        ),
+        [],
+    )
+    .unwrap();
+}
+
+#[test]
+fn parquet_extraction_matches_process_block() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("utxos.parquet");
+    write_dump(&path);
+    let mut hashes = vec![BlockHash::all_zeros(); HEIGHT as usize];
+    hashes.push(block(vec![]).block_hash());
+    let block_hashes = BlockHashes::new(hashes);
+
+    for keep_op_return in [false, true] {
+        let leaves = if keep_op_return {
+            let mut kept = Vec::new();
+            for_each_leaf_batch_cancellable(
+                &path,
+                Some(&block_hashes),
+                true,
+                10,
+                &CancellationToken::new(),
+                |batch| {
+                    kept.extend_from_slice(batch);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            kept
+        } else {
+            get_all_leaf_hashes(&path, Some(&block_hashes)).unwrap()
+        };
+        let (added, expected_roots) = processed(keep_op_return);
+        assert_eq!(leaves, added, "keep_op_return = {keep_op_return}");
+
+        // the builder's forest, advanced past the funding spend, has the same roots
+        let mut forest = funded_forest();
+        forest.modify(&leaves, &[funding().1]).unwrap();
+        assert_eq!(roots(&forest), expected_roots);
+    }
+}
+
+#[test]
+fn verify_update_additions_match_process_block() {
+    let block = block(vec![coinbase(), creator()]);
+    for keep_op_return in [false, true] {
+        let adds = block_output_leaves(&block, HEIGHT, keep_op_return).unwrap();
+        assert_eq!(adds, processed(keep_op_return).0);
+    }
+}
+
+/// Serves a single block, backing `get_txout` with the outputs it spends, all created in
+/// `creating`.
+struct MockRpc {
+    block: Block,
+    creating: BlockHash,
+    prevouts: HashMap<OutPoint, (u64, Vec<u8>)>,
+}
+
+impl BitcoinRpc for MockRpc {
+    fn get_block_hash(&self, _height: u64) -> Result<BlockHash> {
+        Ok(self.block.block_hash())
+    }
+    fn get_block(&self, _hash: &BlockHash) -> Result<Block> {
+        Ok(self.block.clone())
+    }
+    fn get_txout(&self, prevout: &OutPoint) -> Result<TxOutInfo> {
+        let (value, script) = self
+            .prevouts
+            .get(prevout)
+            .cloned()
+            .ok_or_else(|| anyhow!("unknown prevout {prevout}"))?;
+        Ok(TxOutInfo {
+            value,
+            script,
+            block_hash: self.creating,
+            is_coinbase: false,
+        })
+    }
+    fn get_block_height(&self, hash: &BlockHash) -> Result<u32> {
+        if *hash == self.creating {
+            Ok(HEIGHT)
+        } else {
+            Err(anyhow!("unknown block {hash}"))
+        }
+    }
+}
+
+#[test]
+fn rpc_leaf_hashes_match_process_block() {
+    // The next block spends every output of the creating one. A spent leaf commits to the
+    // block and height that created it, so the hashes are the ones that block added.
+    let creator = creator();
+    let txid = creator.compute_txid();
+    let creating = block(vec![coinbase(), creator]);
+    let spends: Vec<_> = (0..scripts().len() as u32)
+        .map(|vout| OutPoint { txid, vout })
+        .collect();
+    let prevouts = spends
+        .iter()
+        .zip(scripts())
+        .map(|(outpoint, script)| (*outpoint, (1_000, script)))
+        .collect();
+    let inputs = spends.iter().map(|outpoint| txin(*outpoint)).collect();
+    let mut spending = block(vec![coinbase(), tx(inputs, vec![])]);
+    spending.header.prev_blockhash = creating.block_hash();
+    let rpc = MockRpc {
+        block: spending,
+        creating: creating.block_hash(),
+        prevouts,
+    };
+
+    for keep_op_return in [false, true] {
+        let leaves = get_block_leaf_hashes_with(
+            &rpc,
+            u64::from(HEIGHT) + 1,
+            MissingPrevout::Fail,
+            keep_op_return,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(leaves.hashes, processed(keep_op_return).0);
+    }
+}
@@ -0,0 +1,103 @@
+//! Integration test: an update cancelled while it is still fetching prevouts writes nothing.
+use accumulator_service::delta::DELTA_FILE;
+use accumulator_service::script_utils::btc_rpc::{BitcoinRpc, MissingPrevout, TxOutInfo};
+use accumulator_service::{sync_state, updater};
+use anyhow::Result;
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute, transaction, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, Txid, Witness,
+};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::cell::Cell;
+use std::fs::File;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+fn tx(previous_output: OutPoint) -> Transaction {
+    Transaction {
+        version: transaction::Version::ONE,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            script_sig: ScriptBuf::from_bytes(vec![0x51]),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![],
+    }
+}
+
+/// A coinbase followed by two transactions, each spending one output.
+fn block() -> Block {
+    let spend = |i: u8| tx(OutPoint::new(Txid::from_byte_array([i; 32]), 0));
+    Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: vec![tx(OutPoint::null()), spend(1), spend(2)],
+    }
+}
+
+/// Answers slowly, and the stop request arrives while the first prevout is being fetched.
+struct SlowRpc {
+    cancel: CancellationToken,
+    txouts: Cell<usize>,
+}
+
+impl BitcoinRpc for SlowRpc {
+    fn get_block_hash(&self, _height: u64) -> Result<BlockHash> {
+        Ok(BlockHash::from_byte_array([7; 32]))
+    }
+    fn get_block(&self, _hash: &BlockHash) -> Result<Block> {
+        Ok(block())
+    }
+    fn get_txout(&self, _prevout: &OutPoint) -> Result<TxOutInfo> {
+        self.txouts.set(self.txouts.get() + 1);
+        std::thread::sleep(Duration::from_millis(50));
+        self.cancel.cancel();
+        Ok(TxOutInfo {
+            value: 1_000,
+            script: vec![0x51],
+            block_hash: BlockHash::from_byte_array([6; 32]),
+            is_coinbase: false,
+        })
+    }
+    fn get_block_height(&self, _hash: &BlockHash) -> Result<u32> {
+        Ok(7)
+    }
+}
+
+#[test]
+fn cancelled_update_leaves_forest_untouched() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    let leaves: Vec<_> = (0..3u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+    forest.modify(&leaves, &[]).unwrap();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+    let before = std::fs::read(dir.join("mem_forest.bin")).unwrap();
+
+    let cancel = CancellationToken::new();
+    let rpc = SlowRpc {
+        cancel: cancel.clone(),
+        txouts: Cell::new(0),
+    };
+    updater::update_block_with(dir, Some(&rpc), 7, MissingPrevout::Fail, &cancel).unwrap();
+
+    // stopped before the second transaction's prevout was fetched
+    assert_eq!(rpc.txouts.get(), 1);
+    assert_eq!(std::fs::read(dir.join("mem_forest.bin")).unwrap(), before);
+    assert!(!dir.join(DELTA_FILE).exists());
+    assert!(!dir.join("pollard.bin").exists());
+    assert_eq!(sync_state::read(dir).unwrap(), None);
+}
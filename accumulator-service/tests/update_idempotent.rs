@@ -0,0 +1,150 @@
+//! Integration test: re-applying an already applied height is a successful no-op, also when
+//! a crash kept the sync state from recording it.
+use accumulator_service::delta::{self, DELTA_FILE};
+use accumulator_service::script_utils::btc_rpc::{
+    get_block_leaf_hashes_with, BitcoinRpc, MissingPrevout, TxOutInfo,
+};
+use accumulator_service::sync_state::{self, SyncState};
+use accumulator_service::updater;
+use anyhow::Result;
+use bitcoin::block::{Header, Version};
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute, transaction, Block, BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence,
+    Transaction, TxIn, TxMerkleNode, Txid, Witness,
+};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::fs::File;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+
+#[test]
+fn same_height_twice_is_applied_once() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    let leaves: Vec<_> = (0..3u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+    forest.modify(&leaves, &[]).unwrap();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+
+    updater::update_block_sync(dir, 7, CancellationToken::new()).unwrap();
+    let forest_after = std::fs::read(dir.join("mem_forest.bin")).unwrap();
+    let pollard_after = std::fs::read(dir.join("pollard.bin")).unwrap();
+
+    // a client retry of the same block, and a stale lower one
+    updater::update_block_sync(dir, 7, CancellationToken::new()).unwrap();
+    updater::update_block_sync(dir, 6, CancellationToken::new()).unwrap();
+
+    let logged = delta::read_all(&dir.join(DELTA_FILE)).unwrap();
+    assert_eq!(logged.len(), 1);
+    assert_eq!(logged[0].height, 7);
+    assert_eq!(
+        std::fs::read(dir.join("mem_forest.bin")).unwrap(),
+        forest_after
+    );
+    assert_eq!(
+        std::fs::read(dir.join("pollard.bin")).unwrap(),
+        pollard_after
+    );
+    assert_eq!(sync_state::read(dir).unwrap().unwrap().height, Some(7));
+}
+
+fn outpoint(i: u8) -> OutPoint {
+    OutPoint::new(Txid::from_byte_array([i; 32]), 0)
+}
+
+/// Block 7: a coinbase and one transaction spending `outpoint(1)`, created at height 2.
+struct MockRpc;
+
+impl BitcoinRpc for MockRpc {
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash> {
+        Ok(BlockHash::from_byte_array([height as u8; 32]))
+    }
+    fn get_block(&self, _hash: &BlockHash) -> Result<Block> {
+        let tx = |previous_output| Transaction {
+            version: transaction::Version::ONE,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: ScriptBuf::from_bytes(vec![0x51]),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![],
+        };
+        Ok(Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![tx(OutPoint::null()), tx(outpoint(1))],
+        })
+    }
+    fn get_txout(&self, _prevout: &OutPoint) -> Result<TxOutInfo> {
+        Ok(TxOutInfo {
+            value: 1_000,
+            script: vec![0x51],
+            block_hash: BlockHash::from_byte_array([2; 32]),
+            is_coinbase: false,
+        })
+    }
+    fn get_block_height(&self, _hash: &BlockHash) -> Result<u32> {
+        Ok(2)
+    }
+}
+
+fn synced_to(dir: &Path, height: u64) {
+    let state = SyncState {
+        height: Some(height),
+        block_hash: None,
+        keep_op_return: false,
+        unresolved_prevouts: 0,
+    };
+    sync_state::write(dir, &state).unwrap();
+}
+
+#[test]
+fn block_logged_before_a_crash_is_not_applied_again() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let cancel = CancellationToken::new();
+    let spent = get_block_leaf_hashes_with(&MockRpc, 7, MissingPrevout::Fail, false, &cancel)
+        .unwrap()
+        .hashes;
+    let mut forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    forest
+        .modify(&[BitcoinNodeHash::new([9; 32]), spent[0]], &[])
+        .unwrap();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+    synced_to(dir, 6);
+
+    // blocks must be applied in order
+    let err = updater::update_block_with(dir, Some(&MockRpc), 8, MissingPrevout::Fail, &cancel)
+        .unwrap_err();
+    assert!(err.to_string().contains("synced to 6"), "{err:#}");
+
+    updater::update_block_with(dir, Some(&MockRpc), 7, MissingPrevout::Fail, &cancel).unwrap();
+    let pollard_after = std::fs::read(dir.join("pollard.bin")).unwrap();
+
+    // the process died after logging the block, before pollard.bin and sync_state.json
+    std::fs::remove_file(dir.join("pollard.bin")).unwrap();
+    synced_to(dir, 6);
+
+    // the retry must not delete the spent leaf a second time
+    updater::update_block_with(dir, Some(&MockRpc), 7, MissingPrevout::Fail, &cancel).unwrap();
+    assert_eq!(delta::read_all(&dir.join(DELTA_FILE)).unwrap().len(), 1);
+    assert_eq!(
+        std::fs::read(dir.join("pollard.bin")).unwrap(),
+        pollard_after
+    );
+    assert_eq!(sync_state::read(dir).unwrap().unwrap().height, Some(7));
+}
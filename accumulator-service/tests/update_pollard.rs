@@ -5,24 +5,23 @@ use rustreexo::accumulator::mem_forest::MemForest;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
 use serde_json::json;
 use std::fs::File;
-use std::path::Path;
 use std::time::Duration;
 #[actix_rt::test]
 async fn update_generates_pollard_bin() {
     // isolate in temp dir
     let tmp = tempfile::tempdir().unwrap();
-    std::env::set_current_dir(tmp.path()).unwrap();
+    let dir = tmp.path();
 
     // prepare minimal mem_forest.bin (empty forest)
     let forest: MemForest<BitcoinNodeHash> = MemForest::new();
-    let mut f = File::create("mem_forest.bin").unwrap();
+    let mut f = File::create(dir.join("mem_forest.bin")).unwrap();
     forest.serialize(&mut f).unwrap();
 
     // ensure no pollard.bin present
-    assert!(!Path::new("pollard.bin").exists());
+    assert!(!dir.join("pollard.bin").exists());
 
     // start service
-    let ctx = Context::new();
+    let ctx = Context::in_dir(dir);
     let app = test::init_service(
         App::new()
             .app_data(Data::new(ctx.clone()))
@@ -40,12 +39,12 @@ async fn update_generates_pollard_bin() {
 
     // wait for pollard.bin to be written
     for _ in 0..20 {
-        if Path::new("pollard.bin").exists() {
+        if dir.join("pollard.bin").exists() {
             break;
         }
         tokio::time::sleep(Duration::from_millis(50)).await;
     }
 
     // pollard.bin should now exist
-    assert!(Path::new("pollard.bin").exists(), "pollard.bin not created");
+    assert!(dir.join("pollard.bin").exists(), "pollard.bin not created");
 }
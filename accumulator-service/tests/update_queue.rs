@@ -0,0 +1,45 @@
+//! Integration test: updates sent while one is running queue up and run in order.
+use accumulator_service::delta::{self, DELTA_FILE};
+use accumulator_service::state_machine::{Command, ServiceState};
+use accumulator_service::sync_state;
+use accumulator_service::Context;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use std::fs::File;
+use std::time::Duration;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn queued_updates_run_sequentially() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let forest: MemForest<BitcoinNodeHash> = MemForest::new();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+
+    let ctx = Context::in_dir(dir);
+    // the second update is accepted whether or not the first is still running
+    ctx.send(Command::Update(1)).await.unwrap();
+    ctx.send(Command::Update(2)).await.unwrap();
+
+    let synced_to = || sync_state::read(dir).unwrap().and_then(|s| s.height);
+    let mut state = ctx.status().await.state;
+    for _ in 0..100 {
+        if matches!(state, ServiceState::Error { .. })
+            || (state == ServiceState::Idle && synced_to() == Some(2))
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state = ctx.status().await.state;
+    }
+    assert_eq!(state, ServiceState::Idle);
+
+    // both blocks were applied, in the order they were sent
+    let logged: Vec<u64> = delta::read_all(&dir.join(DELTA_FILE))
+        .unwrap()
+        .iter()
+        .map(|record| record.height)
+        .collect();
+    assert_eq!(logged, vec![1, 2]);
+}
@@ -0,0 +1,58 @@
+//! Integration test: POST /verify compares pollard.bin with mem_forest.bin without touching them.
+use accumulator_service::script_utils::pollard_conv::prune_to;
+use accumulator_service::{api, Context};
+use actix_web::{test, web::Data, App};
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde_json::Value;
+use std::fs::File;
+use std::path::Path;
+
+fn write_pollard(dir: &Path, forest: &MemForest<BitcoinNodeHash>) {
+    let pollard = prune_to(forest, &[]).unwrap();
+    pollard
+        .serialize(&mut File::create(dir.join("pollard.bin")).unwrap())
+        .unwrap();
+}
+
+#[actix_rt::test]
+async fn verify_reports_matching_and_tampered_pollard() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+
+    let mut leaves: Vec<_> = (0..7u8).map(|i| BitcoinNodeHash::new([i; 32])).collect();
+    let mut forest = MemForest::<BitcoinNodeHash>::new();
+    forest.modify(&leaves, &[]).unwrap();
+    forest
+        .serialize(&mut File::create(dir.join("mem_forest.bin")).unwrap())
+        .unwrap();
+    write_pollard(dir, &forest);
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(Context::in_dir(dir)))
+            .configure(api::configure),
+    )
+    .await;
+    let verify = || test::TestRequest::post().uri("/verify").to_request();
+
+    let resp: Value = test::call_and_read_body_json(&app, verify()).await;
+    assert_eq!(resp["consistent"], true);
+    assert_eq!(resp["forest_leaves"], 7);
+    assert_eq!(resp["pollard_leaves"], 7);
+
+    // a pollard for a forest with one different leaf: only that leaf's root differs
+    leaves[6] = BitcoinNodeHash::new([0xff; 32]);
+    let mut other = MemForest::<BitcoinNodeHash>::new();
+    other.modify(&leaves, &[]).unwrap();
+    write_pollard(dir, &other);
+    let forest_before = std::fs::read(dir.join("mem_forest.bin")).unwrap();
+
+    let resp: Value = test::call_and_read_body_json(&app, verify()).await;
+    assert_eq!(resp["consistent"], false);
+    assert_eq!(resp["differing_roots"].as_array().unwrap().len(), 1);
+    assert_eq!(
+        std::fs::read(dir.join("mem_forest.bin")).unwrap(),
+        forest_before
+    );
+}
@@ -0,0 +1,114 @@
+//! Throughput of the hashes the accumulator is built from, under whichever sha2 backend the
+//! crate was built with. Compare backends by running it once per feature:
+//!
+//! ```text
+//! cargo bench -p utreexo --bench hash_backend
+//! cargo bench -p utreexo --bench hash_backend --features sha2-asm
+//! cargo bench -p utreexo --bench hash_backend --features sha2-soft
+//! ```
+use std::time::Duration;
+use std::time::Instant;
+
+use bitcoin::hashes::Hash;
+use bitcoin::Amount;
+use bitcoin::BlockHash;
+use bitcoin::OutPoint;
+use bitcoin::ScriptBuf;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use rustreexo::accumulator::node_hash::AccumulatorHash;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use sha2::Digest;
+use sha2::Sha512_256;
+use utreexo::LeafData;
+
+const HASHES: u32 = 1_000_000;
+const RUNS: u32 = 5;
+
+fn backend() -> &'static str {
+    if cfg!(feature = "sha2-asm") {
+        "sha2-asm"
+    } else if cfg!(feature = "sha2-soft") {
+        "sha2-soft"
+    } else {
+        "runtime detection"
+    }
+}
+
+fn node(i: u32) -> BitcoinNodeHash {
+    let mut bytes = [0u8; 32];
+    bytes[..4].copy_from_slice(&i.to_le_bytes());
+    bytes[31] = 1;
+    BitcoinNodeHash::new(bytes)
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        f();
+    }
+    start.elapsed() / RUNS
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "  {name:<26} {elapsed:>12?}  {:>6.2} Mhash/s",
+        f64::from(HASHES) / elapsed.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    let nodes: Vec<_> = (0..=HASHES)
+        .map(node)
+        .collect();
+    let leaves: Vec<_> = (0..HASHES)
+        .map(|i| LeafData {
+            block_hash: BlockHash::from_byte_array([1; 32]),
+            prevout: OutPoint {
+                txid: Txid::from_byte_array(*node(i)),
+                vout: i % 4,
+            },
+            header_code: 800_000 << 1,
+            utxo: TxOut {
+                value: Amount::from_sat(u64::from(i)),
+                script_pubkey: ScriptBuf::from_bytes(vec![0x00; 22]),
+            },
+        })
+        .collect();
+
+    // parent hashing as the accumulator does it, through sha2 (this backend) and through
+    // rustreexo's parent_hash (bitcoin_hashes, the same everywhere)
+    let sha2_parent = time(|| {
+        for pair in nodes.windows(2) {
+            let parent = Sha512_256::new()
+                .chain_update(*pair[0])
+                .chain_update(*pair[1])
+                .finalize();
+            std::hint::black_box(parent);
+        }
+    });
+    let rustreexo_parent = time(|| {
+        for pair in nodes.windows(2) {
+            std::hint::black_box(BitcoinNodeHash::parent_hash(
+                &pair[0], &pair[1],
+            ));
+        }
+    });
+    let mut scratch = Vec::new();
+    let leaf = time(|| {
+        for leaf in &leaves {
+            std::hint::black_box(leaf.get_leaf_hashes_into(&mut scratch));
+        }
+    });
+
+    println!(
+        "{HASHES} hashes, mean of {RUNS} runs, sha2 backend: {}",
+        backend()
+    );
+    report("parent hash (sha2)", sha2_parent);
+    report(
+        "parent hash (rustreexo)",
+        rustreexo_parent,
+    );
+    report("leaf hash (sha2)", leaf);
+}
@@ -0,0 +1,99 @@
+//! Heap allocations and time of hashing many leaves in a row, with a fresh encoding buffer per
+//! leaf (`get_leaf_hashes`) and with one reused buffer (`get_leaf_hashes_into`):
+//!
+//! ```text
+//! cargo bench -p utreexo --bench leaf_hash_alloc
+//! ```
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use bitcoin::hashes::Hash;
+use bitcoin::Amount;
+use bitcoin::BlockHash;
+use bitcoin::OutPoint;
+use bitcoin::ScriptBuf;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use utreexo::LeafData;
+
+const LEAVES: u32 = 1_000_000;
+
+/// Counts every allocation made through the global allocator.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Allocations made and time taken by `f`.
+fn measure<F: FnMut()>(mut f: F) -> (usize, Duration) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    (
+        ALLOCATIONS.load(Ordering::Relaxed) - before,
+        elapsed,
+    )
+}
+
+fn report(name: &str, (allocations, elapsed): (usize, Duration)) {
+    println!(
+        "  {name:<22} {allocations:>10} allocations  {elapsed:>12?}  {:>6.2} Mleaf/s",
+        f64::from(LEAVES) / elapsed.as_secs_f64() / 1e6
+    );
+}
+
+fn main() {
+    let leaves: Vec<_> = (0..LEAVES)
+        .map(|i| {
+            let mut txid = [0u8; 32];
+            txid[..4].copy_from_slice(&i.to_le_bytes());
+            LeafData {
+                block_hash: BlockHash::from_byte_array([1; 32]),
+                prevout: OutPoint {
+                    txid: Txid::from_byte_array(txid),
+                    vout: i % 4,
+                },
+                header_code: 800_000 << 1,
+                utxo: TxOut {
+                    value: Amount::from_sat(u64::from(i)),
+                    script_pubkey: ScriptBuf::from_bytes(vec![0x00; 22]),
+                },
+            }
+        })
+        .collect();
+
+    let fresh = measure(|| {
+        for leaf in &leaves {
+            std::hint::black_box(leaf.get_leaf_hashes());
+        }
+    });
+    let reused = measure(|| {
+        let mut scratch = Vec::new();
+        for leaf in &leaves {
+            std::hint::black_box(leaf.get_leaf_hashes_into(&mut scratch));
+        }
+    });
+
+    println!("{LEAVES} leaves");
+    report("get_leaf_hashes", fresh);
+    report("get_leaf_hashes_into", reused);
+}
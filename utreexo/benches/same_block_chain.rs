@@ -0,0 +1,121 @@
+//! Time `process_block` over blocks of chained same-block spends, each transaction spending the
+//! only output of the one before it, at several chain lengths. Every spend looks up a leaf the
+//! block itself created, so the time per spend should stay roughly flat as the chain grows:
+//!
+//! ```text
+//! cargo bench -p utreexo --bench same_block_chain
+//! ```
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use bitcoin::absolute;
+use bitcoin::block::Header;
+use bitcoin::block::Version;
+use bitcoin::hashes::Hash;
+use bitcoin::transaction;
+use bitcoin::Amount;
+use bitcoin::Block;
+use bitcoin::BlockHash;
+use bitcoin::CompactTarget;
+use bitcoin::OutPoint;
+use bitcoin::ScriptBuf;
+use bitcoin::Sequence;
+use bitcoin::Transaction;
+use bitcoin::TxIn;
+use bitcoin::TxMerkleNode;
+use bitcoin::TxOut;
+use bitcoin::Witness;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use utreexo::header_code;
+use utreexo::process_block;
+use utreexo::LeafData;
+
+const HEIGHT: u32 = 1;
+const CHAINS: [usize; 5] = [1_000, 2_000, 4_000, 8_000, 16_000];
+const RUNS: u32 = 5;
+
+fn tx(previous_output: OutPoint) -> Transaction {
+    Transaction {
+        version: transaction::Version::ONE,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            script_sig: ScriptBuf::from_bytes(vec![0x51]),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::from_sat(1_000),
+            script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+        }],
+    }
+}
+
+/// A coinbase followed by `spends` transactions, each spending the output of the one before,
+/// and the leaf hash of every input.
+fn chained_block(spends: usize) -> (Block, BTreeMap<TxIn, BitcoinNodeHash>) {
+    let mut block = Block {
+        header: Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        },
+        txdata: vec![tx(OutPoint::null())],
+    };
+    let mut input_leaves = BTreeMap::new();
+    for _ in 0..spends {
+        let prev = block.txdata.last().unwrap();
+        let spend = tx(OutPoint {
+            txid: prev.compute_txid(),
+            vout: 0,
+        });
+        let leaf = LeafData {
+            block_hash: block.block_hash(),
+            prevout: spend.input[0].previous_output,
+            header_code: header_code(HEIGHT, prev.is_coinbase()).unwrap(),
+            utxo: prev.output[0].clone(),
+        }
+        .get_leaf_hashes();
+        input_leaves.insert(spend.input[0].clone(), leaf);
+        block.txdata.push(spend);
+    }
+    (block, input_leaves)
+}
+
+fn main() {
+    println!("chained same-block spends, mean of {RUNS} runs");
+    let mut previous: Option<Duration> = None;
+    for spends in CHAINS {
+        let (block, input_leaves) = chained_block(spends);
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..RUNS {
+            let mut acc = MemForest::<BitcoinNodeHash>::new();
+            let input_leaves = input_leaves.clone();
+            let start = Instant::now();
+            process_block(&block, HEIGHT, &mut acc, input_leaves).unwrap();
+            elapsed += start.elapsed();
+            // only the last output of the chain is left
+            assert_eq!(acc.leaves, 1);
+        }
+        let mean = elapsed / RUNS;
+        let growth = previous
+            .map(|p| {
+                format!(
+                    "{:.2}x",
+                    mean.as_secs_f64() / p.as_secs_f64()
+                )
+            })
+            .unwrap_or_default();
+        println!(
+            "  {spends:>6} spends  {mean:>12?}  {:>8.0} ns/spend  {growth:>6}",
+            mean.as_nanos() as f64 / spends as f64
+        );
+        previous = Some(mean);
+    }
+    println!("each row doubles the chain; near-linear time shows as growth close to 2x");
+}
@@ -4,12 +4,18 @@
 use bitcoin::consensus::Encodable;
 use bitcoin::BlockHash;
 use bitcoin::OutPoint;
+use bitcoin::Script;
+use bitcoin::Transaction;
 use bitcoin::TxOut;
+use bitcoin::Txid;
 use bitcoin::VarInt;
+use bitcoin_hashes::Hash;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use rustreexo::accumulator::proof::Proof;
 use serde::Deserialize;
 use serde::Serialize;
 use sha2::Digest;
+use sha2::Sha256;
 use sha2::Sha512_256;
 
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
@@ -26,14 +32,95 @@ pub enum ScriptPubkeyType {
     WitnessV0ScriptHash,
 }
 
-#[derive(PartialEq, Eq, Clone, Debug, Default)]
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct BatchProof {
     /// All targets that'll be deleted
+    #[serde(with = "var_int_vec")]
     pub targets: Vec<VarInt>,
     /// The inner hashes of a proof
     pub hashes: Vec<BlockHash>,
 }
 
+impl BatchProof {
+    /// Number of hashes in the proof. Unlike [`BatchProof::is_empty`], this doesn't count
+    /// targets.
+    pub fn hash_count(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Whether the proof proves nothing, i.e. has no targets. A proof with targets but no
+    /// hashes (every target is a root) is not empty.
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Number of leaves the proof proves.
+    pub fn target_count(&self) -> usize {
+        self.targets.len()
+    }
+}
+
+/// `VarInt` has no serde impls, so targets are (de)serialized as plain `u64`s.
+mod var_int_vec {
+    use bitcoin::VarInt;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(targets: &[VarInt], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            targets
+                .iter()
+                .map(|target| target.0),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<VarInt>, D::Error> {
+        let targets = Vec::<u64>::deserialize(deserializer)?;
+        Ok(targets
+            .into_iter()
+            .map(VarInt)
+            .collect())
+    }
+}
+
+impl From<Proof<BitcoinNodeHash>> for BatchProof {
+    fn from(proof: Proof<BitcoinNodeHash>) -> Self {
+        BatchProof {
+            targets: proof
+                .targets
+                .into_iter()
+                .map(VarInt)
+                .collect(),
+            hashes: proof
+                .hashes
+                .iter()
+                .map(|hash| BlockHash::from_byte_array(**hash))
+                .collect(),
+        }
+    }
+}
+
+/// Going back to a rustreexo [Proof] can't fail, so this also provides `TryFrom<BatchProof>`
+/// through the blanket impl.
+impl From<BatchProof> for Proof<BitcoinNodeHash> {
+    fn from(proof: BatchProof) -> Self {
+        let targets = proof
+            .targets
+            .into_iter()
+            .map(|target| target.0)
+            .collect();
+        let hashes = proof
+            .hashes
+            .into_iter()
+            .map(|hash| BitcoinNodeHash::new(hash.to_byte_array()))
+            .collect();
+        Proof::new(targets, hashes)
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 pub struct CompactLeafData {
     /// Header code tells the height of creating for this UTXO and whether it's a coinbase
@@ -76,12 +163,85 @@ pub const UTREEXO_TAG_V1: [u8; 64] = [
     0x15, 0x6e, 0xb3, 0x15, 0x1e, 0x0e, 0xd1, 0xb3, 0x09, 0x8b, 0xdc, 0x84, 0x45, 0x86, 0x18, 0x85,
 ];
 
+/// Scripts bigger than this can never be spent (consensus `MAX_SCRIPT_SIZE`).
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Returns whether an output with this locking script can never be spent. Such outputs are
+/// never added to the accumulator, and every leaf-building path must use this same filter so
+/// the circuit and the accumulator-service agree on the leaf set.
+pub fn is_unspendable(script: &Script) -> bool {
+    script.is_op_return() || script.len() > MAX_SCRIPT_SIZE
+}
+
+/// Returns whether an output with this locking script is left out of the accumulator. That is
+/// every unspendable output (see [`is_unspendable`]) unless `keep_op_return` is set, in which
+/// case OP_RETURN data carriers up to [`MAX_SCRIPT_SIZE`] are kept as leaves, for research on
+/// them. The default everywhere is to exclude them, and a build and every block applied on top
+/// of it must use the same setting.
+pub fn is_excluded(script: &Script, keep_op_return: bool) -> bool {
+    if keep_op_return {
+        script.len() > MAX_SCRIPT_SIZE
+    } else {
+        is_unspendable(script)
+    }
+}
+
+/// Header code for a UTXO created at `height` (see [`LeafData::header_code`]), or `None` if
+/// `height` needs more than 31 bits and shifting it would silently drop the top bit. No
+/// Bitcoin block is that high, so this only happens with corrupt input.
+pub fn header_code(height: u32, is_coinbase: bool) -> Option<u32> {
+    height
+        .checked_mul(2)
+        .map(|code| code | u32::from(is_coinbase))
+}
+
+/// The txid of `tx`: double SHA-256 over its version, inputs, outputs and lock time, consensus
+/// encoded in that order without the witness. Same as `Transaction::compute_txid`, but hashed
+/// with `sha2`, which SP1 patches to use its SHA-256 precompile inside the zkVM.
+pub fn compute_txid(tx: &Transaction) -> Txid {
+    let mut tx_bytes = Vec::new();
+
+    tx.version
+        .consensus_encode(&mut tx_bytes)
+        .expect("engines don't error");
+    tx.input
+        .consensus_encode(&mut tx_bytes)
+        .expect("engines don't error");
+    tx.output
+        .consensus_encode(&mut tx_bytes)
+        .expect("engines don't error");
+    tx.lock_time
+        .consensus_encode(&mut tx_bytes)
+        .expect("engines don't error");
+
+    let hash = Sha256::digest(&tx_bytes);
+    let hash = Sha256::digest(hash);
+    let hash_bytes = hash.as_slice();
+    Txid::from_slice(hash_bytes).unwrap()
+}
+
 impl LeafData {
+    /// Hash this leaf as it is added to the accumulator. The preimage is, in order:
+    ///
+    /// ```text
+    /// UTREEXO_TAG_V1 || UTREEXO_TAG_V1 || block_hash (32) || prevout.txid (32)
+    ///     || prevout.vout (u32 LE) || header_code (u32 LE) || consensus-encoded utxo
+    /// ```
+    ///
+    /// hashed with SHA-512/256. This matches utreexod's leaf hash; the block's median time past
+    /// is deliberately not committed, as that would make the leaves incompatible with it.
     pub fn get_leaf_hashes(&self) -> BitcoinNodeHash {
-        let mut ser_utxo = vec![];
+        self.get_leaf_hashes_into(&mut Vec::new())
+    }
+
+    /// Same as [`LeafData::get_leaf_hashes`], but encodes the utxo into `scratch` instead of a
+    /// fresh buffer, so hashing many leaves in a row reuses one allocation. `scratch` is cleared
+    /// first; its previous contents don't matter.
+    pub fn get_leaf_hashes_into(&self, scratch: &mut Vec<u8>) -> BitcoinNodeHash {
+        scratch.clear();
         let _ = self
             .utxo
-            .consensus_encode(&mut ser_utxo);
+            .consensus_encode(scratch);
         let leaf_hash = Sha512_256::new()
             .chain_update(UTREEXO_TAG_V1)
             .chain_update(UTREEXO_TAG_V1)
@@ -93,8 +253,242 @@ impl LeafData {
                     .to_le_bytes(),
             )
             .chain_update(self.header_code.to_le_bytes())
-            .chain_update(ser_utxo)
+            .chain_update(&scratch[..])
             .finalize();
         BitcoinNodeHash::from(leaf_hash.as_slice())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Block;
+    use bitcoin::ScriptBuf;
+    use bitcoin::Witness;
+    use bitcoin_hashes::sha512_256;
+    use bitcoin_hashes::HashEngine;
+    use rustreexo::accumulator::node_hash::AccumulatorHash;
+
+    use super::*;
+
+    fn sample_proof() -> BatchProof {
+        BatchProof {
+            targets: vec![VarInt(0), VarInt(3), VarInt(300)],
+            hashes: vec![
+                BlockHash::from_byte_array([1; 32]),
+                BlockHash::from_byte_array([2; 32]),
+            ],
+        }
+    }
+
+    /// Every transaction of the blocks in `test-data`: coinbases and legacy spends, some with
+    /// several inputs.
+    fn fixture_transactions() -> Vec<Transaction> {
+        [
+            &include_bytes!("../../test-data/block-1txs/block.txt")[..],
+            &include_bytes!("../../test-data/block-3txs/block.txt")[..],
+            &include_bytes!("../../test-data/block-9txs/block.txt")[..],
+        ]
+        .into_iter()
+        .flat_map(|bytes| {
+            bitcoin::consensus::deserialize::<Block>(bytes)
+                .unwrap()
+                .txdata
+        })
+        .collect()
+    }
+
+    #[test]
+    fn compute_txid_matches_bitcoin_for_real_transactions() {
+        let txs = fixture_transactions();
+        assert!(txs
+            .iter()
+            .any(|tx| tx.input.len() > 1));
+        for tx in &txs {
+            assert_eq!(compute_txid(tx), tx.compute_txid());
+        }
+    }
+
+    #[test]
+    fn compute_txid_ignores_witness() {
+        let mut tx = fixture_transactions()
+            .into_iter()
+            .find(|tx| tx.input.len() > 1)
+            .unwrap();
+        for input in &mut tx.input {
+            input.witness = Witness::from_slice(&[vec![0x30; 71], vec![0x02; 33]]);
+        }
+        assert!(tx.total_size() > tx.base_size());
+        assert_eq!(compute_txid(&tx), tx.compute_txid());
+        assert_ne!(
+            compute_txid(&tx).to_byte_array(),
+            tx.compute_wtxid()
+                .to_byte_array()
+        );
+    }
+
+    #[test]
+    fn keep_op_return_only_keeps_op_return() {
+        let op_return = ScriptBuf::from_bytes(vec![0x6a, 0x04, 1, 2, 3, 4]);
+        let oversized = ScriptBuf::from_bytes(vec![0x51; MAX_SCRIPT_SIZE + 1]);
+        let p2pk = ScriptBuf::from_bytes(vec![0x51]);
+        assert!(is_excluded(&op_return, false));
+        assert!(!is_excluded(&op_return, true));
+        assert!(is_excluded(&oversized, true));
+        assert!(!is_excluded(&p2pk, false));
+    }
+
+    #[test]
+    fn header_code_rejects_heights_past_31_bits() {
+        assert_eq!(header_code(5, true), Some((5 << 1) | 1));
+        assert_eq!(header_code(5, false), Some(5 << 1));
+        assert_eq!(
+            header_code(u32::MAX >> 1, true),
+            Some(u32::MAX)
+        );
+        assert_eq!(
+            header_code((u32::MAX >> 1) + 1, false),
+            None
+        );
+        assert_eq!(header_code(u32::MAX, true), None);
+    }
+
+    #[test]
+    fn leaf_hash_is_pinned() {
+        let leaf = LeafData {
+            block_hash: BlockHash::from_byte_array([1; 32]),
+            prevout: OutPoint {
+                txid: bitcoin::Txid::from_byte_array([2; 32]),
+                vout: 3,
+            },
+            header_code: (5 << 1) | 1,
+            utxo: TxOut {
+                value: bitcoin::Amount::from_sat(1_000),
+                script_pubkey: bitcoin::ScriptBuf::from_bytes(vec![0x51]),
+            },
+        };
+        // Changing anything in the preimage changes every leaf in the accumulator.
+        let expected = [
+            0xef, 0x10, 0xce, 0x27, 0x13, 0x9f, 0x9a, 0xcf, 0x05, 0x15, 0x10, 0xe4, 0xfc, 0x80,
+            0x8a, 0x54, 0xde, 0xb7, 0x72, 0x16, 0x90, 0x8d, 0x4c, 0x22, 0x9a, 0x94, 0xf4, 0x82,
+            0x63, 0x59, 0x9b, 0x5f,
+        ];
+        assert_eq!(
+            leaf.get_leaf_hashes(),
+            BitcoinNodeHash::new(expected)
+        );
+
+        // a scratch buffer left over from a bigger utxo must not leak into the hash
+        let mut scratch = vec![0xff; 64];
+        assert_eq!(
+            leaf.get_leaf_hashes_into(&mut scratch),
+            BitcoinNodeHash::new(expected)
+        );
+        assert_eq!(
+            leaf.get_leaf_hashes_into(&mut scratch),
+            BitcoinNodeHash::new(expected)
+        );
+    }
+
+    /// Leaf and parent hashes through the selected sha2 backend must equal bitcoin_hashes',
+    /// which doesn't depend on it. Run under each backend feature to check they all agree, e.g.
+    /// `cargo test -p utreexo --features sha2-soft`.
+    #[test]
+    fn sha2_backend_matches_bitcoin_hashes() {
+        for i in 0..16u8 {
+            let leaf = LeafData {
+                block_hash: BlockHash::from_byte_array([i; 32]),
+                prevout: OutPoint {
+                    txid: bitcoin::Txid::from_byte_array([i ^ 0xff; 32]),
+                    vout: u32::from(i),
+                },
+                header_code: u32::from(i) << 1,
+                utxo: TxOut {
+                    value: bitcoin::Amount::from_sat(u64::from(i) * 1_000),
+                    script_pubkey: ScriptBuf::from_bytes(vec![0x51; usize::from(i) * 10]),
+                },
+            };
+            let mut utxo = Vec::new();
+            leaf.utxo
+                .consensus_encode(&mut utxo)
+                .unwrap();
+            let mut engine = sha512_256::Hash::engine();
+            engine.input(&UTREEXO_TAG_V1);
+            engine.input(&UTREEXO_TAG_V1);
+            engine.input(
+                leaf.block_hash
+                    .as_byte_array(),
+            );
+            engine.input(
+                leaf.prevout
+                    .txid
+                    .as_byte_array(),
+            );
+            engine.input(
+                &leaf
+                    .prevout
+                    .vout
+                    .to_le_bytes(),
+            );
+            engine.input(&leaf.header_code.to_le_bytes());
+            engine.input(&utxo);
+            let reference = sha512_256::Hash::from_engine(engine);
+            assert_eq!(
+                leaf.get_leaf_hashes(),
+                BitcoinNodeHash::new(reference.to_byte_array())
+            );
+
+            let left = BitcoinNodeHash::new([i; 32]);
+            let right = BitcoinNodeHash::new([i.wrapping_add(1); 32]);
+            let parent = Sha512_256::new()
+                .chain_update(*left)
+                .chain_update(*right)
+                .finalize();
+            assert_eq!(
+                BitcoinNodeHash::from(parent.as_slice()),
+                BitcoinNodeHash::parent_hash(&left, &right)
+            );
+        }
+    }
+
+    #[test]
+    fn batch_proof_accessors() {
+        let proof = sample_proof();
+        assert!(!proof.is_empty());
+        assert_eq!(proof.hash_count(), 2);
+        assert_eq!(proof.target_count(), 3);
+
+        let empty = BatchProof::default();
+        assert!(empty.is_empty());
+        assert_eq!(empty.hash_count(), 0);
+        assert_eq!(empty.target_count(), 0);
+
+        // proving a root needs no hashes, but still proves something
+        let root_only = BatchProof {
+            targets: vec![VarInt(14)],
+            hashes: vec![],
+        };
+        assert!(!root_only.is_empty());
+        assert_eq!(root_only.hash_count(), 0);
+        assert_eq!(root_only.target_count(), 1);
+    }
+
+    #[test]
+    fn batch_proof_serde_roundtrip() {
+        let proof = sample_proof();
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: BatchProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(proof, decoded);
+    }
+
+    #[test]
+    fn batch_proof_rustreexo_roundtrip() {
+        let proof = sample_proof();
+        let rustreexo_proof = Proof::<BitcoinNodeHash>::from(proof.clone());
+        assert_eq!(rustreexo_proof.targets, vec![0, 3, 300]);
+        assert_eq!(
+            rustreexo_proof.hashes[0],
+            BitcoinNodeHash::new([1; 32])
+        );
+        assert_eq!(BatchProof::from(rustreexo_proof), proof);
+    }
+}
@@ -1,9 +1,31 @@
+#[cfg(all(
+    target_os = "zkvm",
+    any(
+        feature = "sha2-asm",
+        feature = "sha2-soft"
+    )
+))]
+compile_error!("the sha2-asm / sha2-soft features are for native builds only");
+
 pub mod btc_structs;
 pub mod process_block;
+pub mod roots;
 
 // re‐export the bits you’ll actually need in your script crate:
+pub use btc_structs::compute_txid;
+pub use btc_structs::header_code;
+pub use btc_structs::is_excluded;
+pub use btc_structs::is_unspendable;
 pub use btc_structs::BatchProof;
 pub use btc_structs::LeafData;
 pub use btc_structs::ScriptPubkeyType;
+pub use btc_structs::MAX_SCRIPT_SIZE;
 pub use btc_structs::UTREEXO_TAG_V1;
 pub use process_block::process_block;
+pub use process_block::process_block_changes;
+pub use process_block::process_block_with;
+pub use process_block::BlockChanges;
+pub use process_block::ProcessBlockError;
+pub use process_block::ProcessOptions;
+pub use roots::forest_roots_bytes;
+pub use roots::roots_bytes;
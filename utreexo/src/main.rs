@@ -4,8 +4,7 @@
 #[cfg(not(feature = "native"))]
 sp1_zkvm::entrypoint!(main);
 
-use std::collections::HashMap;
-use std::ops::Deref;
+use std::collections::BTreeMap;
 
 use alloy_sol_types::sol;
 use alloy_sol_types::SolType;
@@ -15,10 +14,16 @@ use rustreexo::accumulator::mem_forest::MemForest;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
 use serde::Deserialize;
 
+// shared with the library, of which the program only needs block processing
+#[allow(dead_code)]
 mod btc_structs;
+#[allow(dead_code)]
 mod process_block;
+mod roots;
 
-use crate::process_block::process_block;
+use crate::process_block::process_block_with;
+use crate::process_block::ProcessOptions;
+use crate::roots::forest_roots_bytes;
 
 fn mem_forest_from_bytes<'de, D>(deserializer: D) -> Result<MemForest<BitcoinNodeHash>, D::Error>
 where
@@ -26,44 +31,65 @@ where
 {
     let bytes: Vec<u8> = serde::Deserialize::deserialize(deserializer)?;
     let cursor = std::io::Cursor::new(bytes);
-    MemForest::<BitcoinNodeHash>::deserialize(cursor).map_err(serde::de::Error::custom)
+    MemForest::<BitcoinNodeHash>::deserialize(cursor).map_err(|e| {
+        serde::de::Error::custom(format!(
+            "mem_forest is not a serialized MemForest: {e}"
+        ))
+    })
 }
 
+/// Version of the input format, read before anything else. Version 1 was the unversioned
+/// format without `keep_op_return`, where OP_RETURN outputs were never leaves.
+const INPUT_VERSION: u32 = 2;
+
+/// Bit of the committed policy byte set when OP_RETURN outputs were added as leaves.
+const POLICY_KEEP_OP_RETURN: u8 = 1;
+
 #[derive(Deserialize)]
 struct AccumulatorInput {
+    /// Must be [`INPUT_VERSION`]
+    version: u32,
     block: Block,
     height: u32,
     #[serde(deserialize_with = "mem_forest_from_bytes")]
     mem_forest: MemForest<BitcoinNodeHash>,
-    input_leaf_hashes: HashMap<TxIn, BitcoinNodeHash>,
+    input_leaf_hashes: BTreeMap<TxIn, BitcoinNodeHash>,
+    /// Add OP_RETURN outputs as leaves; must match how `mem_forest` was built
+    keep_op_return: bool,
 }
 
 type PublicValuesTuple = sol! {
     (
         bytes, // acc roots
+        uint8, // leaf policy, see POLICY_KEEP_OP_RETURN
     )
 };
 
 pub fn main() {
-    let (block, height, mut acc, input_leaf_hashes) = read_inputs();
-    let _proof = process_block(
+    let (block, height, mut acc, input_leaf_hashes, keep_op_return) = read_inputs();
+    let options = ProcessOptions {
+        keep_op_return,
+        ..Default::default()
+    };
+    let _proof = process_block_with(
         &block,
         height,
         &mut acc,
         input_leaf_hashes,
-    );
-    let acc_roots: Vec<BitcoinNodeHash> = acc
-        .get_roots()
-        .iter()
-        .map(|rc| rc.get_data())
-        .collect();
-    let acc_roots_bytes: Vec<[u8; 32]> = acc_roots
-        .iter()
-        .map(|hash| *hash.deref())
-        .collect();
+        options,
+    )
+    .unwrap_or_else(|e| panic!("failed to process block {height}: {e}"));
+    let acc_roots_bytes = forest_roots_bytes(&acc).unwrap_or_else(|e| panic!("{e}"));
     let acc_roots_bytes_flat: Vec<u8> = acc_roots_bytes.concat();
-
-    let bytes = PublicValuesTuple::abi_encode(&(acc_roots_bytes_flat,));
+    // The roots only mean something under the leaf policy they were computed with, so a
+    // verifier must be able to tell which one the prover used
+    let policy = if keep_op_return {
+        POLICY_KEEP_OP_RETURN
+    } else {
+        0
+    };
+
+    let bytes = PublicValuesTuple::abi_encode(&(acc_roots_bytes_flat, policy));
     commit_slice(&bytes);
 }
 
@@ -72,7 +98,8 @@ fn read_inputs() -> (
     Block,
     u32,
     MemForest<BitcoinNodeHash>,
-    HashMap<TxIn, BitcoinNodeHash>,
+    BTreeMap<TxIn, BitcoinNodeHash>,
+    bool,
 ) {
     use std::io::Read;
     use std::io::{self};
@@ -96,14 +123,25 @@ fn read_inputs() -> (
         std::process::exit(1);
     }
 
-    let parsed: AccumulatorInput = serde_json::from_str(&input_data)
-        .expect("Deserialization failed: Provided input is invalid or cannot be parsed into the required types");
+    // serde_json names the missing or mistyped field and where it is in the input
+    let parsed: AccumulatorInput = serde_json::from_str(&input_data).unwrap_or_else(|e| {
+        eprintln!("Error: invalid input: {e}");
+        std::process::exit(1);
+    });
+    if parsed.version != INPUT_VERSION {
+        eprintln!(
+            "Error: input version {} is not supported, expected {INPUT_VERSION}",
+            parsed.version
+        );
+        std::process::exit(1);
+    }
 
     (
         parsed.block,
         parsed.height,
         parsed.mem_forest,
         parsed.input_leaf_hashes,
+        parsed.keep_op_return,
     )
 }
 
@@ -112,13 +150,20 @@ fn read_inputs() -> (
     Block,
     u32,
     MemForest<BitcoinNodeHash>,
-    HashMap<TxIn, BitcoinNodeHash>,
+    BTreeMap<TxIn, BitcoinNodeHash>,
+    bool,
 ) {
+    let version = sp1_zkvm::io::read::<u32>();
+    assert_eq!(
+        version, INPUT_VERSION,
+        "input version {version} is not supported"
+    );
     (
         sp1_zkvm::io::read::<Block>(),
         sp1_zkvm::io::read::<u32>(),
         sp1_zkvm::io::read::<MemForest<BitcoinNodeHash>>(),
-        sp1_zkvm::io::read::<HashMap<TxIn, BitcoinNodeHash>>(),
+        sp1_zkvm::io::read::<BTreeMap<TxIn, BitcoinNodeHash>>(),
+        sp1_zkvm::io::read::<bool>(),
     )
 }
 
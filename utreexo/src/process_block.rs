@@ -1,48 +1,178 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
 
-use bitcoin::consensus::Encodable;
 use bitcoin::Block;
 use bitcoin::OutPoint;
-use bitcoin::Transaction;
 use bitcoin::TxIn;
-use bitcoin::Txid;
-use bitcoin_hashes::Hash;
 use rustreexo::accumulator::mem_forest::MemForest;
 use rustreexo::accumulator::node_hash::BitcoinNodeHash;
-use sha2::Digest;
-use sha2::Sha256;
 
+use crate::btc_structs::compute_txid;
+use crate::btc_structs::header_code;
+use crate::btc_structs::is_excluded;
 use crate::btc_structs::BatchProof;
 use crate::btc_structs::LeafData;
 
-fn compute_txid(tx: &Transaction) -> Txid {
-    let mut tx_bytes = Vec::new();
-
-    tx.version
-        .consensus_encode(&mut tx_bytes)
-        .expect("engines don't error");
-    tx.input
-        .consensus_encode(&mut tx_bytes)
-        .expect("engines don't error");
-    tx.output
-        .consensus_encode(&mut tx_bytes)
-        .expect("engines don't error");
-    tx.lock_time
-        .consensus_encode(&mut tx_bytes)
-        .expect("engines don't error");
-
-    let hash = Sha256::digest(&tx_bytes);
-    let hash = Sha256::digest(hash);
-    let hash_bytes = hash.as_slice();
-    Txid::from_slice(hash_bytes).unwrap()
+/// Leaves created by a block so far, in creation order, with an index from hash to position so
+/// a same-block spend finds its leaf in constant time instead of scanning every output.
+///
+/// Removal swaps the last leaf into the freed slot, so the final order (which determines the
+/// accumulator's shape) is exactly what `Vec::swap_remove` on a linear scan would produce.
+/// Leaf hashes commit to their outpoint, so a hash is never added twice.
+struct BlockLeaves {
+    leaves: Vec<BitcoinNodeHash>,
+    index: HashMap<BitcoinNodeHash, usize>,
 }
 
+impl BlockLeaves {
+    fn with_capacity(capacity: usize) -> Self {
+        BlockLeaves {
+            leaves: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn add(&mut self, hash: BitcoinNodeHash) {
+        self.index
+            .insert(hash, self.leaves.len());
+        self.leaves.push(hash);
+    }
+
+    /// Remove `hash` if this block created it. Returns whether it did.
+    fn spend(&mut self, hash: &BitcoinNodeHash) -> bool {
+        let Some(idx) = self.index.remove(hash) else {
+            return false;
+        };
+        self.leaves.swap_remove(idx);
+        if let Some(moved) = self.leaves.get(idx) {
+            self.index.insert(*moved, idx);
+        }
+        true
+    }
+}
+
+/// Why a block could not be applied to the accumulator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessBlockError {
+    /// No leaf hash was supplied for the input spending this outpoint.
+    MissingInputLeaf(OutPoint),
+    /// The input spends an output created earlier in the same block that was never added as a
+    /// leaf because it is unspendable, so there is nothing to delete.
+    SpendsUnspendable(OutPoint),
+    /// The accumulator rejected the block's additions and deletions.
+    Modify(String),
+    /// The coinbase's segwit witness commitment doesn't match the block's transactions.
+    WitnessCommitment,
+    /// The height is too large to be encoded in a header code (see [`header_code`]).
+    HeightOverflow(u32),
+}
+
+impl fmt::Display for ProcessBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessBlockError::MissingInputLeaf(prevout) => {
+                write!(
+                    f,
+                    "no leaf hash for the input spending {prevout}"
+                )
+            }
+            ProcessBlockError::SpendsUnspendable(prevout) => write!(
+                f,
+                "input spends {prevout}, an unspendable output of the same block"
+            ),
+            ProcessBlockError::Modify(e) => write!(f, "failed to modify accumulator: {e}"),
+            ProcessBlockError::WitnessCommitment => write!(
+                f,
+                "witness commitment does not match the block's transactions"
+            ),
+            ProcessBlockError::HeightOverflow(height) => write!(
+                f,
+                "height {height} is too large for a header code"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProcessBlockError {}
+
+/// Optional checks [`process_block_with`] runs before touching the accumulator, and the leaf
+/// policy it applies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessOptions {
+    /// Check the coinbase's segwit witness commitment against the block's wtxids. Off by
+    /// default, since it hashes every transaction once more.
+    pub check_witness_commitment: bool,
+    /// Add OP_RETURN outputs as leaves instead of skipping them (see [`is_excluded`]). Off by
+    /// default; the accumulator being built on must have been built the same way.
+    pub keep_op_return: bool,
+}
+
+/// [`process_block_with`] without any of the optional checks.
+///
+/// `input_leaf_hashes` is only ever looked up, so its order can't change the result, but it is
+/// read from the zkVM's stdin. A `BTreeMap` serializes the same entries to the same bytes every
+/// time, which keeps the prover's input, and so the proof, reproducible.
 pub fn process_block(
     block: &Block,
     height: u32,
     acc: &mut MemForest<BitcoinNodeHash>,
-    input_leaf_hashes: HashMap<TxIn, BitcoinNodeHash>,
-) -> BatchProof {
+    input_leaf_hashes: BTreeMap<TxIn, BitcoinNodeHash>,
+) -> Result<BatchProof, ProcessBlockError> {
+    process_block_with(
+        block,
+        height,
+        acc,
+        input_leaf_hashes,
+        ProcessOptions::default(),
+    )
+}
+
+/// Apply `block` to `acc`: add its spendable outputs and delete the leaves its inputs spend.
+/// Fails before touching the accumulator if an input can't be resolved or an enabled check
+/// fails.
+pub fn process_block_with(
+    block: &Block,
+    height: u32,
+    acc: &mut MemForest<BitcoinNodeHash>,
+    input_leaf_hashes: BTreeMap<TxIn, BitcoinNodeHash>,
+    options: ProcessOptions,
+) -> Result<BatchProof, ProcessBlockError> {
+    process_block_changes(
+        block,
+        height,
+        acc,
+        input_leaf_hashes,
+        options,
+    )
+    .map(|changes| changes.proof)
+}
+
+/// What applying a block did to the accumulator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockChanges {
+    pub proof: BatchProof,
+    /// Leaves added, in the order they were added. Outputs spent within the same block are not
+    /// among them.
+    pub added: Vec<BitcoinNodeHash>,
+    /// Leaves deleted: the ones spent by the block's inputs that existed before it.
+    pub deleted: Vec<BitcoinNodeHash>,
+}
+
+/// Like [`process_block_with`], but also returns the leaves the block added and deleted, for
+/// callers that keep their own leaf cache or change log.
+pub fn process_block_changes(
+    block: &Block,
+    height: u32,
+    acc: &mut MemForest<BitcoinNodeHash>,
+    input_leaf_hashes: BTreeMap<TxIn, BitcoinNodeHash>,
+    options: ProcessOptions,
+) -> Result<BlockChanges, ProcessBlockError> {
+    if options.check_witness_commitment && !block.check_witness_commitment() {
+        return Err(ProcessBlockError::WitnessCommitment);
+    }
+
     // Pre-calculate capacity estimates
     let estimated_inputs: usize = block
         .txdata
@@ -56,7 +186,9 @@ pub fn process_block(
         .map(|tx| tx.output.len())
         .sum();
     let mut inputs = Vec::with_capacity(estimated_inputs);
-    let mut utxos = Vec::with_capacity(estimated_utxos);
+    let mut utxos = BlockLeaves::with_capacity(estimated_utxos);
+    // Outputs of this block that were skipped as unspendable, to explain a later spend of one.
+    let mut unspendable = HashSet::new();
 
     // Block is static, thus its hash should be computed outside of the loop.
     let block_hash = block.block_hash();
@@ -66,31 +198,35 @@ pub fn process_block(
 
         for input in tx.input.iter() {
             if !tx.is_coinbase() {
+                if unspendable.contains(&input.previous_output) {
+                    return Err(ProcessBlockError::SpendsUnspendable(
+                        input.previous_output,
+                    ));
+                }
                 let hash = *input_leaf_hashes
                     .get(input)
-                    .unwrap();
-                if let Some(idx) = utxos
-                    .iter()
-                    .position(|h| *h == hash)
-                {
-                    utxos.swap_remove(idx);
-                } else {
+                    .ok_or(ProcessBlockError::MissingInputLeaf(
+                        input.previous_output,
+                    ))?;
+                if !utxos.spend(&hash) {
                     inputs.push(hash);
                 }
             }
         }
 
         for (idx, output) in tx.output.iter().enumerate() {
-            // TODO: doublecheck if is_op_return is proper method
-            if !output
-                .script_pubkey
-                .is_op_return()
-            {
-                let header_code = if tx.is_coinbase() {
-                    (height << 1) | 1
-                } else {
-                    height << 1
-                };
+            if is_excluded(
+                &output.script_pubkey,
+                options.keep_op_return,
+            ) {
+                unspendable.insert(OutPoint {
+                    txid,
+                    vout: idx as u32,
+                });
+            } else {
+                let header_code = header_code(height, tx.is_coinbase()).ok_or(
+                    ProcessBlockError::HeightOverflow(height),
+                )?;
                 let leaf = LeafData {
                     block_hash,
                     header_code,
@@ -100,16 +236,412 @@ pub fn process_block(
                     },
                     utxo: output.to_owned(),
                 };
-                utxos.push(leaf.get_leaf_hashes());
+                utxos.add(leaf.get_leaf_hashes());
             }
         }
     }
 
-    acc.modify(&utxos, &inputs)
+    acc.modify(&utxos.leaves, &inputs)
+        .map_err(ProcessBlockError::Modify)?;
+
+    Ok(BlockChanges {
+        proof: BatchProof {
+            targets: vec![],
+            hashes: vec![],
+        },
+        added: utxos.leaves,
+        deleted: inputs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::absolute;
+    use bitcoin::block::Header;
+    use bitcoin::block::Version;
+    use bitcoin::transaction;
+    use bitcoin::Amount;
+    use bitcoin::BlockHash;
+    use bitcoin::CompactTarget;
+    use bitcoin::ScriptBuf;
+    use bitcoin::Sequence;
+    use bitcoin::Transaction;
+    use bitcoin::TxMerkleNode;
+    use bitcoin::TxOut;
+    use bitcoin::Txid;
+    use bitcoin::Witness;
+    use bitcoin_hashes::Hash;
+
+    use super::*;
+
+    fn txin(previous_output: OutPoint) -> TxIn {
+        TxIn {
+            previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }
+    }
+
+    fn tx(input: Vec<TxIn>, scripts: Vec<Vec<u8>>) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input,
+            output: scripts
+                .into_iter()
+                .map(|script| TxOut {
+                    value: Amount::from_sat(1_000),
+                    script_pubkey: ScriptBuf::from_bytes(script),
+                })
+                .collect(),
+        }
+    }
+
+    /// A block whose second transaction creates an OP_RETURN output and a normal one, and whose
+    /// third transaction spends output `spent` of the second. Also returns the leaf hashes of
+    /// every non-coinbase input and an accumulator holding the one pre-existing UTXO.
+    fn spending_block(
+        spent: u32,
+    ) -> (
+        Block,
+        BTreeMap<TxIn, BitcoinNodeHash>,
+        MemForest<BitcoinNodeHash>,
+    ) {
+        let coinbase = tx(
+            vec![txin(OutPoint::null())],
+            vec![vec![0x51]],
+        );
+        let funding = txin(OutPoint {
+            txid: Txid::from_byte_array([9; 32]),
+            vout: 0,
+        });
+        let creator = tx(
+            vec![funding.clone()],
+            vec![vec![0x6a, 0x01, 0x00], vec![0x51]],
+        );
+        let spend = txin(OutPoint {
+            txid: creator.compute_txid(),
+            vout: spent,
+        });
+        let spender = tx(vec![spend.clone()], vec![vec![0x51]]);
+        let block = Block {
+            header: Header {
+                version: Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![coinbase, creator, spender],
+        };
+
+        let existing = BitcoinNodeHash::new([7; 32]);
+        let mut acc = MemForest::new();
+        acc.modify(&[existing], &[])
+            .unwrap();
+        let created = LeafData {
+            block_hash: block.block_hash(),
+            prevout: spend.previous_output,
+            header_code: 1 << 1,
+            utxo: block.txdata[1].output[spent as usize].clone(),
+        }
+        .get_leaf_hashes();
+        let hashes = BTreeMap::from([(funding, existing), (spend, created)]);
+        (block, hashes, acc)
+    }
+
+    /// A block with a segwit spend of the accumulator's only leaf and a coinbase committing to
+    /// it, with the commitment's first byte flipped if `tamper` is set.
+    fn segwit_block(
+        tamper: bool,
+    ) -> (
+        Block,
+        BTreeMap<TxIn, BitcoinNodeHash>,
+        MemForest<BitcoinNodeHash>,
+    ) {
+        let (mut block, mut hashes, acc) = spending_block(1);
+        let mut coinbase_in = txin(OutPoint::null());
+        coinbase_in.witness = Witness::from_slice(&[[0u8; 32]]);
+        block.txdata[0].input = vec![coinbase_in];
+        let funding = block.txdata[1].input[0].clone();
+        let existing = hashes
+            .remove(&funding)
+            .unwrap();
+        block.txdata[1].input[0]
+            .witness
+            .push([1, 2, 3]);
+        hashes.insert(
+            block.txdata[1].input[0].clone(),
+            existing,
+        );
+
+        let witness_root = block.witness_root().unwrap();
+        let mut commitment = Block::compute_witness_commitment(&witness_root, &[0; 32])
+            .to_byte_array()
+            .to_vec();
+        if tamper {
+            commitment[0] ^= 1;
+        }
+        let mut script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        script.extend(commitment);
+        block.txdata[0]
+            .output
+            .push(TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::from_bytes(script),
+            });
+        (block, hashes, acc)
+    }
+
+    #[test]
+    fn witness_commitment_is_checked_when_asked() {
+        let check = ProcessOptions {
+            check_witness_commitment: true,
+            ..Default::default()
+        };
+        let (block, hashes, mut acc) = segwit_block(false);
+        process_block_with(&block, 1, &mut acc, hashes, check).unwrap();
+
+        let (block, hashes, mut acc) = segwit_block(true);
+        let leaves = acc.leaves;
+        assert_eq!(
+            process_block_with(
+                &block,
+                1,
+                &mut acc,
+                hashes.clone(),
+                check
+            ),
+            Err(ProcessBlockError::WitnessCommitment)
+        );
+        assert_eq!(acc.leaves, leaves);
+        // without the check the block goes through
+        process_block(&block, 1, &mut acc, hashes).unwrap();
+    }
+
+    /// The linear scan `BlockLeaves` replaced, kept to check the order it leaves behind.
+    fn scan_spend(leaves: &mut Vec<BitcoinNodeHash>, hash: &BitcoinNodeHash) -> bool {
+        match leaves
+            .iter()
+            .position(|h| h == hash)
+        {
+            Some(idx) => {
+                leaves.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn block_leaves_match_linear_scan() {
+        let hash = |i: u32| {
+            let mut bytes = [0u8; 32];
+            bytes[..4].copy_from_slice(&i.to_le_bytes());
+            BitcoinNodeHash::new(bytes)
+        };
+        let mut indexed = BlockLeaves::with_capacity(0);
+        let mut scanned = Vec::new();
+        let mut next = 0u32;
+        // a fixed pseudo-random mix of adds and spends, some of leaves that were never added
+        let mut state = 0x2545_f491u32;
+        for _ in 0..5_000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if state.is_multiple_of(3) {
+                indexed.add(hash(next));
+                scanned.push(hash(next));
+                next += 1;
+            } else {
+                let target = hash(state % (next + 10));
+                assert_eq!(
+                    indexed.spend(&target),
+                    scan_spend(&mut scanned, &target)
+                );
+            }
+            assert_eq!(indexed.leaves, scanned);
+        }
+    }
+
+    #[test]
+    fn long_same_block_chain_collapses() {
+        let (mut block, mut hashes, mut acc) = spending_block(1);
+        // every transaction spends the only output of the one before it
+        block.txdata.truncate(2);
+        block.txdata[1].output = vec![block.txdata[1].output[1].clone()];
+        for _ in 0..2_000 {
+            let prev = block.txdata.last().unwrap();
+            let spend = txin(OutPoint {
+                txid: prev.compute_txid(),
+                vout: 0,
+            });
+            let created = LeafData {
+                block_hash: block.block_hash(),
+                prevout: spend.previous_output,
+                header_code: 1 << 1,
+                utxo: prev.output[0].clone(),
+            }
+            .get_leaf_hashes();
+            hashes.insert(spend.clone(), created);
+            block
+                .txdata
+                .push(tx(vec![spend], vec![vec![0x51]]));
+        }
+        process_block(&block, 1, &mut acc, hashes).unwrap();
+        // the pre-existing leaf plus the coinbase output and the last output of the chain
+        assert_eq!(acc.leaves, 3);
+    }
+
+    #[test]
+    fn changes_list_added_and_deleted_leaves() {
+        let (block, hashes, mut acc) = spending_block(1);
+        let changes = process_block_changes(
+            &block,
+            1,
+            &mut acc,
+            hashes,
+            ProcessOptions::default(),
+        )
         .unwrap();
 
-    BatchProof {
-        targets: vec![],
-        hashes: vec![],
+        // the coinbase output and the spender's output; the creator's spendable output is
+        // spent in the same block and its OP_RETURN is never a leaf
+        let leaf = |tx: &Transaction, header_code| {
+            LeafData {
+                block_hash: block.block_hash(),
+                prevout: OutPoint {
+                    txid: tx.compute_txid(),
+                    vout: 0,
+                },
+                header_code,
+                utxo: tx.output[0].clone(),
+            }
+            .get_leaf_hashes()
+        };
+        assert_eq!(
+            changes.added,
+            vec![
+                leaf(&block.txdata[0], (1 << 1) | 1),
+                leaf(&block.txdata[2], 1 << 1)
+            ]
+        );
+        assert_eq!(
+            changes.deleted,
+            vec![BitcoinNodeHash::new([7; 32])]
+        );
+        assert!(changes.proof.is_empty());
+    }
+
+    #[test]
+    fn height_past_31_bits_is_an_error() {
+        let (block, hashes, mut acc) = spending_block(1);
+        let height = (u32::MAX >> 1) + 1;
+        assert_eq!(
+            process_block(&block, height, &mut acc, hashes),
+            Err(ProcessBlockError::HeightOverflow(
+                height
+            ))
+        );
+        assert_eq!(acc.leaves, 1);
+    }
+
+    /// The bytes of the entries in the order serde visits them when serializing the map.
+    fn serialized_entries(map: &BTreeMap<TxIn, BitcoinNodeHash>) -> Vec<u8> {
+        serde_json::to_vec(&map.iter().collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn input_leaf_hashes_serialize_deterministically() {
+        let (_, hashes, _) = spending_block(1);
+        let entries: Vec<_> = hashes.into_iter().collect();
+        let forward: BTreeMap<_, _> = entries
+            .iter()
+            .cloned()
+            .collect();
+        let backward: BTreeMap<_, _> = entries
+            .iter()
+            .rev()
+            .cloned()
+            .collect();
+        assert_eq!(
+            serialized_entries(&forward),
+            serialized_entries(&backward)
+        );
+    }
+
+    #[test]
+    fn same_block_spend_is_deduplicated() {
+        let (block, hashes, mut acc) = spending_block(1);
+        process_block(&block, 1, &mut acc, hashes).unwrap();
+        // the output spent within the block never reaches the forest, so only two leaves are added
+        assert_eq!(acc.leaves, 3);
+    }
+
+    #[test]
+    fn keep_op_return_adds_op_return_outputs() {
+        let (block, hashes, mut acc) = spending_block(1);
+        let default = process_block_changes(
+            &block,
+            1,
+            &mut acc,
+            hashes,
+            ProcessOptions::default(),
+        )
+        .unwrap();
+        let (block, hashes, mut acc) = spending_block(1);
+        let keep = ProcessOptions {
+            keep_op_return: true,
+            ..Default::default()
+        };
+        let kept = process_block_changes(&block, 1, &mut acc, hashes, keep).unwrap();
+
+        let op_return = LeafData {
+            block_hash: block.block_hash(),
+            prevout: OutPoint {
+                txid: block.txdata[1].compute_txid(),
+                vout: 0,
+            },
+            header_code: 1 << 1,
+            utxo: block.txdata[1].output[0].clone(),
+        }
+        .get_leaf_hashes();
+        assert!(!default
+            .added
+            .contains(&op_return));
+        let mut expected = default.added.clone();
+        expected.insert(1, op_return);
+        assert_eq!(kept.added, expected);
+        assert_eq!(kept.deleted, default.deleted);
+    }
+
+    #[test]
+    fn spending_unspendable_output_is_reported() {
+        let (block, hashes, mut acc) = spending_block(0);
+        let spent = OutPoint {
+            txid: block.txdata[1].compute_txid(),
+            vout: 0,
+        };
+        assert_eq!(
+            process_block(&block, 1, &mut acc, hashes),
+            Err(ProcessBlockError::SpendsUnspendable(
+                spent
+            ))
+        );
+    }
+
+    #[test]
+    fn missing_input_leaf_is_reported() {
+        let (block, mut hashes, mut acc) = spending_block(1);
+        let funding = block.txdata[1].input[0].clone();
+        hashes.remove(&funding);
+        assert_eq!(
+            process_block(&block, 1, &mut acc, hashes),
+            Err(ProcessBlockError::MissingInputLeaf(
+                funding.previous_output
+            ))
+        );
     }
 }
@@ -0,0 +1,99 @@
+//! Accumulator roots as the 32-byte values committed to outside the accumulator.
+//!
+//! Besides a regular hash, a root can be `BitcoinNodeHash::Empty`, once every leaf of its
+//! tree has been deleted, and rustreexo uses `BitcoinNodeHash::Placeholder` internally while
+//! computing which roots a deletion destroys. Both dereference to `[0; 32]`, so a placeholder
+//! that escaped would be indistinguishable from an empty root. An empty root is committed as
+//! all zeros, as in utreexod; a placeholder is never valid and is rejected.
+use std::fmt;
+
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+
+/// A root held a placeholder hash instead of a real or empty one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaceholderRoot {
+    /// Index of the offending root.
+    pub index: usize,
+}
+
+impl fmt::Display for PlaceholderRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "root {} is a placeholder hash",
+            self.index
+        )
+    }
+}
+
+impl std::error::Error for PlaceholderRoot {}
+
+/// The bytes `root` is committed as: its hash, or all zeros for an empty root. `None` for a
+/// placeholder.
+pub fn root_bytes(root: &BitcoinNodeHash) -> Option<[u8; 32]> {
+    match root {
+        BitcoinNodeHash::Some(hash) => Some(*hash),
+        BitcoinNodeHash::Empty => Some([0; 32]),
+        BitcoinNodeHash::Placeholder => None,
+    }
+}
+
+/// [`root_bytes`] of every root in `roots`, in order.
+pub fn roots_bytes(roots: &[BitcoinNodeHash]) -> Result<Vec<[u8; 32]>, PlaceholderRoot> {
+    roots
+        .iter()
+        .enumerate()
+        .map(|(index, root)| root_bytes(root).ok_or(PlaceholderRoot { index }))
+        .collect()
+}
+
+/// [`root_bytes`] of every root of `acc`, in order.
+pub fn forest_roots_bytes(
+    acc: &MemForest<BitcoinNodeHash>,
+) -> Result<Vec<[u8; 32]>, PlaceholderRoot> {
+    let roots: Vec<BitcoinNodeHash> = acc
+        .get_roots()
+        .iter()
+        .map(|rc| rc.get_data())
+        .collect();
+    roots_bytes(&roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_root_is_zeros() {
+        let leaf = BitcoinNodeHash::new([1; 32]);
+        let mut acc = MemForest::new();
+        acc.modify(&[leaf], &[])
+            .unwrap();
+        assert_eq!(
+            forest_roots_bytes(&acc),
+            Ok(vec![[1; 32]])
+        );
+
+        // deleting the only leaf leaves an empty root in place
+        acc.modify(&[], &[leaf])
+            .unwrap();
+        assert_eq!(
+            forest_roots_bytes(&acc),
+            Ok(vec![[0; 32]])
+        );
+    }
+
+    #[test]
+    fn placeholder_root_is_rejected() {
+        let roots = [
+            BitcoinNodeHash::new([1; 32]),
+            BitcoinNodeHash::Placeholder,
+        ];
+        assert_eq!(root_bytes(&roots[1]), None);
+        assert_eq!(
+            roots_bytes(&roots),
+            Err(PlaceholderRoot { index: 1 })
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Malformed input to the native runner is reported on stderr instead of panicking.
+#![cfg(feature = "native")]
+
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+use bitcoin::constants::genesis_block;
+use bitcoin::Network;
+use rustreexo::accumulator::mem_forest::MemForest;
+use rustreexo::accumulator::node_hash::BitcoinNodeHash;
+use serde_json::json;
+use serde_json::Value;
+
+/// The program's `INPUT_VERSION`.
+const INPUT_VERSION: u32 = 2;
+
+/// Run the program on `input` and return its exit code and stderr.
+fn run(input: &Value) -> (Option<i32>, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_utreexo-program"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.to_string().as_bytes())
+        .unwrap();
+    let output = child
+        .wait_with_output()
+        .unwrap();
+    (
+        output.status.code(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+fn empty_forest() -> Vec<u8> {
+    let mut buf = Vec::new();
+    MemForest::<BitcoinNodeHash>::new()
+        .serialize(&mut buf)
+        .unwrap();
+    buf
+}
+
+#[test]
+fn missing_field_is_named() {
+    let input = json!({
+        "version": INPUT_VERSION,
+        "block": genesis_block(Network::Regtest),
+        "height": 0,
+        "mem_forest": empty_forest(),
+        "keep_op_return": false,
+    });
+    let (code, stderr) = run(&input);
+    assert_eq!(code, Some(1));
+    assert!(
+        stderr.contains("missing field `input_leaf_hashes`"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn invalid_forest_is_reported() {
+    let input = json!({
+        "version": INPUT_VERSION,
+        "block": genesis_block(Network::Regtest),
+        "height": 0,
+        "mem_forest": [1, 2, 3],
+        "input_leaf_hashes": {},
+        "keep_op_return": false,
+    });
+    let (code, stderr) = run(&input);
+    assert_eq!(code, Some(1));
+    assert!(
+        stderr.contains("mem_forest is not a serialized MemForest"),
+        "{stderr}"
+    );
+}